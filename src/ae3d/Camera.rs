@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::ae3d::Transformable::Orientation;
+use crate::error;
 
 use super::Window::Window;
 
@@ -274,7 +275,7 @@ impl Camera
 					program, 512,
 					&mut written, infoLog.as_mut_ptr()
 				);
-				println!("Failed to link shader:\n{}", String::from_raw_parts(
+				error!("Failed to link shader:\n{}", String::from_raw_parts(
 					infoLog.as_mut_ptr() as *mut u8, written as usize, 512
 				));
 			}
@@ -319,7 +320,7 @@ impl Camera
 					shader, len,
 					std::ptr::null_mut(), error.as_ptr() as *mut i8
 				);
-				println!("Failed to compile shader {path}{ext}:\n{}", error.to_str().unwrap());
+				error!("Failed to compile shader {path}{ext}:\n{}", error.to_str().unwrap());
 			}
 			shader
 		}
@@ -28,6 +28,7 @@ impl Entity
 		let mut ent = Self::new();
 		
 		bind::network(&ent.script);
+		bind::log(&ent.script);
 		bind::world(&ent.script);
 		bind::window(&ent.script);
 		bind::shaders(&ent.script);
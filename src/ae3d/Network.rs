@@ -1,77 +1,318 @@
 use std::time::{Duration, Instant};
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::io::{Read, Write};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc::{Receiver, Sender}};
 
 use mio::{net::{TcpStream, UdpSocket}, Events, Interest, Poll, Registry, Token};
 
-use crate::{ae3d::Window::Window, envell::message::{ToClient, ToServer}};
+use crate::envell::{inventory::Slot, message::{self, ToClient, ToServer}, movement::{self, Input, Rules}, net::ServerInfo, netsim::{Conditions, Simulator}, packet::{self, Frame, History, Packet, Quantization, Snapshot, State}, stats::Stats};
+use crate::{error, warn, info};
 
+const REDUNDANT_INPUTS: usize = 8;
+const HEARTBEAT: Duration = Duration::from_secs(1);
+const CHAT_HISTORY: usize = 100;
+
+pub struct Reconnect
+{
+	pub attempts: u8,
+	pub delay: Duration,
+	pub maxDelay: Duration
+}
+
+impl Default for Reconnect
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			attempts: 5,
+			delay: Duration::from_millis(500),
+			maxDelay: Duration::from_secs(8)
+		}
+	}
+}
+
+// A server found on the local network.
+pub struct Server
+{
+	pub addr: SocketAddr,
+	pub info: ServerInfo,
+	pub seen: Instant,
+	pub ping: Duration
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status
+{
+	Disconnected,
+	Connecting,
+	Handshaking,
+	Ready,
+	Reconnecting,
+	Failed
+}
+
+impl Status
+{
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Self::Disconnected => "disconnected",
+			Self::Connecting => "connecting",
+			Self::Handshaking => "handshaking",
+			Self::Ready => "ready",
+			Self::Reconnecting => "reconnecting",
+			Self::Failed => "failed"
+		}
+	}
+}
+
+pub enum Event
+{
+	Connected,
+	Disconnected(String, bool),
+	Message(&'static str, json::JsonValue)
+}
+
+// Handle owned by the main thread. The network thread gets a clone of the lock,
+// so both sides only ever see the state between whole updates.
 pub struct Network
 {
+	shared: Arc<Mutex<Shared>>,
+	events: Receiver<Event>
+}
+
+impl Network
+{
+	pub fn init() -> Self
+	{
+		let (tx, rx) = std::sync::mpsc::channel();
+		Self
+		{
+			shared: Arc::new(Mutex::new(Shared::new(tx))),
+			events: rx
+		}
+	}
+
+	pub fn lock(&self) -> MutexGuard<'_, Shared> { lock(&self.shared) }
+
+	pub fn connect(&self, ip: String, password: String) -> bool
+	{
+		let Some(generation) = self.lock().open(ip, password) else { return false; };
+		let shared = self.shared.clone();
+		let _ = std::thread::Builder::new()
+			.name(String::from("Network Update"))
+			.spawn(move || update(shared, generation));
+		true
+	}
+
+	pub fn search(&self)
+	{
+		let shared = self.shared.clone();
+		let _ = std::thread::Builder::new()
+			.name(String::from("Server Search"))
+			.spawn(move || search(shared));
+	}
+
+	// Events are produced by the network thread and handled on the main one.
+	pub fn pollEvents(&self) -> Vec<Event> { self.events.try_iter().collect() }
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared>
+{
+	shared.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+pub struct Shared
+{
+	generation: u32,
+	status: Status,
+	reason: String,
+	events: Sender<Event>,
 	active: bool,
 	ready: bool,
 	tcp: Option<TcpStream>,
 	udp: UdpSocket,
 	tcpSequence: Vec<ToClient>,
-	udpSequence: Vec<u8>,
+	servers: Vec<Server>,
+	password: String,
+	inventory: Vec<Slot>,
+	// Time, user and message, oldest first.
+	chat: VecDeque<(String, String, String)>,
 	id: u8,
 	tickRate: u8,
-	state: HashMap<u8, (glam::Vec3, glam::Vec2)>,
+	quantization: Quantization,
+	rules: Rules,
+	state: HashMap<u8, State>,
+	input: (glam::Vec3, glam::Vec2, u16),
+	inputSeq: u16,
+	inputs: VecDeque<Input>,
+	seq: u16,
+	sent: History,
+	serverAck: Option<u16>,
+	snapshots: History,
+	lastSnapshot: Option<u16>,
 	udpSock: SocketAddr,
-	tcpSock: SocketAddr
+	tcpSock: SocketAddr,
+	connected: bool,
+	token: Option<u64>,
+	timeout: Duration,
+	lastSeen: Instant,
+	reconnect: Reconnect,
+	attempt: u8,
+	retryAt: Option<Instant>,
+	stats: Stats,
+	tcpOutbox: Simulator<()>,
+	udpOutbox: Simulator<SocketAddr>,
+	// Start of a message whose rest has not arrived yet.
+	inbox: Vec<u8>
 }
 
-impl Network
+impl Shared
 {
-	pub fn init() -> Self
+	fn new(events: Sender<Event>) -> Self
 	{
 		Self
 		{
+			generation: 0,
+			status: Status::Disconnected,
+			reason: String::new(),
+			events,
 			active: false,
 			ready: false,
 			udp: UdpSocket::bind("0.0.0.0:0".parse().unwrap()).unwrap(),
 			tcp: None,
 			tcpSequence: vec![],
-			udpSequence: vec![],
+			servers: vec![],
+			password: String::new(),
+			inventory: vec![],
+			chat: VecDeque::new(),
 			id: u8::MAX,
 			tickRate: 10,
+			quantization: Quantization::default(),
+			rules: Rules::default(),
 			state: HashMap::new(),
+			input: (glam::Vec3::ZERO, glam::Vec2::ZERO, 0),
+			inputSeq: 0,
+			inputs: VecDeque::new(),
+			seq: 0,
+			sent: History::default(),
+			serverAck: None,
+			snapshots: History::default(),
+			lastSnapshot: None,
 			udpSock: "0.0.0.0:0".parse().unwrap(),
-			tcpSock: "0.0.0.0:0".parse().unwrap()
+			tcpSock: "0.0.0.0:0".parse().unwrap(),
+			connected: false,
+			token: None,
+			timeout: Duration::from_secs(10),
+			lastSeen: Instant::now(),
+			reconnect: Reconnect::default(),
+			attempt: 0,
+			retryAt: None,
+			stats: Stats::default(),
+			tcpOutbox: Simulator::new(true),
+			udpOutbox: Simulator::new(false),
+			inbox: vec![]
+		}
+	}
+
+	pub fn configure(&mut self, cfg: &json::JsonValue)
+	{
+		let d = Reconnect::default();
+		self.reconnect = Reconnect
+		{
+			attempts: cfg["reconnectAttempts"].as_u8().unwrap_or(d.attempts),
+			delay: cfg["reconnectDelay"].as_f32()
+				.map(Duration::from_secs_f32).unwrap_or(d.delay),
+			maxDelay: cfg["reconnectMaxDelay"].as_f32()
+				.map(Duration::from_secs_f32).unwrap_or(d.maxDelay)
+		};
+		self.setSimulation(Conditions::parse(&cfg["simulation"]));
+	}
+
+	pub fn setSimulation(&mut self, cond: Conditions)
+	{
+		self.tcpOutbox.cond = cond;
+		self.udpOutbox.cond = cond;
+	}
+
+	pub fn getSimulation(&self) -> Conditions { self.udpOutbox.cond }
+
+	fn flush(&mut self)
+	{
+		for (addr, raw) in self.udpOutbox.due()
+		{
+			if let Err(x) = self.udp.send_to(&raw, addr)
+			{
+				error!("Error when sending data on UDP:\n{x:#?}");
+			}
+		}
+		let Some(tcp) = self.tcp.as_mut() else { return; };
+		for (_, raw) in self.tcpOutbox.due()
+		{
+			let _ = tcp.write_all(&raw);
 		}
 	}
 
 	pub fn reset(&mut self)
 	{
+		if self.active
+		{
+			self.reason = String::from("disconnected");
+			self.setStatus(Status::Disconnected);
+		}
 		self.active = false;
+		self.ready = false;
+		self.connected = false;
+		self.token = None;
+		self.retryAt = None;
 		if let Some(tcp) = self.tcp.as_mut()
 		{
 			let _ = tcp.shutdown(std::net::Shutdown::Both);
 		}
 	}
 
-	pub fn connect(&mut self, ip: String) -> bool
+	// Starts a new connection and returns its generation.
+	// Network threads of older generations stop on their own.
+	fn open(&mut self, ip: String, password: String) -> Option<u32>
 	{
 		let addr = ip.parse();
 		if let Ok(addr) = addr
 		{
 			self.tcpSock = addr;
+			if let Ok(udp) = UdpSocket::bind(localAddr(&addr))
+			{
+				self.udp = udp;
+			}
 			let tcp = TcpStream::connect(addr);
 			if let Ok(tcp) = tcp
 			{
 				self.tcp = Some(tcp);
+				self.inbox.clear();
 				self.active = true;
-				let _ = std::thread::Builder::new()
-					.name(String::from("Network Update"))
-					.spawn(update);
-				return true;
+				self.attempt = 0;
+				self.token = None;
+				self.password = password;
+				self.inventory.clear();
+				self.chat.clear();
+				self.reason.clear();
+				self.generation = self.generation.wrapping_add(1);
+				self.setStatus(Status::Connecting);
+				return Some(self.generation);
 			}
-			println!("TCP: {}", tcp.unwrap_err());
-			return false;
+			let x = tcp.unwrap_err();
+			error!("TCP: {x}");
+			self.reason = x.to_string();
+			self.setStatus(Status::Failed);
+			return None;
 		}
-		println!("\"{ip}\": {}", addr.unwrap_err());
-		false
+		let x = addr.unwrap_err();
+		error!("\"{ip}\": {x}");
+		self.reason = x.to_string();
+		self.setStatus(Status::Failed);
+		None
 	}
 
 	pub fn isReady(&self) -> bool { self.ready }
@@ -80,267 +321,577 @@ impl Network
 
 	pub fn send(&mut self, msg: ToServer)
 	{
-		if let Some(tcp) = self.tcp.as_mut()
+		if self.tcp.is_some()
 		{
-			let _ = tcp.write_all(&msg.toRaw());
+			let raw = msg.toRaw();
+			self.stats.sent(raw.len());
+			self.tcpOutbox.push((), raw);
+			self.flush();
 		}
 	}
 
 	pub fn hasMessage(&self, topic: String) -> bool
 	{
-		for msg in &self.tcpSequence
+		self.tcpSequence.iter().any(|x| describe(x).is_some_and(|(t, _)| t == topic))
+	}
+
+	pub fn getMessage(&mut self, topic: String) -> json::JsonValue
+	{
+		let index = self.tcpSequence.iter()
+			.position(|x| describe(x).is_some_and(|(t, _)| t == topic));
+		match index
 		{
-			match *msg
-			{
-				ToClient::Setup(..) => if topic == "setup" { return true; }
-			}
+			Some(i) => describe(&self.tcpSequence.remove(i)).unwrap().1,
+			None => json::JsonValue::Null
 		}
-		false
 	}
 
-	pub fn getMessage(&mut self, topic: String) -> json::JsonValue
+	pub fn getInventory(&self) -> &Vec<Slot> { &self.inventory }
+
+	// The server checks the request and answers with the whole inventory.
+	pub fn moveItem(&mut self, from: u8, to: u8) { self.send(ToServer::MoveItem(from, to)); }
+
+	pub fn splitItem(&mut self, from: u8, to: u8, count: u8)
+	{
+		self.send(ToServer::SplitItem(from, to, count));
+	}
+
+	pub fn getChat(&self) -> &VecDeque<(String, String, String)> { &self.chat }
+
+	pub fn sendChat(&mut self, msg: String)
+	{
+		if self.ready && !msg.trim().is_empty() { self.send(ToServer::Chat(msg)); }
+	}
+
+	pub fn getStatus(&self) -> Status { self.status }
+	pub fn getReason(&self) -> String { self.reason.clone() }
+
+	fn setStatus(&mut self, status: Status)
 	{
-		let mut out = json::JsonValue::Null;
-		let mut index = usize::MAX;
-		for i in 0..self.tcpSequence.len()
+		if self.status == status { return; }
+		let was = std::mem::replace(&mut self.status, status);
+		match status
 		{
-			match self.tcpSequence[i]
+			Status::Ready => self.emit(Event::Connected),
+			Status::Reconnecting if was == Status::Ready =>
 			{
-				ToClient::Setup(tickRate, id, port) if topic == "setup" =>
-				{
-					index = i;
-					out = json::object!{
-						tickRate: tickRate,
-						id: id,
-						port: port
-					};
-					break;
-				}
-				_ => {}
+				self.emit(Event::Disconnected(self.reason.clone(), true));
 			}
+			Status::Failed | Status::Disconnected =>
+			{
+				self.emit(Event::Disconnected(self.reason.clone(), false));
+			}
+			_ => {}
 		}
-		if index != usize::MAX
-		{
-			self.tcpSequence.swap_remove(index);
-		}
-		out
 	}
 
-	pub fn setup(&mut self, tickRate: u8, id: u8, port: u16)
+	fn emit(&self, e: Event) { let _ = self.events.send(e); }
+
+	pub fn setup(&mut self, tickRate: u8, id: u8, port: u16, q: Quantization, rules: Rules)
 	{
 		self.id = id;
 		self.tickRate = tickRate;
-		let ip = self.tcp.as_ref().unwrap().peer_addr().unwrap().ip().to_string();
-		self.udpSock = format!("{ip}:{port}").parse().unwrap();
+		self.quantization = q;
+		self.rules = rules;
+		self.inputs.clear();
+		self.sent.clear();
+		self.snapshots.clear();
+		self.serverAck = None;
+		self.lastSnapshot = None;
+		self.stats.resetSequence();
+		let ip = self.tcp.as_ref().unwrap().peer_addr().unwrap().ip();
+		self.udpSock = SocketAddr::new(ip, port);
 		self.ready = true;
-		println!("Network is set up: {tickRate}|{port}|{id}");
+		self.setStatus(Status::Ready);
+		info!("Network is set up: {tickRate}|{port}|{id}");
 	}
 
-	pub fn getState(&self, id: u8) -> (glam::Vec3, glam::Vec2)
+	pub fn getState(&self, id: u8) -> State
 	{
 		self.state.get(&id).cloned().unwrap_or_default()
 	}
 
-	pub fn setState(&mut self, pos: glam::Vec3, angle: glam::Vec2)
+	pub fn setState(&mut self, pos: glam::Vec3, angle: glam::Vec3)
+	{
+		let s = self.state.entry(self.id).or_default();
+		s.pos = pos;
+		s.angle = angle;
+	}
+
+	pub fn setVelocity(&mut self, vel: glam::Vec3)
+	{
+		self.state.entry(self.id).or_default().vel = vel;
+	}
+
+	pub fn setAnimation(&mut self, anim: u8)
+	{
+		self.state.entry(self.id).or_default().anim = anim;
+	}
+
+	pub fn setFlags(&mut self, flags: u16)
+	{
+		self.state.entry(self.id).or_default().flags = flags;
+	}
+
+	pub fn isAuthoritative(&self) -> bool { self.rules.authoritative }
+
+	pub fn getStats(&self) -> &Stats { &self.stats }
+
+	pub fn setInput(&mut self, movement: glam::Vec3, angle: glam::Vec2, buttons: u16)
+	{
+		self.input = (movement, angle, buttons);
+	}
+
+	fn predict(&mut self)
+	{
+		self.inputSeq = self.inputSeq.wrapping_add(1);
+		let i = Input::new(self.inputSeq, self.input.0, self.input.1, self.input.2);
+		let s = self.state.entry(self.id).or_default();
+		movement::simulate(s, &i, &self.rules, 1.0 / self.tickRate as f32);
+		self.inputs.push_back(i);
+		while self.inputs.len() > packet::HISTORY { self.inputs.pop_front(); }
+	}
+
+	fn reconcile(&mut self, f: &Frame, lastInput: Option<u16>)
+	{
+		if let Some(last) = lastInput
+		{
+			while self.inputs.front().is_some_and(|x| !packet::newer(x.seq, last))
+			{
+				self.inputs.pop_front();
+			}
+		}
+		let mut s = f.state(&self.quantization);
+		for i in &self.inputs
+		{
+			movement::simulate(&mut s, i, &self.rules, 1.0 / self.tickRate as f32);
+			s = Frame::quantize(&s, &self.quantization).state(&self.quantization);
+		}
+		self.state.insert(self.id, s);
+	}
+
+	fn sendState(&mut self)
 	{
-		self.state.insert(self.id, (pos, angle));
+		self.seq = self.seq.wrapping_add(1);
+		let mut entries = Snapshot::new();
+		let mut inputs = vec![];
+		if self.rules.authoritative
+		{
+			self.predict();
+			let skip = self.inputs.len().saturating_sub(REDUNDANT_INPUTS);
+			inputs = self.inputs.iter().skip(skip).cloned().collect();
+		}
+		else
+		{
+			let s = self.state.get(&self.id).cloned().unwrap_or_default();
+			entries.insert(self.id, Frame::quantize(&s, &self.quantization));
+		}
+		let msg = Packet
+		{
+			sender: self.id,
+			seq: self.seq,
+			base: self.serverAck,
+			ack: self.lastSnapshot,
+			lastInput: None,
+			entries,
+			inputs
+		};
+		let raw = msg.encode(self.serverAck.and_then(|x| self.sent.get(x)));
+		self.stats.sent(raw.len());
+		self.udpOutbox.push(self.udpSock, raw);
+		self.flush();
+		self.sent.push(msg.seq, msg.entries);
 	}
 
-	pub fn discoveredIP(&mut self) -> String
+	fn receiveState(&mut self, buf: &[u8])
 	{
-		if !self.udpSequence.is_empty()
+		let Some(header) = Packet::header(buf) else { return; };
+		self.stats.received(buf.len());
+		self.stats.sequence(header.seq);
+		if let Some(last) = self.lastSnapshot
+			&& !packet::newer(header.seq, last)
+		{
+			return;
+		}
+		let baseline = header.base.and_then(|x| self.snapshots.get(x));
+		let Some(msg) = Packet::decode(buf, baseline) else { return; };
+		for (id, f) in &msg.entries
 		{
-			return format!(
-				"{}.{}.{}.{}:{}",
-				self.udpSequence.remove(0),
-				self.udpSequence.remove(0),
-				self.udpSequence.remove(0),
-				self.udpSequence.remove(0),
-				u16::from_be_bytes([
-					self.udpSequence.remove(0),
-					self.udpSequence.remove(0),
-				])
-			);
+			if *id == self.id && self.rules.authoritative
+			{
+				self.reconcile(f, msg.lastInput);
+				continue;
+			}
+			self.state.insert(*id, f.state(&self.quantization));
+		}
+		if let Some(ack) = msg.ack
+			&& self.serverAck.is_none_or(|x| packet::newer(ack, x))
+		{
+			self.serverAck = Some(ack);
+		}
+		self.lastSnapshot = Some(msg.seq);
+		self.snapshots.push(msg.seq, msg.entries);
+	}
+
+	pub fn getServers(&self) -> &Vec<Server> { &self.servers }
+	pub fn clearServers(&mut self) { self.servers.clear(); }
+
+	fn found(&mut self, addr: SocketAddr, info: ServerInfo, ping: Duration)
+	{
+		let seen = Instant::now();
+		match self.servers.iter_mut().find(|x| x.addr == addr)
+		{
+			Some(x) => { x.info = info; x.seen = seen; x.ping = ping; }
+			None => self.servers.push(Server { addr, info, seen, ping })
 		}
-		String::new()
 	}
 }
 
-fn update()
+fn update(shared: Arc<Mutex<Shared>>, generation: u32)
 {
 	let mut poll = Poll::new().unwrap();
 	let mut events = Events::with_capacity(64);
-	let n = Window::getNetwork();
 
-	let mut tcpAttempt = 0;
-	let tickTime = Duration::from_secs_f32(1.0 / n.tickRate as f32);
 	let mut tickInstant = Instant::now();
-	let evTime = Duration::from_secs_f32(0.1 / n.tickRate as f32);
-
-	let _ = poll.registry().register(
-		n.tcp.as_mut().unwrap(), Token(0),
-		Interest::WRITABLE
-	);
-	
-	while n.active
-	{
-		if tickInstant.elapsed() >= tickTime && n.ready
-		{
-			let s = n.state.get(&n.id).cloned().unwrap_or_default();
-			match n.udp.send_to(&[
-				&[n.id],
-				&((s.0.x * 100.0) as i16).to_be_bytes() as &[u8],
-				&((s.0.y * 100.0) as i16).to_be_bytes() as &[u8],
-				&((s.0.z * 100.0) as i16).to_be_bytes() as &[u8],
-				&(s.1.x as i16).to_be_bytes() as &[u8],
-				&(s.1.y as i8).to_be_bytes() as &[u8],
-			].concat(), n.udpSock)
+	let mut heartbeat = Instant::now();
+
+	if let Some(tcp) = lock(&shared).tcp.as_mut()
+	{
+		let _ = poll.registry().register(tcp, Token(0), Interest::WRITABLE);
+	}
+
+	loop
+	{
+		let evTime;
+		{
+			let mut n = lock(&shared);
+			if !n.active || n.generation != generation { break; }
+
+			let tickTime = Duration::from_secs_f32(1.0 / n.tickRate as f32);
+			evTime = Duration::from_secs_f32(0.1 / n.tickRate as f32);
+
+			if tickInstant.elapsed() >= tickTime && n.ready && n.connected
 			{
-				Ok(_) => {}
-				Err(x) =>
-				{
-					println!("Error when sending data on UDP:\n{x:#?}");
-				}
+				n.sendState();
+				tickInstant = Instant::now();
+			}
+
+			if n.connected && heartbeat.elapsed() >= HEARTBEAT
+			{
+				let ping = n.stats.ping();
+				n.send(ToServer::Ping(ping));
+				n.stats.updateRates();
+				heartbeat = Instant::now();
+			}
+
+			if n.connected && n.lastSeen.elapsed() > n.timeout
+			{
+				warn!("Server has timed out.");
+				lost(&mut n, poll.registry(), "timeout");
 			}
-			tickInstant = Instant::now();
+
+			if n.retryAt.is_some_and(|x| x <= Instant::now())
+			{
+				retry(&mut n, poll.registry());
+			}
+
+			n.flush();
 		}
 
+		// The lock is released while waiting, so the main thread is never blocked on the socket.
 		let _ = poll.poll(&mut events, Some(evTime));
+
+		let mut n = lock(&shared);
+		if n.generation != generation { break; }
 		for e in events.iter()
 		{
 			if e.token().0 == 0
 			{
-				if e.is_writable()
+				if e.is_writable() && !n.connected
 				{
-					match activate(n, poll.registry())
-					{
-						true => { tcpAttempt = u8::MAX; },
-						false => tcpAttempt += 1
-					};
-					if tcpAttempt == 5 { n.active = false; }
-					if tcpAttempt == u8::MAX
-					{
-						let _ = n.udp.set_broadcast(false);
-					}
+					if !activate(&mut n, poll.registry()) { lost(&mut n, poll.registry(), "refused"); }
 					continue;
 				}
-				
+
 				if e.is_read_closed()
 				{
-					println!("Lost connection with server.");
-					n.active = false;
+					warn!("Lost connection with server.");
+					lost(&mut n, poll.registry(), "closed");
 					break;
 				}
-				
-				let mut buf = vec![];
+
+				let mut received = 0;
 				let mut b = [0u8; 256];
-				if let Ok(size) = n.tcp.as_mut().unwrap().read(&mut b)
+				while let Ok(size) = n.tcp.as_mut().unwrap().read(&mut b)
 				{
-					buf.append(&mut b[..size].to_vec());
+					if size == 0 { break; }
+					n.inbox.extend_from_slice(&b[..size]);
+					received += size;
+				}
+				if received > 0
+				{
+					n.lastSeen = Instant::now();
+					n.stats.received(received);
+				}
+				let (messages, used) = ToClient::fromRaw(&n.inbox);
+				n.inbox.drain(..used);
+				for msg in messages
+				{
+					receive(&mut n, msg);
 				}
-				n.tcpSequence.append(&mut ToClient::fromRaw(buf));
 			}
 			if e.token().0 == 1
 			{
-				// xxxxxxxx xxxxxxxx xxxxxxxx xxxxxxxx xxxxxxxx xxxxxxxx xxxxxxxx xxxxxxxx
-				// 1  ]2        ]3       ]6     ]_____ 4                 5               ]
-				// 1 - id, 2 - yaw, 3 - pitch, 4 - x, 5 - y, 6 - controls
-
-				let mut buf = [0u8; 10];
-				while let Ok(_) = n.udp.recv_from(&mut buf)
+				let mut buf = [0u8; 1500];
+				while let Ok((size, _)) = n.udp.recv_from(&mut buf)
 				{
-					let id = buf[0];
-					let x = i16::from_be_bytes([buf[1], buf[2]]);
-					let y = i16::from_be_bytes([buf[3], buf[4]]);
-					let z = i16::from_be_bytes([buf[5], buf[6]]);
-					let yaw = i16::from_be_bytes([buf[7], buf[8]]);
-					let pitch = i8::from_be_bytes([buf[9]]);
-					n.state.insert(id, (
-						glam::vec3(
-							x as f32 * 0.01,
-							y as f32 * 0.01,
-							z as f32 * 0.01
-						),
-						glam::vec2(yaw as f32, pitch as f32)
-					));
+					n.lastSeen = Instant::now();
+					n.receiveState(&buf[..size]);
 				}
 			}
 		}
 	}
 }
 
-fn activate(n: &mut Network, reg: &Registry) -> bool
+fn receive(n: &mut Shared, msg: ToClient)
 {
-	let tcp = n.tcp.as_mut().unwrap();
-	if tcp.peer_addr().is_err()
+	if let Some((topic, data)) = describe(&msg)
+	{
+		n.emit(Event::Message(topic, data));
+	}
+	match msg
 	{
-		if let Ok(socket) = TcpStream::connect(n.tcpSock)
+		ToClient::Ping(id) => n.send(ToServer::Pong(id)),
+		ToClient::Pong(id) => n.stats.pong(id),
+		ToClient::Session(token, timeout) =>
+		{
+			n.token = Some(token);
+			n.timeout = Duration::from_secs(timeout as u64);
+		}
+		ToClient::Setup(tickRate, id, port, q, rules) =>
+		{
+			let first = !n.ready;
+			n.setup(tickRate, id, port, q, rules);
+			if first { n.tcpSequence.push(msg); }
+		}
+		ToClient::PlayerLeft(id) =>
+		{
+			n.state.remove(&id);
+			n.tcpSequence.push(msg);
+		}
+		ToClient::Rejected(reason) =>
+		{
+			warn!("Server has rejected the connection: {reason}");
+			n.reason = format!("rejected: {}", describe(&msg).unwrap().1["reason"]);
+			n.attempt = n.reconnect.attempts;
+			n.token = None;
+			n.tcpSequence.push(msg);
+		}
+		ToClient::Inventory(slots) => n.inventory = slots,
+		ToClient::Teleport(_) => n.tcpSequence.push(msg),
+		ToClient::TickRate(rate) =>
 		{
-			let _ = reg.deregister(tcp);
-			*tcp = socket;
-			let _ = reg.register(
-				tcp, Token(0),
-				Interest::WRITABLE
-			);
+			info!("Server tick rate is now {rate}.");
+			n.tickRate = rate;
+			n.tcpSequence.push(msg);
 		}
-		return false;
+		ToClient::Chat(time, user, text) =>
+		{
+			n.chat.push_back((time, user, text));
+			while n.chat.len() > CHAT_HISTORY { n.chat.pop_front(); }
+		}
+	}
+}
+
+// Topic and Lua-facing data of a message, if scripts are interested in it.
+fn describe(msg: &ToClient) -> Option<(&'static str, json::JsonValue)>
+{
+	match *msg
+	{
+		ToClient::Setup(tickRate, id, port, q, rules) => Some(("setup", json::object!{
+			tickRate: tickRate,
+			id: id,
+			port: port,
+			posPrecision: q.position,
+			velPrecision: q.velocity,
+			authoritative: rules.authoritative,
+			moveSpeed: rules.speed
+		})),
+		ToClient::PlayerLeft(id) => Some(("playerLeft", json::object!{ id: id })),
+		ToClient::Rejected(reason) => Some(("rejected", json::object!{
+			reason: match reason
+			{
+				message::REJECT_FULL => "full",
+				message::REJECT_PASSWORD => "password",
+				message::REJECT_KICKED => "kicked",
+				message::REJECT_BANNED => "banned",
+				message::REJECT_SHUTDOWN => "shutdown",
+				_ => "unknown"
+			}
+		})),
+		ToClient::Inventory(ref slots) => Some(("inventory", json::object!{
+			slots: slots.iter()
+				.map(|x| json::object!{ id: x.id.clone(), name: x.name.clone(), count: x.count, max: x.max })
+				.collect::<Vec<_>>()
+		})),
+		ToClient::Teleport(ref cp) => Some(("teleport", json::object!{ checkpoint: cp.clone() })),
+		ToClient::TickRate(rate) => Some(("tickRate", json::object!{ tickRate: rate })),
+		ToClient::Chat(ref time, ref user, ref text) => Some(("chat", json::object!{
+			time: time.clone(),
+			user: user.clone(),
+			msg: text.clone()
+		})),
+		_ => None
 	}
+}
+
+fn activate(n: &mut Shared, reg: &Registry) -> bool
+{
+	let tcp = n.tcp.as_mut().unwrap();
+	if tcp.peer_addr().is_err() { return false; }
 	let _ = reg.reregister(tcp, Token(0), Interest::READABLE);
 	let _ = reg.register(&mut n.udp, Token(1), Interest::READABLE);
-	n.send(ToServer::Setup(n.udp.local_addr().unwrap().port()));
-	n.udpSequence.clear();
+	let port = n.udp.local_addr().unwrap().port();
+	match n.token
+	{
+		Some(token) => n.send(ToServer::Resume(token, port)),
+		None => n.send(ToServer::Setup(port, n.password.clone()))
+	}
+	let _ = n.udp.set_broadcast(false);
+	n.setStatus(Status::Handshaking);
+	n.connected = true;
+	n.attempt = 0;
+	n.lastSeen = Instant::now();
 	true
 }
 
-pub fn search()
+fn lost(n: &mut Shared, reg: &Registry, reason: &str)
+{
+	// A rejection has already explained why we are giving up.
+	if n.reason.is_empty() || n.attempt < n.reconnect.attempts
+	{
+		n.reason = reason.to_string();
+	}
+	if let Some(tcp) = n.tcp.as_mut()
+	{
+		let _ = reg.deregister(tcp);
+		let _ = tcp.shutdown(std::net::Shutdown::Both);
+	}
+	if n.connected { let _ = reg.deregister(&mut n.udp); }
+	n.connected = false;
+	n.tcpOutbox.clear();
+
+	if n.attempt >= n.reconnect.attempts
+	{
+		warn!("Giving up on reconnecting.");
+		n.active = false;
+		n.setStatus(Status::Failed);
+		return;
+	}
+	let delay = n.reconnect.delay
+		.saturating_mul(1 << n.attempt.min(16))
+		.min(n.reconnect.maxDelay);
+	info!("Reconnecting in {:.1}s...", delay.as_secs_f32());
+	n.setStatus(Status::Reconnecting);
+	n.retryAt = Some(Instant::now() + delay);
+}
+
+fn retry(n: &mut Shared, reg: &Registry)
+{
+	n.retryAt = None;
+	n.attempt += 1;
+	match TcpStream::connect(n.tcpSock)
+	{
+		Ok(mut tcp) =>
+		{
+			let _ = reg.register(&mut tcp, Token(0), Interest::WRITABLE);
+			n.tcp = Some(tcp);
+			n.inbox.clear();
+		}
+		Err(x) =>
+		{
+			error!("TCP: {x}");
+			lost(n, reg, &x.to_string());
+		}
+	}
+}
+
+// Asks for servers over IPv4 broadcast and the IPv6 link-local multicast group.
+fn search(shared: Arc<Mutex<Shared>>)
 {
 	let mut poll = Poll::new()
 		.expect("Failed to create socket selector");
 	let mut events = Events::with_capacity(16);
 
-	let mut udp = UdpSocket::bind(
-		"0.0.0.0:0".parse().unwrap()
-	).expect("Failed to create UDP socket");
-	udp.set_broadcast(true).expect("Failed to enable broadcast");
+	let mut sockets = vec![];
+	if let Ok(udp) = UdpSocket::bind(localAddr(&discovery(false)))
+	{
+		match udp.set_broadcast(true)
+		{
+			Ok(_) => sockets.push((udp, discovery(false))),
+			Err(x) => warn!("Cannot enable broadcast: {x}")
+		}
+	}
+	if let Ok(udp) = UdpSocket::bind(localAddr(&discovery(true)))
+	{
+		sockets.push((udp, discovery(true)));
+	}
+	if sockets.is_empty() { panic!("Failed to create UDP socket"); }
+
+	for (i, (udp, _)) in sockets.iter_mut().enumerate()
+	{
+		poll.registry().register(
+			udp, Token(i),
+			Interest::READABLE
+		).expect("Failed to add UDP socket to registry");
+	}
 
-	poll.registry().register(
-		&mut udp, Token(0),
-		Interest::READABLE
-	).expect("Failed to add UDP socket to registry");
+	info!("Started searching...");
 
-	println!("Started searching...");
+	let start = Instant::now();
+	for (udp, to) in &sockets
+	{
+		if let Err(x) = udp.send_to(&[], *to) { warn!("Search at {to}: {x}"); }
+	}
 
-	let _ = udp.send_to(&[], "255.255.255.255:26225".parse().unwrap());
-	
 	'search: loop
 	{
 		let _ = poll.poll(
 			&mut events,
 			Some(Duration::from_secs(2))
 		);
-		
+
 		if events.is_empty() { break 'search; }
-		
-		let mut buf = [0u8; 2];
-		while let Ok((size, addr)) = udp.recv_from(&mut buf)
+
+		for (udp, _) in &sockets
 		{
-			if size != 2 { continue; }
-			match addr.ip()
+			let mut buf = [0u8; 1024];
+			while let Ok((size, addr)) = udp.recv_from(&mut buf)
 			{
-				std::net::IpAddr::V4(a) =>
-				{
-					Window::getNetwork().udpSequence.append(&mut [
-						&a.octets() as &[u8],
-						&buf as &[u8]
-					].concat());
-				}
-				std::net::IpAddr::V6(_) =>
-				{
-					println!("Cannot use IPv6 yet.")
-				}
+				let Some(info) = ServerInfo::fromRaw(&buf[..size]) else { continue; };
+				let addr = SocketAddr::new(addr.ip().to_canonical(), info.port);
+				lock(&shared).found(addr, info, start.elapsed());
 			}
 		}
 	}
 
-	println!("Stopped searching.");
-}
\ No newline at end of file
+	info!("Stopped searching.");
+}
+
+fn discovery(v6: bool) -> SocketAddr
+{
+	if v6
+	{
+		SocketAddr::new(crate::envell::net::DISCOVERY_GROUP.into(), crate::envell::net::DISCOVERY_PORT)
+	}
+	else
+	{
+		SocketAddr::new(Ipv4Addr::BROADCAST.into(), crate::envell::net::DISCOVERY_PORT)
+	}
+}
+
+fn localAddr(remote: &SocketAddr) -> SocketAddr
+{
+	if remote.is_ipv6() { SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0) }
+	else { SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0) }
+}
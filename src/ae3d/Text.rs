@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::ae3d::Camera::Camera;
+use crate::warn;
 
 use super::{Camera::Drawable, Transformable::Transformable2D, Window::Window};
 
@@ -104,7 +105,7 @@ impl Font
 		let g = self.glyphs.get(&(c as u16));
 		match g
 		{
-			None => { println!("Символ не найден: {c}({})", c as u16); g },
+			None => { warn!("Glyph not found: {c} ({})", c as u16); g },
 			Some(_) => g
 		}
 	}
@@ -1,6 +1,7 @@
 use mlua::{Function, Lua, Value};
 
 use crate::ae3d::{Camera::Camera, Window::Window};
+use crate::error;
 
 use super::{bind, Camera::Drawable, Sprite::Sprite, Text::Text};
 
@@ -39,6 +40,7 @@ impl Object
 		bind::window(&obj.script);
 		bind::world(&obj.script);
 		bind::network(&obj.script);
+		bind::log(&obj.script);
 		bind::profiler(&obj.script);
 		bind::math(&obj.script);
 		
@@ -148,7 +150,7 @@ impl UI
 		);
 		if src.is_err()
 		{
-			println!("Failed to load UI: {}", src.unwrap_err());
+			error!("Failed to load UI: {}", src.unwrap_err());
 			return;
 		}
 		let src = src.unwrap();
@@ -171,7 +173,7 @@ impl UI
 					Ok(_) => {},
 					Err(x) =>
 					{
-						println!("Object Init: {name}\n{x}\n");
+						error!("Object Init: {name}\n{x}");
 					}
 				}
 			}
@@ -203,7 +205,7 @@ impl UI
 					Ok(_) => {},
 					Err(x) =>
 					{
-						println!("Object Update: {name}\n{x}\n");
+						error!("Object Update: {name}\n{x}");
 						let _ = obj.script.globals().raw_remove("Update");
 					}
 				}
@@ -212,6 +214,11 @@ impl UI
 		Window::getProfiler().save("uiUpdate".to_string());
 	}
 
+	pub fn getScripts(&self) -> impl Iterator<Item = &mlua::Lua>
+	{
+		self.objects.iter().map(|x| &x.script)
+	}
+
 	pub fn requestLoad(&mut self, path: String)
 	{
 		self.reload = path;
@@ -268,7 +275,7 @@ impl Drawable for UI
 					Ok(_) => {},
 					Err(x) =>
 					{
-						println!("Object Draw: {name}\n{x}\n");
+						error!("Object Draw: {name}\n{x}");
 						let _ = obj.script.globals().raw_remove("Draw");
 					}
 				}
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use glfw::Context;
 
-use crate::ae3d::{Network::Network, Profiler::Profiler, World::World};
+use crate::ae3d::{bind, Network::Network, Profiler::Profiler, World::World};
+use crate::envell::log;
+use crate::{error, warn, info, debug};
 
 use super::{Camera::Camera, Programmable::{Programmable, Variable}, UI::UI};
 
@@ -88,6 +90,8 @@ impl Window
 		let mut maximized = false;
 		let mut uiPath = "";
 		let mut iconPath = "";
+		let mut logFilter = log::Filter::default();
+		let mut logFile = "res/logs/ae3d.log";
 
 		for (name, section) in cfg.entries()
 		{
@@ -123,8 +127,20 @@ impl Window
 					{
 						iconPath = y.as_str().unwrap();
 					}
+					if x == "logLevel"
+					{
+						logFilter = y.as_str().and_then(log::Filter::parse).unwrap_or(logFilter);
+					}
+					if x == "logFile"
+					{
+						logFile = y.as_str().unwrap();
+					}
 				}
 			}
+			if name == "network"
+			{
+				i.net.lock().configure(section);
+			}
 			if name == "custom"
 			{
 				for (name, value) in section.entries()
@@ -143,6 +159,8 @@ impl Window
 			}
 		}
 
+		log::init(logFilter, logFile);
+
 		if fullscreen
 		{
 			vsync = true;
@@ -183,11 +201,11 @@ impl Window
 			{
 				stb_image::image::LoadResult::Error(x) =>
 				{
-					println!("Failed to load icon: {x}")
+					error!("Failed to load icon: {x}")
 				}
 				stb_image::image::LoadResult::ImageF32(_) =>
 				{
-					println!("Cannot load F32 images yet")
+					warn!("Cannot load F32 images yet")
 				}
 				stb_image::image::LoadResult::ImageU8(data) =>
 				{
@@ -234,9 +252,11 @@ impl Window
 			gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
 			gl::Viewport(0, 0, size.x as i32, size.y as i32);
 
-			println!("{}", Self::getGLString(gl::VERSION));
-			println!("{}", Self::getGLString(gl::VENDOR));
-			println!("{}", Self::getGLString(gl::RENDERER));
+			info!("OpenGL {}, {}, {}",
+				Self::getGLString(gl::VERSION),
+				Self::getGLString(gl::VENDOR),
+				Self::getGLString(gl::RENDERER)
+			);
 		}
 
 		i.ui.load(uiPath);
@@ -283,7 +303,7 @@ impl Window
 				glfw::WindowEvent::Close =>
 				{
 					window.set_should_close(true);
-					i.net.reset();
+					i.net.lock().reset();
 				}
 				glfw::WindowEvent::MouseButton(b, a, m) =>
 				{
@@ -318,13 +338,18 @@ impl Window
 							.collect::<Vec<String>>()
 					);
 				}
-				e => println!("{e:?}")
+				e => debug!("{e:?}")
 			}
 		}
 
 		i.profiler.save("winUpdate".to_string());
 
 		i.ui.updateReload();
+		for e in i.net.pollEvents()
+		{
+			bind::networkEvent(i.world.getScript(), &e);
+			for s in i.ui.getScripts() { bind::networkEvent(s, &e); }
+		}
 		i.world.update();
 		i.ui.update();
 	}
@@ -350,7 +375,7 @@ impl Window
 		unsafe
 		{
 			let x = gl::GetError();
-			if x != 0 { println!("GL Error: {x}"); }
+			if x != 0 { error!("GL Error: {x}"); }
 		}
 		Window::getInstance().window.as_mut().unwrap().swap_buffers();
 	}
@@ -546,20 +571,20 @@ impl Window
 			},
 			stb_image::image::LoadResult::ImageF32(_) =>
 			{
-				println!("Failed to load texture from {path}: unable to read F32 type.");
+				error!("Failed to load texture from {path}: unable to read F32 type.");
 				0
 			}
 			stb_image::image::LoadResult::Error(s) =>
 			{
-				println!("Error on reading texture from {path}:\n{s}");
+				error!("Error on reading texture from {path}:\n{s}");
 				0
 			}
 		}
 	}
 
-	pub fn getNetwork() -> &'static mut Network
+	pub fn getNetwork() -> &'static Network
 	{
-		&mut Window::getInstance().net
+		&Window::getInstance().net
 	}
 
 	pub fn getWorld() -> &'static mut World
@@ -577,14 +602,23 @@ impl Window
 	pub fn launchServer()
 	{
 		let i = Window::getInstance();
+		if i.server.as_ref().is_some_and(|x| !x.is_finished()) { return; }
 		if let Ok(s) = std::thread::Builder::new()
 			.name(String::from("Server"))
-			.spawn(crate::envell::main)
+			.spawn(|| crate::envell::main(crate::envell::options::Options::default(), None))
 		{
 			i.server = Some(s);
 		}
 	}
 
+	// Players are sent away and the game is saved before this returns.
+	pub fn stopServer()
+	{
+		let Some(s) = Window::getInstance().server.take() else { return; };
+		crate::envell::stop();
+		let _ = s.join();
+	}
+
 	pub fn setMousePos(pos: glam::Vec2)
 	{
 		let w = Window::getInstance().window.as_mut().unwrap();
@@ -3,6 +3,7 @@ use std::collections::HashMap;
 use mlua::Lua;
 
 use crate::ae3d::{bind, Camera::{Camera, Drawable}, Entity::Entity, Window::Window};
+use crate::error;
 
 pub struct World
 {
@@ -43,13 +44,14 @@ impl World
 		match self.script.load(src).exec()
 		{
 			Ok(_) => {}
-			Err(x) => { println!("Не удалось загрузить мир: {x}"); return; }
+			Err(x) => { error!("Failed to load world: {x}"); return; }
 		}
 
 		self.init = true;
 
 		bind::window(&self.script);
 		bind::network(&self.script);
+		bind::log(&self.script);
 		bind::world(&self.script);
 		bind::shaders(&self.script);
 		bind::camera(&self.script);
@@ -93,6 +95,7 @@ impl World
 	}
 
 	pub fn getName(&self) -> String { self.name.clone() }
+	pub fn getScript(&self) -> &Lua { &self.script }
 }
 
 impl Drawable for World
@@ -3,8 +3,10 @@ use mlua::{Lua, Table};
 use crate::ae3d::UI;
 use crate::ae3d::{glTF::GLTF, Mesh::Mesh, Skeleton::Skeleton};
 use crate::ae3d::{Entity::Entity, Programmable::Variable, World::World};
+use crate::envell::script::{call, fromJSON};
+use crate::{error, warn};
 
-use super::Window::Window;
+use super::{Network::Event, Window::Window};
 
 fn getScript(id: String) -> &'static mlua::Lua
 {
@@ -38,9 +40,8 @@ pub fn execFunc(script: &Lua, func: &str)
 			Ok(_) => {}
 			Err(x) =>
 			{
-				println!("Failed to call '{func}' function:\n{x}");
-				println!("Script: {}\n",
-					script.globals().raw_get::<String>("ScriptID").unwrap()
+				error!("Script '{}' failed to call '{func}':\n{x}",
+					script.globals().raw_get::<String>("ScriptID").unwrap_or_default()
 				);
 				let _ = script.globals().raw_remove(func);
 			}
@@ -48,6 +49,22 @@ pub fn execFunc(script: &Lua, func: &str)
 	}
 }
 
+pub fn networkEvent(script: &Lua, e: &Event)
+{
+	match e
+	{
+		Event::Connected => call(script, "OnConnected", ()),
+		Event::Disconnected(reason, reconnecting) =>
+		{
+			call(script, "OnDisconnected", (reason.clone(), *reconnecting));
+		}
+		Event::Message(topic, data) =>
+		{
+			call(script, "OnNetworkMessage", (*topic, fromJSON(script, data)));
+		}
+	}
+}
+
 fn func<F, A, R>(s: &Lua, t: &mlua::Table, name: &str, f: F)
 where
 	F: Fn(&Lua, A) -> mlua::Result<R>
@@ -215,6 +232,13 @@ pub fn window(script: &Lua)
 		Ok(())
 	}).unwrap());
 
+	let _ = table.raw_set("stopServer",
+	script.create_function(|_, _: ()|
+	{
+		Window::stopServer();
+		Ok(())
+	}).unwrap());
+
 	let _ = table.raw_set("size",
 	script.create_function(|_, _: ()|
 	{
@@ -357,7 +381,7 @@ pub fn window(script: &Lua)
 			.load(code.1)
 			.exec()
 		{
-			println!("{}: {x}", code.0);
+			error!("{}: {x}", code.0);
 		}
 		Ok(())
 	}).unwrap());
@@ -730,18 +754,91 @@ pub fn network(s: &Lua)
 {
 	let t = s.create_table().unwrap();
 
-	func(s, &t, "connect", |_, ip: String| Ok(Window::getNetwork().connect(ip)));
-	func(s, &t, "disconnect", |_, _: ()| { Window::getNetwork().reset(); Ok(()) });
-	func(s, &t, "isReady", |_, _: ()| Ok(Window::getNetwork().isReady()));
-	func(s, &t, "isActive", |_, _: ()| Ok(Window::getNetwork().isActive()));
-	func(s, &t, "id", |_, _: ()| Ok(Window::getNetwork().getID()));
-	func(s, &t, "discovered", |_, _: ()| { Ok(Window::getNetwork().discoveredIP()) });
+	func(s, &t, "connect", |_, (ip, pwd): (String, Option<String>)|
+	{
+		Ok(Window::getNetwork().connect(ip, pwd.unwrap_or_default()))
+	});
+	func(s, &t, "disconnect", |_, _: ()| { Window::getNetwork().lock().reset(); Ok(()) });
+	func(s, &t, "isReady", |_, _: ()| Ok(Window::getNetwork().lock().isReady()));
+	func(s, &t, "isActive", |_, _: ()| Ok(Window::getNetwork().lock().isActive()));
+	func(s, &t, "status", |_, _: ()|
+	{
+		let n = Window::getNetwork().lock();
+		Ok((n.getStatus().name(), n.getReason()))
+	});
+	func(s, &t, "id", |_, _: ()| Ok(Window::getNetwork().lock().getID()));
+	func(s, &t, "servers", |s, _: ()|
+	{
+		let list = s.create_table().unwrap();
+		for x in Window::getNetwork().lock().getServers()
+		{
+			let t = s.create_table().unwrap();
+			let _ = t.raw_set("address", x.addr.to_string());
+			let _ = t.raw_set("name", x.info.name.clone());
+			let _ = t.raw_set("version", x.info.version);
+			let _ = t.raw_set("players", x.info.players);
+			let _ = t.raw_set("maxPlayers", x.info.maxPlayers);
+			let _ = t.raw_set("password", x.info.password);
+			let _ = t.raw_set("checkpoint", x.info.checkpoint.clone());
+			let _ = t.raw_set("ping", x.ping.as_secs_f32() * 1000.0);
+			let _ = t.raw_set("age", x.seen.elapsed().as_secs_f32());
+			let _ = t.raw_set("compatible", x.info.version == crate::envell::packet::VERSION);
+			let _ = list.raw_push(t);
+		}
+		Ok(list)
+	});
+	func(s, &t, "clearServers", |_, _: ()| { Window::getNetwork().lock().clearServers(); Ok(()) });
 
 	func(s, &t, "search", |_, _: ()|
 	{
-		let _ = std::thread::Builder::new()
-			.name(String::from("Server Search"))
-			.spawn(|| crate::ae3d::Network::search());
+		Window::getNetwork().search();
+		Ok(())
+	});
+
+	// Slots are numbered from 1 in Lua and from 0 on the wire.
+	func(s, &t, "inventory", |s, _: ()|
+	{
+		let list = s.create_table().unwrap();
+		for x in Window::getNetwork().lock().getInventory()
+		{
+			let t = s.create_table().unwrap();
+			let _ = t.raw_set("id", x.id.clone());
+			let _ = t.raw_set("name", x.name.clone());
+			let _ = t.raw_set("count", x.count);
+			let _ = t.raw_set("max", x.max);
+			let _ = list.raw_push(t);
+		}
+		Ok(list)
+	});
+	func(s, &t, "moveItem", |_, (from, to): (u8, u8)|
+	{
+		if from == 0 || to == 0 { return Ok(()); }
+		Window::getNetwork().lock().moveItem(from - 1, to - 1);
+		Ok(())
+	});
+	func(s, &t, "splitItem", |_, (from, to, count): (u8, u8, u8)|
+	{
+		if from == 0 || to == 0 { return Ok(()); }
+		Window::getNetwork().lock().splitItem(from - 1, to - 1, count);
+		Ok(())
+	});
+	// New messages also arrive as the "chat" network message.
+	func(s, &t, "chat", |s, _: ()|
+	{
+		let list = s.create_table().unwrap();
+		for (time, user, msg) in Window::getNetwork().lock().getChat()
+		{
+			let t = s.create_table().unwrap();
+			let _ = t.raw_set("time", time.clone());
+			let _ = t.raw_set("user", user.clone());
+			let _ = t.raw_set("msg", msg.clone());
+			let _ = list.raw_push(t);
+		}
+		Ok(list)
+	});
+	func(s, &t, "sendChat", |_, msg: String|
+	{
+		Window::getNetwork().lock().sendChat(msg);
 		Ok(())
 	});
 
@@ -754,23 +851,33 @@ pub fn network(s: &Lua)
 	
 	func(s, &t, "setup", |_, data: Table|
 	{
-		Window::getNetwork().setup(
+		Window::getNetwork().lock().setup(
 			data.raw_get("tickRate").unwrap_or(10),
 			data.raw_get("id").unwrap_or(0),
-			data.raw_get("port").unwrap_or(26225)
+			data.raw_get("port").unwrap_or(26225),
+			crate::envell::packet::Quantization
+			{
+				position: data.raw_get("posPrecision").unwrap_or(100),
+				velocity: data.raw_get("velPrecision").unwrap_or(100)
+			},
+			crate::envell::movement::Rules
+			{
+				authoritative: data.raw_get("authoritative").unwrap_or(false),
+				speed: data.raw_get("moveSpeed").unwrap_or(200)
+			}
 		);
 		Ok(())
 	});
 
 	func(s, &t, "hasMessage", |_, topic: String|
 	{
-		Ok(Window::getNetwork().hasMessage(topic))
+		Ok(Window::getNetwork().lock().hasMessage(topic))
 	});
 
 	func(s, &t, "getMessage", |s, topic: String|
 	{
 		let t = s.create_table().unwrap();
-		let data = Window::getNetwork().getMessage(topic.clone());
+		let data = Window::getNetwork().lock().getMessage(topic.clone());
 		match topic.as_str()
 		{
 			"setup" =>
@@ -778,30 +885,149 @@ pub fn network(s: &Lua)
 				let _ = t.raw_set("tickRate", data["tickRate"].as_u8().unwrap());
 				let _ = t.raw_set("port", data["port"].as_u16().unwrap());
 				let _ = t.raw_set("id", data["id"].as_u8().unwrap());
+				let _ = t.raw_set("posPrecision", data["posPrecision"].as_u16().unwrap());
+				let _ = t.raw_set("velPrecision", data["velPrecision"].as_u16().unwrap());
+				let _ = t.raw_set("authoritative", data["authoritative"].as_bool().unwrap());
+				let _ = t.raw_set("moveSpeed", data["moveSpeed"].as_u16().unwrap());
+			}
+			"playerLeft" =>
+			{
+				let _ = t.raw_set("id", data["id"].as_u8().unwrap());
+			}
+			"rejected" =>
+			{
+				let _ = t.raw_set("reason", data["reason"].as_str().unwrap_or("unknown"));
+			}
+			"teleport" =>
+			{
+				let _ = t.raw_set("checkpoint", data["checkpoint"].as_str().unwrap_or(""));
 			}
-			x => { println!("Unknown topic: {x}"); }
+			"tickRate" =>
+			{
+				let _ = t.raw_set("tickRate", data["tickRate"].as_u8().unwrap_or(10));
+			}
+			x => { warn!("Unknown topic: {x}"); }
 		}
 		Ok(t)
 	});
 
-	func(s, &t, "setState", |_, data: (f32, f32, f32, f32, f32)|
+	func(s, &t, "setState", |_, data: (f32, f32, f32, f32, f32, Option<f32>)|
 	{
-		Window::getNetwork().setState(
+		Window::getNetwork().lock().setState(
 			glam::vec3(data.0, data.1, data.2),
-			glam::vec2(data.3, data.4)
+			glam::vec3(data.3, data.4, data.5.unwrap_or(0.0))
 		);
 		Ok(())
 	});
 
 	func(s, &t, "getState", |_, id: u8|
 	{
-		let s = Window::getNetwork().getState(id);
-		Ok((s.0.x, s.0.y, s.0.z, s.1.x, s.1.y))
+		let s = Window::getNetwork().lock().getState(id);
+		Ok((s.pos.x, s.pos.y, s.pos.z, s.angle.x, s.angle.y, s.angle.z))
+	});
+
+	func(s, &t, "setVelocity", |_, v: (f32, f32, f32)|
+	{
+		Window::getNetwork().lock().setVelocity(glam::vec3(v.0, v.1, v.2));
+		Ok(())
+	});
+
+	func(s, &t, "getVelocity", |_, id: u8|
+	{
+		let v = Window::getNetwork().lock().getState(id).vel;
+		Ok((v.x, v.y, v.z))
+	});
+
+	func(s, &t, "setAnimation", |_, anim: u8|
+	{
+		Window::getNetwork().lock().setAnimation(anim);
+		Ok(())
+	});
+
+	func(s, &t, "getAnimation", |_, id: u8|
+	{
+		Ok(Window::getNetwork().lock().getState(id).anim)
+	});
+
+	func(s, &t, "setFlags", |_, flags: u16|
+	{
+		Window::getNetwork().lock().setFlags(flags);
+		Ok(())
+	});
+
+	func(s, &t, "getFlags", |_, id: u8|
+	{
+		Ok(Window::getNetwork().lock().getState(id).flags)
+	});
+
+	func(s, &t, "stats", |s, _: ()|
+	{
+		let t = s.create_table().unwrap();
+		let x = Window::getNetwork().lock().getStats().clone();
+		let _ = t.raw_set("rtt", x.rtt.as_secs_f32() * 1000.0);
+		let _ = t.raw_set("loss", x.loss());
+		let _ = t.raw_set("lost", x.lost);
+		let _ = t.raw_set("sentBytes", x.sentBytes);
+		let _ = t.raw_set("sentPackets", x.sentPackets);
+		let _ = t.raw_set("receivedBytes", x.receivedBytes);
+		let _ = t.raw_set("receivedPackets", x.receivedPackets);
+		let _ = t.raw_set("sendRate", x.sendRate);
+		let _ = t.raw_set("receiveRate", x.receiveRate);
+		Ok(t)
+	});
+
+	func(s, &t, "setSimulation", |_, x: Table|
+	{
+		let d = Window::getNetwork().lock().getSimulation();
+		Window::getNetwork().lock().setSimulation(crate::envell::netsim::Conditions
+		{
+			enabled: x.raw_get("enabled").unwrap_or(d.enabled),
+			latency: x.raw_get("latency").unwrap_or(d.latency),
+			jitter: x.raw_get("jitter").unwrap_or(d.jitter),
+			loss: x.raw_get::<u8>("loss").unwrap_or(d.loss).min(100),
+			duplicate: x.raw_get::<u8>("duplicate").unwrap_or(d.duplicate).min(100),
+			reorder: x.raw_get::<u8>("reorder").unwrap_or(d.reorder).min(100)
+		});
+		Ok(())
+	});
+
+	func(s, &t, "getSimulation", |s, _: ()|
+	{
+		let t = s.create_table().unwrap();
+		let c = Window::getNetwork().lock().getSimulation();
+		let _ = t.raw_set("enabled", c.enabled);
+		let _ = t.raw_set("latency", c.latency);
+		let _ = t.raw_set("jitter", c.jitter);
+		let _ = t.raw_set("loss", c.loss);
+		let _ = t.raw_set("duplicate", c.duplicate);
+		let _ = t.raw_set("reorder", c.reorder);
+		Ok(t)
+	});
+
+	func(s, &t, "isAuthoritative", |_, _: ()|
+	{
+		Ok(Window::getNetwork().lock().isAuthoritative())
+	});
+
+	func(s, &t, "setInput", |_, i: (f32, f32, f32, f32, f32, Option<u16>)|
+	{
+		Window::getNetwork().lock().setInput(
+			glam::vec3(i.0, i.1, i.2),
+			glam::vec2(i.3, i.4),
+			i.5.unwrap_or(0)
+		);
+		Ok(())
 	});
 
 	let _ = s.globals().set("network", t);
 }
 
+// The same log is shown in the in-game console.
+pub fn log(s: &Lua)
+{
+	crate::envell::script::log(s);
+}
+
 pub fn camera(s: &Lua)
 {
 	let t = s.create_table().unwrap();
@@ -926,96 +1152,5 @@ pub fn camera(s: &Lua)
 // TODO
 pub fn math(s: &Lua)
 {
-	let t = s.create_table().unwrap();
-	
-	func(s, &t, "clamp", |_, x: (f32, f32, f32)|
-	{
-		Ok(x.0.clamp(x.1, x.2))
-	});
-
-	func(s, &t, "rectContains", |_,  x: (Table, Table)|
-	{
-		let p = glam::vec2(
-			x.0.raw_get("x").unwrap_or(0.0),
-			x.0.raw_get("y").unwrap_or(0.0)
-		);
-		let r = glam::vec4(
-			x.1.raw_get("x").unwrap_or(0.0),
-			x.1.raw_get("y").unwrap_or(0.0),
-			x.1.raw_get("w").unwrap_or(0.0),
-			x.1.raw_get("h").unwrap_or(0.0)
-		);
-		Ok(
-			p.x == p.x.clamp(r.x, r.x + r.z) &&
-			p.y == p.y.clamp(r.y, r.y + r.w)
-		)
-	});
-
-	func(s, &t, "rectIntersects", |_, i: (Table, Table)|
-	{
-		let r1 = glam::vec4(
-			i.0.raw_get("x").unwrap_or(0.0),
-			i.0.raw_get("y").unwrap_or(0.0),
-			i.0.raw_get("z").unwrap_or(0.0),
-			i.0.raw_get("w").unwrap_or(0.0),
-		);
-		let r2 = glam::vec4(
-			i.1.raw_get("x").unwrap_or(0.0),
-			i.1.raw_get("y").unwrap_or(0.0),
-			i.1.raw_get("z").unwrap_or(0.0),
-			i.1.raw_get("w").unwrap_or(0.0),
-		);
-		let il = r1.x.max(r2.x);
-		let it = r1.y.max(r2.y);
-		let ir = (r1.x + r1.z).min(r2.x + r2.z);
-		let ib = (r1.y + r1.w).min(r2.y + r2.w);
-		Ok((il < ir) && (it < ib))
-	});
-
-	func(s, &t, "lerp", |_, x: (f32, f32, f32)|
-	{
-		let a = x.0; let b = x.1; let t = x.2;
-		Ok(a * (1.0 - t) + b * t)
-	});
-
-	func(s, &t, "cubicIn", |_, x: f32|
-	{
-		Ok(x.powi(3))
-	});
-
-	func(s, &t, "cubicOut", |_, x: f32|
-	{
-		Ok(1.0 - (1.0 - x).powi(3))
-	});
-
-	func(s, &t, "cubicInOut", |_, x: f32|
-	{
-		Ok(
-			if x < 0.5 { 4.0 * x.powi(3) }
-			else { 1.0 - (-2.0 * x + 2.0).powi(3) * 0.5 }
-		)
-	});
-
-	func(s, &t, "sineIn", |_, x: f32|
-	{
-		Ok(1.0 - (x * std::f32::consts::PI).cos() * 0.5)
-	});
-
-	func(s, &t, "sineOut", |_, x: f32|
-	{
-		Ok((x * std::f32::consts::PI * 0.5).sin())
-	});
-
-	func(s, &t, "sineInOut", |_, x: f32|
-	{
-		Ok(-((x * std::f32::consts::PI).cos() - 1.0) * 0.5)
-	});
-
-	func(s, &t, "round", |_, x: (f32, u8)|
-	{
-		let factor = 10.0_f32.powi(x.1 as i32);
-		Ok((x.0 * factor).round() / factor)
-	});
-
-	let _ = s.globals().raw_set("aemath", t);
-}
\ No newline at end of file
+	crate::envell::script::math(s);
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::ae3d::Window::Window;
+use crate::{error, debug};
 
 #[derive(Default, Debug)]
 pub struct BufferView
@@ -134,9 +135,9 @@ impl GLTF
 	{
 		let mut gltf = Self::default();
 		let src = std::fs::read_to_string(&path);
-		if src.is_err() { println!("Error READ {path}: {src:?}"); return gltf; }
+		if src.is_err() { error!("Error READ {path}: {src:?}"); return gltf; }
 		let src = json::parse(&src.unwrap());
-		if src.is_err() { println!("Error PARSE {path}: {src:?}"); return gltf; }
+		if src.is_err() { error!("Error PARSE {path}: {src:?}"); return gltf; }
 
 		// TODO simplify parser
 
@@ -156,7 +157,7 @@ impl GLTF
 							match std::fs::read(p.join("/"))
 							{
 								Ok(b) => gltf.buffers.push(b),
-								Err(x) => println!("Failed {path}: {x:#?}")
+								Err(x) => error!("Failed {path}: {x:#?}")
 							}
 						}
 					}
@@ -491,7 +492,7 @@ impl GLTF
 				}
 			}
 			else if section.0 == "asset" {}
-			else { println!("{}", section.0); }
+			else { debug!("{}", section.0); }
 		}
 		
 		gltf
@@ -0,0 +1,155 @@
+use std::net::IpAddr;
+
+use crate::envell::{config, http, web::{self, Req, Resp}};
+
+// Requests that may be posted to /api/<topic>, with the same JSON as over the WebSocket.
+const TOPICS: [&str; 10] = [
+	"login", "logout", "changePassword", "newMessage", "saveSettings",
+	"clickButton", "modal", "saveSlot", "playerAction", "unban"
+];
+
+// JSON over plain HTTP for scripts and bots:
+//   GET  /api/status, /api/players, /api/settings, /api/saves, /api/chat?since=<n>
+//   POST /api/<topic> with a JSON body, e.g. /api/login {"password": "..."}
+// Everything but the status needs "Authorization: Bearer <token>" from /api/login.
+// HEAD works wherever GET does, like for static files.
+pub fn route(req: &http::Request, ip: IpAddr) -> Result<Req, u16>
+{
+	let name = name(req);
+	let get = matches!(req.method, http::Method::Get | http::Method::Head);
+	let post = matches!(&req.method, http::Method::Other(x) if x == "POST");
+	match name
+	{
+		"status" if get => Ok(Req::State),
+		"players" if get => Ok(Req::Players),
+		"settings" if get => Ok(Req::GetSettings),
+		"saves" if get => Ok(Req::Saves),
+		"chat" if get => Ok(Req::ChatMessages(
+			param(&req.query, "since").and_then(|x| x.parse().ok()).unwrap_or(0)
+		)),
+		x if post && TOPICS.contains(&x) =>
+		{
+			let body = String::from_utf8_lossy(&req.body);
+			let data = if body.trim().is_empty() { json::JsonValue::Null }
+				else { json::parse(&body).map_err(|_| 400u16)? };
+			web::request(ip, x, data).ok_or(400)
+		}
+		x if allowed(x).is_some() => Err(405),
+		_ => Err(404)
+	}
+}
+
+// Token from "Authorization: Bearer <token>", empty for guests.
+pub fn token(req: &http::Request) -> String
+{
+	req.header("authorization")
+		.and_then(|x| x.strip_prefix("Bearer "))
+		.unwrap_or("")
+		.trim()
+		.to_string()
+}
+
+// Main answers a request with any number of responses.
+// A refusal or a failed modal decides the status, otherwise the first answer is the body.
+pub fn response(answers: Vec<Resp>, head: bool, keepAlive: bool) -> Vec<u8>
+{
+	let mut data = None;
+	for x in answers
+	{
+		match x
+		{
+			Resp::Auth(Some(token), ..) => return reply(200, json::object!{ token: token }, head, keepAlive),
+			Resp::Auth(None, reason, _) if reason == "logout" => return reply(200, json::object!{ ok: true }, head, keepAlive),
+			Resp::Auth(None, reason, wait) if reason == "locked" =>
+			{
+				let body = json::stringify(json::object!{ error: reason, wait: wait });
+				return http::response(429, &[
+					("Content-Type", "application/json"),
+					("Retry-After", &wait.to_string())
+				], body.as_bytes(), head, keepAlive);
+			}
+			Resp::Auth(None, reason, _) =>
+			{
+				let body = json::stringify(json::object!{ error: reason });
+				return http::response(401, &[
+					("Content-Type", "application/json"),
+					("WWW-Authenticate", "Bearer")
+				], body.as_bytes(), head, keepAlive);
+			}
+			Resp::Modal(id) if id.ends_with("-fail") => return reply(409, json::object!{ error: id }, head, keepAlive),
+			Resp::Modal(_) => {}
+			x if data.is_none() => data = Some(body(x)),
+			_ => {}
+		}
+	}
+	reply(200, data.unwrap_or(json::object!{ ok: true }), head, keepAlive)
+}
+
+// Answer to a request route() refused. A 405 lists the methods the endpoint takes.
+pub fn reject(req: &http::Request, status: u16) -> Vec<u8>
+{
+	let allow = if status == 405 { allowed(name(req)) } else { None };
+	let mut headers = vec![("Content-Type", "application/json")];
+	headers.extend(allow.map(|x| ("Allow", x)));
+	let body = json::stringify(json::object!{ error: http::reason(status) });
+	http::response(status, &headers, body.as_bytes(), req.method == http::Method::Head, req.keepAlive())
+}
+
+pub fn error(status: u16, keepAlive: bool) -> Vec<u8>
+{
+	reply(status, json::object!{ error: http::reason(status) }, false, keepAlive)
+}
+
+fn reply(status: u16, data: json::JsonValue, head: bool, keepAlive: bool) -> Vec<u8>
+{
+	http::response(status, &[("Content-Type", "application/json")], json::stringify(data).as_bytes(), head, keepAlive)
+}
+
+fn name(req: &http::Request) -> &str { req.path.strip_prefix("/api/").unwrap_or("") }
+
+fn allowed(name: &str) -> Option<&'static str>
+{
+	match name
+	{
+		"status" | "players" | "settings" | "saves" | "chat" => Some("GET, HEAD"),
+		x if TOPICS.contains(&x) => Some("POST"),
+		_ => None
+	}
+}
+
+// The panel gets forms and localized tables, scripts get plain values.
+fn body(x: Resp) -> json::JsonValue
+{
+	match x
+	{
+		// Anyone may ask for the status, so players are only counted, like for guests of the panel.
+		// Who plays, from where and with what ping and loss is in /api/players.
+		Resp::State(state, stats) => json::object!{
+			slot: state.slot,
+			checkpoint: state.checkpoint,
+			saved: state.date,
+			visible: state.visible,
+			netsim: state.netsim,
+			players: stats.len()
+		},
+		Resp::GetSettings(cfg) =>
+		{
+			let mut out = json::object!{};
+			for (_, section) in config::settings(&cfg).entries()
+			{
+				for (key, entry) in section.entries()
+				{
+					let _ = out.insert(key, entry["value"].clone());
+				}
+			}
+			out
+		}
+		Resp::Saved(waiting, adjusted) => json::object!{ ok: true, waiting: waiting, adjusted: adjusted },
+		x => web::toJSON(x).1
+	}
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str>
+{
+	query.split('&').find_map(|x| x.strip_prefix(name)?.strip_prefix('='))
+}
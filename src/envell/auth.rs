@@ -0,0 +1,206 @@
+use std::{collections::HashMap, io::Read, net::IpAddr, time::{Duration, Instant}};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::warn;
+
+const ITERATIONS: u32 = 10000;
+const MAX_FAILURES: u8 = 5;
+const LOCKOUT: Duration = Duration::from_secs(300);
+const SESSION_TTL: Duration = Duration::from_secs(12 * 3600);
+
+// What a web panel request needs. Guests may only look at the server state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission
+{
+	View,
+	Chat,
+	Settings,
+	Control,
+	Saves,
+	Players,
+	Stop
+}
+
+#[derive(Debug)]
+pub enum Login
+{
+	Ok(String),
+	Wrong,
+	// Seconds until the address may try again.
+	Locked(u64)
+}
+
+struct Session
+{
+	lastSeen: Instant
+}
+
+// Sessions outlive WebSocket connections, so a reloaded page can resume with its token.
+#[derive(Default)]
+pub struct Auth
+{
+	sessions: HashMap<String, Session>,
+	connections: HashMap<usize, String>,
+	failures: HashMap<IpAddr, (u8, Option<Instant>)>
+}
+
+impl Auth
+{
+	pub fn login(&mut self, id: usize, ip: IpAddr, password: &str, stored: &str) -> Login
+	{
+		let entry = self.failures.entry(ip).or_insert((0, None));
+		if let Some(until) = entry.1
+		{
+			if until > Instant::now() { return Login::Locked((until - Instant::now()).as_secs() + 1); }
+			*entry = (0, None);
+		}
+		if !verify(stored, password)
+		{
+			entry.0 += 1;
+			if entry.0 >= MAX_FAILURES
+			{
+				warn!("Too many failed logins from {ip}, locked for {}s.", LOCKOUT.as_secs());
+				entry.1 = Some(Instant::now() + LOCKOUT);
+				return Login::Locked(LOCKOUT.as_secs());
+			}
+			return Login::Wrong;
+		}
+		self.failures.remove(&ip);
+		let token = BASE64_STANDARD.encode(random(24));
+		self.sessions.insert(token.clone(), Session { lastSeen: Instant::now() });
+		self.connections.insert(id, token.clone());
+		Login::Ok(token)
+	}
+
+	pub fn resume(&mut self, id: usize, token: &str) -> bool
+	{
+		self.expire();
+		let Some(s) = self.sessions.get_mut(token) else { return false; };
+		s.lastSeen = Instant::now();
+		self.connections.insert(id, token.to_string());
+		true
+	}
+
+	pub fn logout(&mut self, id: usize)
+	{
+		if let Some(token) = self.connections.remove(&id)
+		{
+			self.sessions.remove(&token);
+			self.connections.retain(|_, x| *x != token);
+		}
+	}
+
+	// The connection is gone, but its session may be resumed later.
+	pub fn closed(&mut self, id: usize) { self.connections.remove(&id); }
+
+	// Every session ends, e.g. after the password is changed.
+	pub fn reset(&mut self)
+	{
+		self.sessions.clear();
+		self.connections.clear();
+	}
+
+	pub fn closeAll(&mut self) { self.connections.clear(); }
+
+	pub fn allowed(&mut self, id: usize, p: Permission) -> bool
+	{
+		if p == Permission::View { return true; }
+		let Some(token) = self.connections.get(&id) else { return false; };
+		match self.sessions.get_mut(token)
+		{
+			Some(s) if s.lastSeen.elapsed() < SESSION_TTL =>
+			{
+				s.lastSeen = Instant::now();
+				true
+			}
+			_ =>
+			{
+				self.connections.remove(&id);
+				false
+			}
+		}
+	}
+
+	fn expire(&mut self)
+	{
+		self.sessions.retain(|_, s| s.lastSeen.elapsed() < SESSION_TTL);
+		let sessions = &self.sessions;
+		self.connections.retain(|_, t| sessions.contains_key(t));
+	}
+}
+
+// "pbkdf2-sha1$<iterations>$<salt>$<key>", salt and key in base64.
+pub fn hash(password: &str) -> String
+{
+	let salt = random(16);
+	let key = pbkdf2(password.as_bytes(), &salt, ITERATIONS);
+	format!(
+		"pbkdf2-sha1${ITERATIONS}${}${}",
+		BASE64_STANDARD.encode(salt),
+		BASE64_STANDARD.encode(key)
+	)
+}
+
+pub fn verify(stored: &str, password: &str) -> bool
+{
+	let parts: Vec<&str> = stored.split('$').collect();
+	let ["pbkdf2-sha1", iterations, salt, key] = parts[..] else { return false; };
+	let (Ok(iterations), Ok(salt), Ok(key)) = (
+		iterations.parse::<u32>(),
+		BASE64_STANDARD.decode(salt),
+		BASE64_STANDARD.decode(key)
+	) else { return false; };
+	if iterations == 0 || key.len() != 20 { return false; }
+	let got = pbkdf2(password.as_bytes(), &salt, iterations);
+	got.iter().zip(&key).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 20]
+{
+	let mut k = [0u8; 64];
+	if key.len() > 64 { k[..20].copy_from_slice(&sha1_smol::Sha1::from(key).digest().bytes()); }
+	else { k[..key.len()].copy_from_slice(key); }
+	let mut inner = sha1_smol::Sha1::new();
+	inner.update(&k.map(|x| x ^ 0x36));
+	inner.update(data);
+	let mut outer = sha1_smol::Sha1::new();
+	outer.update(&k.map(|x| x ^ 0x5c));
+	outer.update(&inner.digest().bytes());
+	outer.digest().bytes()
+}
+
+// PBKDF2-HMAC-SHA1 with a single 20-byte block.
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 20]
+{
+	let mut u = hmac(password, &[salt, &1u32.to_be_bytes()].concat());
+	let mut out = u;
+	for _ in 1..iterations
+	{
+		u = hmac(password, &u);
+		for (o, x) in out.iter_mut().zip(u) { *o ^= x; }
+	}
+	out
+}
+
+// OS randomness where available, otherwise randomly seeded hashers.
+fn random(n: usize) -> Vec<u8>
+{
+	let mut out = vec![0u8; n];
+	if std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut out)).is_ok()
+	{
+		return out;
+	}
+	use std::hash::{BuildHasher, Hasher};
+	let time = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|x| x.as_nanos() as u64)
+		.unwrap_or(0);
+	for (i, chunk) in out.chunks_mut(8).enumerate()
+	{
+		let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+		h.write_u64(time ^ i as u64);
+		chunk.copy_from_slice(&h.finish().to_le_bytes()[..chunk.len()]);
+	}
+	out
+}
@@ -0,0 +1,86 @@
+use std::io::Write;
+
+use crate::envell::state;
+use crate::error;
+
+// Messages loaded back on start.
+const HISTORY: usize = 500;
+
+#[derive(Clone, Debug)]
+pub struct Entry
+{
+	pub time: String,
+	pub user: String,
+	pub msg: String
+}
+
+// Chat shared by web clients and players.
+// Every message is appended to a file as one JSON line, so a crash loses nothing.
+pub struct Chat
+{
+	entries: Vec<Entry>,
+	path: String
+}
+
+impl Chat
+{
+	pub fn load(path: &str) -> Self
+	{
+		let mut entries = vec![];
+		let mut lines = 0;
+		if let Ok(f) = std::fs::read_to_string(path)
+		{
+			for line in f.lines().filter(|x| !x.trim().is_empty())
+			{
+				lines += 1;
+				let Ok(x) = json::parse(line) else { continue; };
+				entries.push(Entry
+				{
+					time: x["time"].as_str().unwrap_or("").to_string(),
+					user: x["user"].as_str().unwrap_or("???").to_string(),
+					msg: x["msg"].as_str().unwrap_or("").to_string()
+				});
+			}
+		}
+		if entries.len() > HISTORY { entries.drain(..entries.len() - HISTORY); }
+
+		let chat = Self { entries, path: path.to_string() };
+		// The file only grows while running, old messages are dropped here.
+		if lines > HISTORY * 2
+		{
+			let raw: String = chat.entries.iter().map(line).collect();
+			if let Err(x) = std::fs::write(path, raw) { error!("Failed to trim chat history: {x}"); }
+		}
+		chat
+	}
+
+	pub fn push(&mut self, user: String, msg: String) -> Entry
+	{
+		let e = Entry { time: state::now(), user, msg };
+		let file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path);
+		if let Err(x) = file.and_then(|mut f| f.write_all(line(&e).as_bytes()))
+		{
+			error!("Failed to save chat message: {x}");
+		}
+		self.entries.push(e.clone());
+		e
+	}
+
+	// Messages after the first `offset`, newest first.
+	pub fn since(&self, offset: usize) -> Vec<Entry>
+	{
+		let mut out = self.entries.get(offset..).unwrap_or_default().to_vec();
+		out.reverse();
+		out
+	}
+
+	pub fn recent(&self, count: usize) -> &[Entry]
+	{
+		&self.entries[self.entries.len().saturating_sub(count)..]
+	}
+}
+
+fn line(e: &Entry) -> String
+{
+	json::stringify(json::object!{ time: e.time.clone(), user: e.user.clone(), msg: e.msg.clone() }) + "\n"
+}
@@ -1,42 +1,270 @@
-#[derive(Clone, Debug)]
-pub struct Config
+use crate::envell::{movement::Rules, netsim::Conditions, packet::Quantization};
+use crate::error;
+
+const SERVER: &str = "Сервер";
+const NETSIM: &str = "Симуляция сети";
+
+// Player ids stay below this whatever the limit, world entities take the ids from here on.
+pub const MAX_PLAYERS: u8 = 32;
+
+// How a setting is read from JSON and written back.
+// Numbers out of the allowed range are clamped instead of dropped,
+// the flag tells whether the value was taken as sent.
+trait Value: Sized
+{
+	fn read(v: &json::JsonValue, range: Option<(i64, i64)>) -> Option<(Self, bool)>;
+	fn write(&self) -> json::JsonValue;
+}
+
+fn number(v: &json::JsonValue, range: Option<(i64, i64)>, min: i64, max: i64) -> Option<(i64, bool)>
 {
-	pub tickRate: u8,
-	pub firstCP: String,
-	pub itemCellSize: u8,
-	pub port: u16,
-	pub playersCount: u8,
-	pub sysTickRate: u16,
-	pub locked: bool,
-	pub password: String
+	let x = v.as_f64().or_else(|| v.as_str().and_then(|x| x.trim().parse().ok()))?;
+	if !x.is_finite() { return None; }
+	let (lo, hi) = range.unwrap_or((min, max));
+	let n = (x.round() as i64).clamp(lo.max(min), hi.min(max));
+	Some((n, n as f64 == x))
+}
+
+impl Value for u8
+{
+	fn read(v: &json::JsonValue, range: Option<(i64, i64)>) -> Option<(Self, bool)>
+	{
+		number(v, range, 0, u8::MAX as i64).map(|(x, exact)| (x as u8, exact))
+	}
+	fn write(&self) -> json::JsonValue { (*self).into() }
+}
+
+impl Value for u16
+{
+	fn read(v: &json::JsonValue, range: Option<(i64, i64)>) -> Option<(Self, bool)>
+	{
+		number(v, range, 0, u16::MAX as i64).map(|(x, exact)| (x as u16, exact))
+	}
+	fn write(&self) -> json::JsonValue { (*self).into() }
+}
+
+impl Value for String
+{
+	fn read(v: &json::JsonValue, _: Option<(i64, i64)>) -> Option<(Self, bool)>
+	{
+		v.as_str().map(|x| (x.to_string(), true))
+	}
+	fn write(&self) -> json::JsonValue { self.clone().into() }
+}
+
+impl Value for bool
+{
+	fn read(v: &json::JsonValue, _: Option<(i64, i64)>) -> Option<(Self, bool)> { v.as_bool().map(|x| (x, true)) }
+	fn write(&self) -> json::JsonValue { (*self).into() }
+}
+
+macro_rules! stored
+{
+	(runtime) => { false };
+	($kind:ident) => { true };
+}
+
+macro_rules! shown
+{
+	(range) => { true };
+	(text) => { true };
+	($kind:ident) => { false };
+}
+
+macro_rules! idle
+{
+	(idle) => { true };
+	() => { false };
+}
+
+macro_rules! bounds
+{
+	(range, $section:expr, $name:expr, $min:expr, $max:expr) => { Some(($min, $max)) };
+	($kind:ident $(, $arg:expr)*) => { None };
+}
+
+macro_rules! form
+{
+	($value:expr; range, $section:expr, $name:expr, $min:expr, $max:expr) =>
+	{
+		Some(($section, json::object!{
+			type: "range",
+			name: $name,
+			value: $value,
+			props: { min: $min, max: $max }
+		}))
+	};
+	($value:expr; text, $section:expr, $name:expr) =>
+	{
+		Some(($section, json::object!{ type: "string", name: $name, value: $value }))
+	};
+	($value:expr; $kind:ident $(, $arg:expr)*) => { None };
 }
 
-impl Default for Config
+macro_rules! schema
 {
-	fn default() -> Self
+	($(
+		$(#[$meta:meta])*
+		$field:ident: $ty:ty = $default:expr => $kind:ident($($arg:expr),*) $(+ $when:ident)?
+	),* $(,)?) =>
 	{
-		Self
+		#[derive(Clone, Debug)]
+		pub struct Config
 		{
-			tickRate: 10,
-			firstCP: String::from(""),
-			itemCellSize: 10,
-			port: 26225,
-			playersCount: 5,
-			sysTickRate: 100,
-			locked: false,
-			password: String::from("tr_aeterno")
+			$( $(#[$meta])* pub $field: $ty ),*
 		}
-	}
+
+		impl Default for Config
+		{
+			fn default() -> Self
+			{
+				Self { $( $field: $default ),* }
+			}
+		}
+
+		// Returns the names of values that were not taken as sent.
+		fn read(cfg: &mut Config, data: &json::JsonValue, all: bool) -> Vec<&'static str>
+		{
+			let mut adjusted = vec![];
+			$(
+				let v = &data[stringify!($field)];
+				let wanted = if all { stored!($kind) } else { shown!($kind) };
+				if wanted && !v.is_null()
+				{
+					match Value::read(v, bounds!($kind $(, $arg)*))
+					{
+						Some((x, exact)) =>
+						{
+							cfg.$field = x;
+							if !exact { adjusted.push(stringify!($field)); }
+						}
+						None => adjusted.push(stringify!($field))
+					}
+				}
+			)*
+			adjusted
+		}
+
+		fn write(cfg: &Config) -> json::JsonValue
+		{
+			let mut out = json::object!{};
+			$(
+				if stored!($kind)
+				{
+					let _ = out.insert(stringify!($field), cfg.$field.write());
+				}
+			)*
+			out
+		}
+
+		pub fn settings(cfg: &Config) -> json::JsonValue
+		{
+			let mut out = json::object!{};
+			$(
+				let entry: Option<(&str, json::JsonValue)> = form!(cfg.$field.write(); $kind $(, $arg)*);
+				if let Some((section, entry)) = entry
+				{
+					if out[section].is_null() { out[section] = json::object!{}; }
+					out[section][stringify!($field)] = entry;
+				}
+			)*
+			out
+		}
+
+		// Returns what can be applied right away and the names of the settings left waiting.
+		pub fn live(old: &Config, new: &Config) -> (Config, Vec<&'static str>)
+		{
+			let mut now = new.clone();
+			let mut waiting = vec![];
+			$(
+				if idle!($($when)?) && old.$field != new.$field
+				{
+					now.$field = old.$field.clone();
+					waiting.push(stringify!($field));
+				}
+			)*
+			(now, waiting)
+		}
+	};
+}
+
+// Every setting is described once here. The table generates the struct,
+// its defaults, load, save, apply and the web form, so they cannot drift apart.
+// Kinds:
+//   range(section, name, min, max) - number in the web form, clamped to the range
+//   text(section, name)            - string in the web form
+//   stored()                       - kept in the file, not shown in the web form
+//   runtime()                      - neither stored nor shown
+// Snapshots, prediction and the world depend on settings marked "+ idle",
+// so those wait until nobody plays. The rest apply at once.
+schema!
+{
+	name: String = String::from("Envell") => text(SERVER, "Название сервера"),
+	joinPassword: String = String::new() => text(SERVER, "Пароль для входа (пусто - без пароля)"),
+	tickRate: u8 = 10 => range(SERVER, "Частота синхронизации игроков", 1, 100),
+	firstCP: String = String::new() => text(SERVER, "Первый чекпоинт"),
+	worldScript: String = String::new() => text(SERVER, "Скрипт мира на сервере (пусто - нет)") + idle,
+	itemCellSize: u8 = 10 => range(SERVER, "Количество предметов в ячейке", 1, 255),
+	playersCount: u8 = 5 => range(SERVER, "Количество игроков", 1, MAX_PLAYERS as i64),
+	port: u16 = 26225 => range(SERVER, "Порт сервера", 1024, 65535),
+	sysTickRate: u16 = 100 => range(SERVER, "Частота обновления сервера", 1, 1024),
+	posPrecision: u16 = 100 => range(SERVER, "Точность позиций (шагов на метр)", 1, 1000) + idle,
+	velPrecision: u16 = 100 => range(SERVER, "Точность скоростей (шагов на м/с)", 1, 1000) + idle,
+	movementMode: u8 = 0 => range(SERVER, "Движение на сервере (0 - у клиентов, 1 - на сервере)", 0, 1) + idle,
+	moveSpeed: u16 = 200 => range(SERVER, "Скорость движения (см/с)", 1, 2000) + idle,
+	timeout: u16 = 10 => range(SERVER, "Время ожидания игрока (с)", 2, 120),
+	reconnectTime: u16 = 60 => range(SERVER, "Время на переподключение (с)", 0, 600),
+	autosave: u16 = 300 => range(SERVER, "Автосохранение (с, 0 - выкл)", 0, 3600),
+	backups: u8 = 3 => range(SERVER, "Резервные копии автосохранений", 0, 20),
+	simEnabled: u8 = 0 => range(NETSIM, "Включена (0 - нет, 1 - да)", 0, 1),
+	simLatency: u16 = 0 => range(NETSIM, "Задержка (мс)", 0, 2000),
+	simJitter: u16 = 0 => range(NETSIM, "Разброс задержки (мс)", 0, 1000),
+	simLoss: u8 = 0 => range(NETSIM, "Потери пакетов (%)", 0, 100),
+	simDuplicate: u8 = 0 => range(NETSIM, "Дублирование пакетов (%)", 0, 100),
+	simReorder: u8 = 0 => range(NETSIM, "Перестановка пакетов (%)", 0, 100),
+	saveSlot: String = String::from("main") => stored(),
+	// Plain admin password of older configurations, replaced by adminHash on start.
+	password: String = String::from("tr_aeterno") => stored(),
+	adminHash: String = String::new() => stored()
 }
 
-pub fn apply(cfg: &mut Config, data: json::JsonValue)
+// Whether the web form has a setting with this name.
+pub fn known(key: &str) -> bool
 {
-	cfg.firstCP = data["firstCP"].as_str().unwrap_or("").to_string();
-	cfg.itemCellSize = data["itemCellSize"].as_u8().unwrap_or(10);
-	cfg.playersCount = data["playersCount"].as_u8().unwrap_or(5);
-	cfg.port = data["port"].as_u16().unwrap_or(26225);
-	cfg.tickRate = data["tickRate"].as_u8().unwrap_or(10);
-	cfg.sysTickRate = data["sysTickRate"].as_u16().unwrap_or(100);
+	settings(&Config::default()).entries().any(|(_, section)| section.has_key(key))
+}
+
+// Takes the fields of the web form. Anything missing or malformed keeps its value.
+// Returns the names of values that were clamped, rounded or dropped.
+pub fn apply(cfg: &mut Config, data: json::JsonValue) -> Vec<&'static str>
+{
+	read(cfg, &data, false)
+}
+
+impl Config
+{
+	pub fn quantization(&self) -> Quantization
+	{
+		Quantization { position: self.posPrecision, velocity: self.velPrecision }
+	}
+
+	pub fn rules(&self) -> Rules
+	{
+		Rules { authoritative: self.movementMode == 1, speed: self.moveSpeed }
+	}
+
+	pub fn conditions(&self) -> Conditions
+	{
+		Conditions
+		{
+			enabled: self.simEnabled == 1,
+			latency: self.simLatency,
+			jitter: self.simJitter,
+			loss: self.simLoss.min(100),
+			duplicate: self.simDuplicate.min(100),
+			reorder: self.simReorder.min(100)
+		}
+	}
 }
 
 pub fn load(path: &str) -> Config
@@ -44,15 +272,10 @@ pub fn load(path: &str) -> Config
 	let mut c = Config::default();
 	if let Ok(f) = std::fs::read_to_string(path)
 	{
-		if let Ok(cfg) = json::parse(&f)
+		match json::parse(&f)
 		{
-			c.firstCP = cfg["firstCP"].as_str().unwrap_or("").to_string();
-			c.itemCellSize = cfg["itemCellSize"].as_u8().unwrap_or(10);
-			c.playersCount = cfg["playersCount"].as_u8().unwrap_or(5);
-			c.port = cfg["port"].as_u16().unwrap_or(26225);
-			c.tickRate = cfg["tickRate"].as_u8().unwrap_or(10);
-			c.sysTickRate = cfg["sysTickRate"].as_u16().unwrap_or(100);
-			c.password = cfg["password"].as_str().unwrap_or("tr_aeterno").to_string();
+			Ok(cfg) => { read(&mut c, &cfg, true); }
+			Err(x) => error!("Configuration '{path}' is broken: {x}")
 		}
 	}
 	c
@@ -60,55 +283,8 @@ pub fn load(path: &str) -> Config
 
 pub fn save(cfg: &Config, path: &str)
 {
-	let _ = std::fs::write(path, json::stringify(json::object!{
-		firstCP: cfg.firstCP.clone(),
-		itemCellSize: cfg.itemCellSize,
-		playersCount: cfg.playersCount,
-		port: cfg.port,
-		tickRate: cfg.tickRate,
-		password: cfg.password.clone()
-	}));
-}
-
-pub fn settings(cfg: &Config) -> json::JsonValue
-{
-	json::object!{
-		"Сервер": {
-			tickRate: {
-				type: "range",
-				name: "Частота синхронизации игроков",
-				value: cfg.tickRate,
-				props: { min: 1, max: 100 }
-			},
-			firstCP: {
-				type: "string",
-				name: "Первый чекпоинт",
-				value: cfg.firstCP.clone()
-			},
-			itemCellSize: {
-				type: "range",
-				name: "Количество предметов в ячейке",
-				value: cfg.itemCellSize,
-				props: { min: 1, max: 255 }
-			},
-			playersCount: {
-				type: "range",
-				name: "Количество игроков",
-				value: cfg.playersCount,
-				props: { min: 1, max: 32 }
-			},
-			port: {
-				type: "range",
-				name: "Порт сервера",
-				value: cfg.port,
-				props: { min: 1024, max: 65535 }
-			},
-			sysTickRate: {
-				type: "range",
-				name: "Частота обновления сервера",
-				value: cfg.sysTickRate,
-				props: { min: 1, max: 1024 }
-			}
-		}
+	if let Err(x) = std::fs::write(path, json::stringify(write(cfg)))
+	{
+		error!("Failed to save configuration to '{path}': {x}");
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,79 @@
+use crate::envell::{config, player, web};
+
+// Connection id of the console in web requests. The console is always trusted.
+pub const ID: usize = usize::MAX - 1;
+
+const HELP: &str = "Commands:
+  status                 save slot, checkpoint and players online
+  players                connected players
+  kick <id>              disconnect a player
+  say <text>             chat message from the server
+  set <setting> <value>  change a setting, e.g. set tickRate 20
+  visible on|off         open or close the gates for LAN search
+  save                   save the game
+  stop                   save the game and stop the server
+  help                   show this message";
+
+pub enum Command
+{
+	Status,
+	Players,
+	// Anything the web panel can ask for.
+	Web(web::Req)
+}
+
+pub fn main(toMain: std::sync::mpsc::Sender<Command>)
+{
+	for line in std::io::stdin().lines()
+	{
+		let Ok(line) = line else { break; };
+		match parse(&line)
+		{
+			Ok(Some(cmd)) => if toMain.send(cmd).is_err() { break; },
+			Ok(None) => {}
+			Err(x) if x.is_empty() => println!("{HELP}"),
+			Err(x) => println!("{x}\nType 'help' for the list of commands.")
+		}
+	}
+}
+
+// Words are separated by spaces; `say` and `set` take the rest of the line as is.
+fn parse(line: &str) -> Result<Option<Command>, String>
+{
+	let line = line.trim();
+	if line.is_empty() { return Ok(None); }
+	let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+	let rest = rest.trim();
+	let web = |x| Ok(Some(Command::Web(x)));
+	match cmd.to_lowercase().as_str()
+	{
+		"help" | "?" => Err(String::new()),
+		"status" => Ok(Some(Command::Status)),
+		"players" => Ok(Some(Command::Players)),
+		"kick" =>
+		{
+			let id = rest.parse::<u8>().map_err(|_| format!("kick: '{rest}' is not a player id"))?;
+			web(web::Req::PlayerAction(id, player::Action::Kick))
+		}
+		"say" if rest.is_empty() => Err(String::from("say: nothing to say")),
+		"say" => web(web::Req::NewMessage(rest.to_string())),
+		"set" =>
+		{
+			let Some((key, value)) = rest.split_once(' ')
+			else { return Err(String::from("set: usage is 'set <setting> <value>'")); };
+			if !config::known(key) { return Err(format!("set: unknown setting '{key}'")); }
+			let mut data = json::object!{};
+			let _ = data.insert(key, value.trim());
+			web(web::Req::SaveSettings(data))
+		}
+		"visible" => match rest
+		{
+			"on" => web(web::Req::ClickButton(String::from("setVisible"))),
+			"off" => web(web::Req::ClickButton(String::from("setInvisible"))),
+			_ => Err(String::from("visible: expected 'on' or 'off'"))
+		},
+		"save" => web(web::Req::ClickButton(String::from("save"))),
+		"stop" => web(web::Req::Modal(String::from("stopServer"), json::JsonValue::Null)),
+		x => Err(format!("Unknown command '{x}'."))
+	}
+}
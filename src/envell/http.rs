@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+
+// Requests bigger than this are answered with 413 instead of being buffered forever.
+pub const MAX_HEAD: usize = 8 * 1024;
+pub const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Method
+{
+	Get,
+	Head,
+	Other(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct Request
+{
+	pub method: Method,
+	// Normalized, always starts with '/', never contains "." or ".." segments.
+	pub path: String,
+	pub query: String,
+	pub version: u8,
+	// Header names are lowercase.
+	pub headers: HashMap<String, String>,
+	pub body: Vec<u8>
+}
+
+impl Request
+{
+	pub fn header(&self, name: &str) -> Option<&str>
+	{
+		self.headers.get(name).map(|x| x.as_str())
+	}
+
+	// HTTP/1.1 keeps the connection unless told otherwise, HTTP/1.0 closes it.
+	pub fn keepAlive(&self) -> bool
+	{
+		let tokens = self.header("connection").unwrap_or("").to_lowercase();
+		let has = |t: &str| tokens.split(',').any(|x| x.trim() == t);
+		if self.version >= 1 { !has("close") } else { has("keep-alive") }
+	}
+
+	pub fn isUpgrade(&self) -> bool
+	{
+		self.header("upgrade").is_some_and(|x| x.eq_ignore_ascii_case("websocket"))
+	}
+}
+
+// Collects bytes from the socket until whole requests are available.
+#[derive(Debug, Default)]
+pub struct Parser
+{
+	buf: Vec<u8>
+}
+
+impl Parser
+{
+	pub fn push(&mut self, data: &[u8]) { self.buf.extend_from_slice(data); }
+
+	// Bytes that came after the last parsed request, e.g. WebSocket frames after an upgrade.
+	pub fn take(&mut self) -> Vec<u8> { std::mem::take(&mut self.buf) }
+
+	// Ok(None) means more bytes are needed. Err carries the status code to reply with,
+	// after which the connection should be closed.
+	pub fn next(&mut self) -> Result<Option<Request>, u16>
+	{
+		let Some(end) = self.buf.windows(4).position(|x| x == b"\r\n\r\n") else
+		{
+			if self.buf.len() > MAX_HEAD { return Err(413); }
+			return Ok(None);
+		};
+		if end > MAX_HEAD { return Err(413); }
+
+		let head = std::str::from_utf8(&self.buf[..end]).map_err(|_| 400u16)?;
+		let mut lines = head.split("\r\n");
+		let mut first = lines.next().unwrap_or("").split(' ');
+		let (Some(method), Some(target), Some(version), None) =
+			(first.next(), first.next(), first.next(), first.next())
+			else { return Err(400); };
+		let version = match version
+		{
+			"HTTP/1.1" => 1,
+			"HTTP/1.0" => 0,
+			_ => return Err(400)
+		};
+		let method = match method
+		{
+			"GET" => Method::Get,
+			"HEAD" => Method::Head,
+			x if !x.is_empty() && x.bytes().all(|c| c.is_ascii_uppercase()) => Method::Other(x.to_string()),
+			_ => return Err(400)
+		};
+
+		let mut headers = HashMap::new();
+		for line in lines
+		{
+			let Some((name, value)) = line.split_once(':') else { return Err(400); };
+			if name.is_empty() || name.contains(|c: char| c.is_whitespace()) { return Err(400); }
+			headers.insert(name.to_lowercase(), value.trim().to_string());
+		}
+
+		if headers.contains_key("transfer-encoding") { return Err(400); }
+		let length = match headers.get("content-length")
+		{
+			Some(x) => x.parse::<usize>().map_err(|_| 400u16)?,
+			None => 0
+		};
+		if length > MAX_BODY { return Err(413); }
+		let start = end + 4;
+		if self.buf.len() < start + length { return Ok(None); }
+
+		let (path, query) = target.split_once('?').unwrap_or((target, ""));
+		let (path, query) = (normalize(path).ok_or(400u16)?, query.to_string());
+
+		let body = self.buf[start..start + length].to_vec();
+		self.buf.drain(..start + length);
+		Ok(Some(Request { method, path, query, version, headers, body }))
+	}
+}
+
+// Decodes %XX, resolves "." and "..", and refuses anything escaping the root.
+pub fn normalize(path: &str) -> Option<String>
+{
+	if !path.starts_with('/') { return None; }
+	let raw = path.as_bytes();
+	let mut decoded = vec![];
+	let mut i = 0;
+	while i < raw.len()
+	{
+		if raw[i] == b'%'
+		{
+			let hex = std::str::from_utf8(raw.get(i + 1..i + 3)?).ok()?;
+			decoded.push(u8::from_str_radix(hex, 16).ok()?);
+			i += 3;
+		}
+		else { decoded.push(raw[i]); i += 1; }
+	}
+	let decoded = String::from_utf8(decoded).ok()?;
+	if decoded.contains(['\0', '\\']) { return None; }
+
+	let mut parts: Vec<&str> = vec![];
+	for s in decoded.split('/')
+	{
+		match s
+		{
+			"" | "." => {}
+			".." => { parts.pop()?; }
+			x => parts.push(x)
+		}
+	}
+	let mut out = String::from("/") + &parts.join("/");
+	if decoded.ends_with('/') && !parts.is_empty() { out.push('/'); }
+	Some(out)
+}
+
+pub fn mime(path: &str) -> &'static str
+{
+	let ext = path.rsplit_once('.').map(|x| x.1.to_lowercase()).unwrap_or_default();
+	match ext.as_str()
+	{
+		"html" | "htm" => "text/html; charset=UTF-8",
+		"css" => "text/css; charset=UTF-8",
+		"js" | "mjs" => "text/javascript; charset=UTF-8",
+		"json" | "map" => "application/json; charset=UTF-8",
+		"txt" => "text/plain; charset=UTF-8",
+		"xml" => "application/xml; charset=UTF-8",
+		"svg" => "image/svg+xml",
+		"png" => "image/png",
+		"jpg" | "jpeg" => "image/jpeg",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"ico" => "image/x-icon",
+		"otf" => "font/otf",
+		"ttf" => "font/ttf",
+		"woff" => "font/woff",
+		"woff2" => "font/woff2",
+		"wasm" => "application/wasm",
+		"mp3" => "audio/mpeg",
+		"ogg" => "audio/ogg",
+		"wav" => "audio/wav",
+		_ => "application/octet-stream"
+	}
+}
+
+pub fn reason(status: u16) -> &'static str
+{
+	match status
+	{
+		101 => "Switching Protocols",
+		200 => "OK",
+		204 => "No Content",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		409 => "Conflict",
+		413 => "Content Too Large",
+		429 => "Too Many Requests",
+		500 => "Internal Server Error",
+		503 => "Service Unavailable",
+		_ => "Unknown"
+	}
+}
+
+// Builds a whole response. HEAD gets the same headers without the body.
+pub fn response(
+	status: u16,
+	headers: &[(&str, &str)],
+	body: &[u8],
+	head: bool,
+	keepAlive: bool
+) -> Vec<u8>
+{
+	let mut out = format!("HTTP/1.1 {status} {}\r\n", reason(status));
+	for (name, value) in headers
+	{
+		out += &format!("{name}: {value}\r\n");
+	}
+	out += &format!(
+		"Content-Length: {}\r\nConnection: {}\r\n\r\n",
+		body.len(),
+		if keepAlive { "keep-alive" } else { "close" }
+	);
+	let mut out = out.into_bytes();
+	if !head { out.extend_from_slice(body); }
+	out
+}
+
+// Short plain-text reply for errors.
+pub fn error(status: u16, head: bool, keepAlive: bool) -> Vec<u8>
+{
+	let mut headers = vec![("Content-Type", "text/plain; charset=UTF-8")];
+	if status == 405 { headers.push(("Allow", "GET, HEAD")); }
+	response(status, &headers, format!("{status} {}", reason(status)).as_bytes(), head, keepAlive)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn parse(raw: &[u8]) -> Result<Option<Request>, u16>
+	{
+		let mut p = Parser::default();
+		p.push(raw);
+		p.next()
+	}
+
+	#[test]
+	fn simpleGet()
+	{
+		let r = parse(b"GET /index.html?x=1 HTTP/1.1\r\nHost: a\r\nX-Test:  v \r\n\r\n")
+			.unwrap().unwrap();
+		assert_eq!(r.method, Method::Get);
+		assert_eq!(r.path, "/index.html");
+		assert_eq!(r.query, "x=1");
+		assert_eq!(r.header("host"), Some("a"));
+		assert_eq!(r.header("x-test"), Some("v"));
+		assert!(r.keepAlive());
+	}
+
+	#[test]
+	fn byteByByte()
+	{
+		let raw = b"POST /api HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+		let mut p = Parser::default();
+		for (i, b) in raw.iter().enumerate()
+		{
+			p.push(&[*b]);
+			let r = p.next().unwrap();
+			if i + 1 < raw.len() { assert!(r.is_none()); }
+			else
+			{
+				let r = r.unwrap();
+				assert_eq!(r.method, Method::Other(String::from("POST")));
+				assert_eq!(r.body, b"hello");
+			}
+		}
+	}
+
+	#[test]
+	fn pipelined()
+	{
+		let mut p = Parser::default();
+		p.push(b"GET /a HTTP/1.1\r\n\r\nHEAD /b HTTP/1.1\r\nConnection: close\r\n\r\nGET /c");
+		assert_eq!(p.next().unwrap().unwrap().path, "/a");
+		let b = p.next().unwrap().unwrap();
+		assert_eq!((b.method.clone(), b.path.as_str(), b.keepAlive()), (Method::Head, "/b", false));
+		assert!(p.next().unwrap().is_none());
+		assert_eq!(p.take(), b"GET /c");
+	}
+
+	#[test]
+	fn keepAliveByVersion()
+	{
+		assert!(!parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap().keepAlive());
+		assert!(parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().unwrap().keepAlive());
+		assert!(!parse(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap().unwrap().keepAlive());
+	}
+
+	#[test]
+	fn malformed()
+	{
+		assert_eq!(parse(b"\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET / HTTP/2.0\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"get / HTTP/1.1\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET / HTTP/1.1\r\nNoColon\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap_err(), 400);
+		assert_eq!(parse(b"GET \xff HTTP/1.1\r\n\r\n").unwrap_err(), 400);
+	}
+
+	#[test]
+	fn tooLarge()
+	{
+		let long = [b"GET / HTTP/1.1\r\nX: ".as_slice(), &vec![b'a'; MAX_HEAD]].concat();
+		assert_eq!(parse(&long).unwrap_err(), 413);
+		let body = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+		assert_eq!(parse(body.as_bytes()).unwrap_err(), 413);
+	}
+
+	#[test]
+	fn traversal()
+	{
+		assert_eq!(normalize("/a/./b//c").as_deref(), Some("/a/b/c"));
+		assert_eq!(normalize("/a/b/../c").as_deref(), Some("/a/c"));
+		assert_eq!(normalize("/dir/").as_deref(), Some("/dir/"));
+		assert_eq!(normalize("/%41%20b").as_deref(), Some("/A b"));
+		assert_eq!(normalize("/"), Some(String::from("/")));
+		assert_eq!(normalize("/../etc/passwd"), None);
+		assert_eq!(normalize("/a/%2e%2e/%2e%2e/x"), None);
+		assert_eq!(normalize("/a\\..\\b"), None);
+		assert_eq!(normalize("/%zz"), None);
+		assert_eq!(normalize("/%00"), None);
+		assert_eq!(normalize("relative"), None);
+		assert_eq!(parse(b"GET /../cfg.json HTTP/1.1\r\n\r\n").unwrap_err(), 400);
+	}
+
+	#[test]
+	fn responses()
+	{
+		let r = response(200, &[("Content-Type", mime("a.CSS"))], b"body", false, true);
+		assert_eq!(
+			r,
+			b"HTTP/1.1 200 OK\r\nContent-Type: text/css; charset=UTF-8\r\n\
+			Content-Length: 4\r\nConnection: keep-alive\r\n\r\nbody"
+		);
+		let h = response(200, &[], b"body", true, false);
+		assert!(h.ends_with(b"Content-Length: 4\r\nConnection: close\r\n\r\n"));
+		assert!(String::from_utf8(error(405, false, true)).unwrap().contains("Allow: GET, HEAD"));
+		assert_eq!(mime("font.woff2"), "font/woff2");
+		assert_eq!(mime("noext"), "application/octet-stream");
+	}
+}
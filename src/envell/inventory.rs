@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::{error, info};
+
+pub const SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct ItemDef
+{
+	pub name: String,
+	pub maxStack: u8
+}
+
+// Item definitions from items.json next to the configuration:
+// { "apple": { "name": "Яблоко", "maxStack": 20 }, ... }
+#[derive(Clone, Debug, Default)]
+pub struct Items
+{
+	defs: HashMap<String, ItemDef>
+}
+
+impl Items
+{
+	pub fn load(path: &str) -> Self
+	{
+		let mut defs = HashMap::new();
+		match std::fs::read_to_string(path).map(|x| json::parse(&x))
+		{
+			Ok(Ok(data)) =>
+			{
+				for (id, d) in data.entries()
+				{
+					defs.insert(id.to_string(), ItemDef
+					{
+						name: d["name"].as_str().unwrap_or(id).to_string(),
+						maxStack: d["maxStack"].as_u8().unwrap_or(u8::MAX).max(1)
+					});
+				}
+				info!("Loaded {} item definitions.", defs.len());
+			}
+			Ok(Err(x)) => error!("Item definitions are broken: {x}"),
+			Err(_) => info!("No item definitions found, any item is allowed.")
+		}
+		Self { defs }
+	}
+
+	pub fn get(&self, id: &str) -> Option<&ItemDef> { self.defs.get(id) }
+
+	// Without a definition file every item is known.
+	pub fn known(&self, id: &str) -> bool
+	{
+		self.defs.is_empty() || self.defs.contains_key(id)
+	}
+}
+
+// How many items fit into one cell.
+#[derive(Clone, Debug, Default)]
+pub struct Limits
+{
+	pub cellSize: u8,
+	pub items: Items
+}
+
+impl Limits
+{
+	pub fn stack(&self, id: &str) -> u8
+	{
+		let cell = self.cellSize.max(1);
+		self.items.get(id).map(|x| x.maxStack.min(cell)).unwrap_or(cell)
+	}
+
+	pub fn name(&self, id: &str) -> String
+	{
+		self.items.get(id).map(|x| x.name.clone()).unwrap_or(id.to_string())
+	}
+}
+
+// What a client sees in one cell.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Slot
+{
+	pub id: String,
+	pub name: String,
+	pub count: u8,
+	pub max: u8
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Inventory
+{
+	pub cells: Vec<(String, u8)>
+}
+
+impl Default for Inventory
+{
+	fn default() -> Self
+	{
+		Self { cells: vec![(String::new(), 0); SIZE] }
+	}
+}
+
+impl Inventory
+{
+	// Fills existing stacks first, then empty cells. Returns how many did not fit.
+	pub fn add(&mut self, id: &str, mut count: u8, l: &Limits) -> u8
+	{
+		if id.is_empty() || !l.items.known(id) { return count; }
+		let max = l.stack(id);
+		for (item, n) in &mut self.cells
+		{
+			if count == 0 { break; }
+			if item == id && *n < max
+			{
+				let add = count.min(max - *n);
+				*n += add;
+				count -= add;
+			}
+		}
+		for (item, n) in &mut self.cells
+		{
+			if count == 0 { break; }
+			if *n == 0
+			{
+				let add = count.min(max);
+				*item = id.to_string();
+				*n = add;
+				count -= add;
+			}
+		}
+		count
+	}
+
+	// Takes items only if there are enough of them.
+	pub fn remove(&mut self, id: &str, count: u8) -> bool
+	{
+		let total: u32 = self.cells.iter()
+			.filter(|(item, _)| item == id)
+			.map(|(_, n)| *n as u32)
+			.sum();
+		if total < count as u32 { return false; }
+		let mut count = count;
+		for (item, n) in self.cells.iter_mut().rev()
+		{
+			if count == 0 { break; }
+			if item != id { continue; }
+			let take = count.min(*n);
+			*n -= take;
+			count -= take;
+			if *n == 0 { item.clear(); }
+		}
+		true
+	}
+
+	// Stacks onto the same item as far as the limit allows, otherwise swaps the cells.
+	pub fn moveItem(&mut self, from: usize, to: usize, l: &Limits) -> bool
+	{
+		if from == to || from >= self.cells.len() || to >= self.cells.len() { return false; }
+		if self.cells[from].1 == 0 { return false; }
+		if self.cells[from].0 == self.cells[to].0
+		{
+			return self.stack(from, to, l);
+		}
+		self.cells.swap(from, to);
+		true
+	}
+
+	pub fn stack(&mut self, from: usize, to: usize, l: &Limits) -> bool
+	{
+		if from == to || from >= self.cells.len() || to >= self.cells.len() { return false; }
+		let id = self.cells[from].0.clone();
+		if self.cells[from].1 == 0 || self.cells[to].1 > 0 && self.cells[to].0 != id { return false; }
+		let room = l.stack(&id).saturating_sub(self.cells[to].1);
+		let n = room.min(self.cells[from].1);
+		if n == 0 { return false; }
+		self.cells[to] = (id, self.cells[to].1 + n);
+		self.cells[from].1 -= n;
+		if self.cells[from].1 == 0 { self.cells[from].0.clear(); }
+		true
+	}
+
+	// Moves part of a stack into an empty cell or onto the same item.
+	pub fn split(&mut self, from: usize, to: usize, count: u8, l: &Limits) -> bool
+	{
+		if from == to || from >= self.cells.len() || to >= self.cells.len() { return false; }
+		let (id, have) = self.cells[from].clone();
+		if count == 0 || have < count { return false; }
+		let (target, n) = &self.cells[to];
+		if *n > 0 && *target != id { return false; }
+		if n.saturating_add(count) > l.stack(&id) { return false; }
+		self.cells[to] = (id, n + count);
+		self.cells[from].1 -= count;
+		if self.cells[from].1 == 0 { self.cells[from].0.clear(); }
+		true
+	}
+
+	pub fn slots(&self, l: &Limits) -> Vec<Slot>
+	{
+		self.cells.iter()
+			.map(|(id, n)| Slot
+			{
+				id: id.clone(),
+				name: if *n > 0 { l.name(id) } else { String::new() },
+				count: *n,
+				max: if *n > 0 { l.stack(id) } else { 0 }
+			})
+			.collect()
+	}
+}
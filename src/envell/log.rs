@@ -0,0 +1,258 @@
+use std::{collections::VecDeque, fs::File, io::Write, sync::Mutex};
+
+// Records kept in memory for the web panel and the in-game console.
+const RECENT: usize = 500;
+// A log file is rotated once it grows past this size.
+const MAX_FILE: u64 = 1 << 20;
+// Rotated files kept next to the current one, name.1.log is the newest.
+const KEEP: u8 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level
+{
+	Error,
+	Warn,
+	Info,
+	Debug
+}
+
+impl Level
+{
+	pub fn parse(s: &str) -> Option<Self>
+	{
+		match s.to_lowercase().as_str()
+		{
+			"error" => Some(Self::Error),
+			"warn" => Some(Self::Warn),
+			"info" => Some(Self::Info),
+			"debug" => Some(Self::Debug),
+			_ => None
+		}
+	}
+
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Self::Error => "error",
+			Self::Warn => "warn",
+			Self::Info => "info",
+			Self::Debug => "debug"
+		}
+	}
+}
+
+// Which records get written: one level for everything, optionally another for some targets.
+// Parsed from "info" or "info,player=debug,web=warn".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter
+{
+	pub level: Level,
+	pub targets: Vec<(String, Level)>
+}
+
+impl Default for Filter
+{
+	fn default() -> Self { Self { level: Level::Info, targets: vec![] } }
+}
+
+impl Filter
+{
+	pub fn parse(s: &str) -> Option<Self>
+	{
+		let mut f = Self::default();
+		for part in s.split(',').map(str::trim).filter(|x| !x.is_empty())
+		{
+			match part.split_once('=')
+			{
+				Some((target, level)) => f.targets.push((target.trim().to_string(), Level::parse(level.trim())?)),
+				None => f.level = Level::parse(part)?
+			}
+		}
+		Some(f)
+	}
+
+	// The last entry for a target wins.
+	pub fn allows(&self, level: Level, target: &str) -> bool
+	{
+		level <= self.targets.iter().rev().find(|(t, _)| t == target).map_or(self.level, |(_, l)| *l)
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Record
+{
+	// Grows by one with every record, so readers can ask for what they have not seen.
+	pub id: u64,
+	pub time: String,
+	pub level: Level,
+	pub target: String,
+	pub msg: String
+}
+
+struct Logger
+{
+	init: bool,
+	filter: Filter,
+	path: String,
+	file: Option<File>,
+	size: u64,
+	recent: VecDeque<Record>,
+	next: u64
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger
+{
+	init: false,
+	filter: Filter { level: Level::Info, targets: vec![] },
+	path: String::new(),
+	file: None,
+	size: 0,
+	recent: VecDeque::new(),
+	next: 1
+});
+
+fn lock() -> std::sync::MutexGuard<'static, Logger>
+{
+	LOGGER.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+// The first call wins: a server launched from the game logs along with the game.
+// Without a path records only go to the console.
+pub fn init(filter: Filter, path: &str)
+{
+	let mut l = lock();
+	if l.init { return; }
+	l.init = true;
+	l.filter = filter;
+	if path.is_empty() { return; }
+	if let Some(dir) = std::path::Path::new(path).parent()
+	{
+		let _ = std::fs::create_dir_all(dir);
+	}
+	l.path = path.to_string();
+	l.open();
+}
+
+// Used by the macros below. The target is the last part of the module path.
+pub fn write(level: Level, target: &str, msg: String)
+{
+	let target = target.rsplit("::").next().unwrap_or(target);
+	let mut l = lock();
+	if !l.filter.allows(level, target) { return; }
+	let time = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap_or_default();
+	let r = Record
+	{
+		id: l.next,
+		time: format!("{}.{:03}", super::state::date(time.as_secs() as i64), time.subsec_millis()),
+		level,
+		target: target.to_string(),
+		msg
+	};
+	l.next += 1;
+
+	let line = format!("{} {:<5} {}: {}\n", r.time, r.level.name().to_uppercase(), r.target, r.msg);
+	print!("{line}");
+	if l.file.is_some()
+	{
+		if l.size + line.len() as u64 > MAX_FILE { l.rotate(); }
+		if let Some(f) = l.file.as_mut()
+			&& f.write_all(line.as_bytes()).is_ok()
+		{
+			l.size += line.len() as u64;
+		}
+	}
+
+	l.recent.push_back(r);
+	while l.recent.len() > RECENT { l.recent.pop_front(); }
+}
+
+// Records newer than `id`, oldest first.
+pub fn since(id: u64) -> Vec<Record>
+{
+	lock().recent.iter().filter(|x| x.id > id).cloned().collect()
+}
+
+impl Logger
+{
+	fn open(&mut self)
+	{
+		match std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+		{
+			Ok(f) =>
+			{
+				self.size = f.metadata().map(|x| x.len()).unwrap_or(0);
+				self.file = Some(f);
+			}
+			Err(x) =>
+			{
+				println!("Cannot open log file '{}': {x}", self.path);
+				self.file = None;
+			}
+		}
+	}
+
+	fn rotate(&mut self)
+	{
+		self.file = None;
+		for n in (1..KEEP).rev()
+		{
+			let _ = std::fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+		}
+		let _ = std::fs::rename(&self.path, rotated(&self.path, 1));
+		self.open();
+	}
+}
+
+// logs/envell.log -> logs/envell.2.log
+fn rotated(path: &str, n: u8) -> String
+{
+	match path.rsplit_once('.')
+	{
+		Some((stem, ext)) if !ext.contains('/') => format!("{stem}.{n}.{ext}"),
+		_ => format!("{path}.{n}")
+	}
+}
+
+#[macro_export]
+macro_rules! error
+{
+	($($arg:tt)*) => { $crate::envell::log::write($crate::envell::log::Level::Error, module_path!(), format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! warn
+{
+	($($arg:tt)*) => { $crate::envell::log::write($crate::envell::log::Level::Warn, module_path!(), format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! info
+{
+	($($arg:tt)*) => { $crate::envell::log::write($crate::envell::log::Level::Info, module_path!(), format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! debug
+{
+	($($arg:tt)*) => { $crate::envell::log::write($crate::envell::log::Level::Debug, module_path!(), format!($($arg)*)) };
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn targetFilter()
+	{
+		let f = Filter::parse("warn, player=debug,web=error").unwrap();
+		assert!(f.allows(Level::Debug, "player"));
+		assert!(!f.allows(Level::Warn, "web"));
+		assert!(f.allows(Level::Warn, "envell"));
+		assert!(!f.allows(Level::Info, "envell"));
+		assert_eq!(Filter::parse("player=loud"), None);
+	}
+}
@@ -1,13 +1,29 @@
-#![allow(dead_code)]
+use crate::envell::{inventory::Slot, movement::Rules, net::short, packet::Quantization};
+use crate::warn;
 
+pub const REJECT_FULL: u8 = 0;
+pub const REJECT_PASSWORD: u8 = 1;
+pub const REJECT_KICKED: u8 = 2;
+pub const REJECT_BANNED: u8 = 3;
+pub const REJECT_SHUTDOWN: u8 = 4;
+
+#[derive(Debug, PartialEq)]
 pub enum ToServer
 {
-	Setup(u16)
+	Setup(u16, String),
+	Resume(u64, u16),
+	Ping(u32),
+	Pong(u32),
+	MoveItem(u8, u8),
+	SplitItem(u8, u8, u8),
+	Chat(String)
 }
 
 impl ToServer
 {
-	pub fn fromRaw(buf: Vec<u8>) -> Vec<Self>
+	// TCP is a stream, so the last message may be cut short.
+	// Returns the messages and how many bytes they took, the rest waits for the next read.
+	pub fn fromRaw(buf: &[u8]) -> (Vec<Self>, usize)
 	{
 		let mut out = vec![];
 
@@ -16,40 +32,109 @@ impl ToServer
 		{
 			match buf[offset]
 			{
-				0 =>
+				0 if buf.len() >= offset + 4 =>
+				{
+					let len = buf[offset + 3] as usize;
+					let Some(pwd) = buf.get(offset + 4..offset + 4 + len) else { break; };
+					out.push(Self::Setup(
+						u16::from_be_bytes([buf[offset + 1], buf[offset + 2]]),
+						String::from_utf8_lossy(pwd).to_string()
+					));
+					offset += 4 + len;
+				}
+				2 if buf.len() >= offset + 11 =>
+				{
+					out.push(Self::Resume(
+						u64::from_be_bytes(buf[offset + 1..offset + 9].try_into().unwrap()),
+						u16::from_be_bytes([buf[offset + 9], buf[offset + 10]])
+					));
+					offset += 11;
+				}
+				3 if buf.len() >= offset + 5 =>
+				{
+					out.push(Self::Ping(u32::from_be_bytes(
+						buf[offset + 1..offset + 5].try_into().unwrap()
+					)));
+					offset += 5;
+				}
+				4 if buf.len() >= offset + 5 =>
 				{
-					out.push(Self::Setup(u16::from_be_bytes(
-						[buf[offset + 1], buf[offset + 2]]
+					out.push(Self::Pong(u32::from_be_bytes(
+						buf[offset + 1..offset + 5].try_into().unwrap()
 					)));
+					offset += 5;
+				}
+				5 if buf.len() >= offset + 3 =>
+				{
+					out.push(Self::MoveItem(buf[offset + 1], buf[offset + 2]));
 					offset += 3;
 				}
-				x => { println!("Unknown byte: {x}"); offset += 1; }
+				6 if buf.len() >= offset + 4 =>
+				{
+					out.push(Self::SplitItem(buf[offset + 1], buf[offset + 2], buf[offset + 3]));
+					offset += 4;
+				}
+				7 if buf.len() >= offset + 2 =>
+				{
+					let Some((msg, end)) = text(buf, offset + 1) else { break; };
+					out.push(Self::Chat(msg));
+					offset = end;
+				}
+				0 | 2..=7 => break,
+				x => { warn!("Unknown byte: {x}"); offset += 1; }
 			}
 		}
 		
-		out
+		(out, offset)
 	}
 
+	// Only the game sends these.
+	#[allow(dead_code)]
 	pub fn toRaw(self) -> Vec<u8>
 	{
 		match self
 		{
-			Self::Setup(port) =>
+			Self::Setup(port, pwd) =>
+			{
+				let pwd = &pwd.as_bytes()[..pwd.len().min(u8::MAX as usize)];
+				[&[0], &port.to_be_bytes() as &[u8], &[pwd.len() as u8], pwd].concat()
+			}
+			Self::Resume(token, port) =>
 			{
-				[&[0], &port.to_be_bytes() as &[u8]].concat()
+				[&[2], &token.to_be_bytes() as &[u8], &port.to_be_bytes() as &[u8]].concat()
 			}
+			Self::Ping(id) => [&[3], &id.to_be_bytes() as &[u8]].concat(),
+			Self::Pong(id) => [&[4], &id.to_be_bytes() as &[u8]].concat(),
+			Self::MoveItem(from, to) => vec![5, from, to],
+			Self::SplitItem(from, to, count) => vec![6, from, to, count],
+			Self::Chat(msg) => [&[7], &withLen(&msg) as &[u8]].concat()
 		}
 	}
 }
 
+#[derive(Debug, PartialEq)]
 pub enum ToClient
 {
-	Setup(u8, u8, u16)
+	Setup(u8, u8, u16, Quantization, Rules),
+	PlayerLeft(u8),
+	Session(u64, u16),
+	Ping(u32),
+	Pong(u32),
+	Rejected(u8),
+	Inventory(Vec<Slot>),
+	// Checkpoint the player is sent back to.
+	Teleport(String),
+	// Time, user and message.
+	Chat(String, String, String),
+	// The server has changed its tick rate on the fly.
+	TickRate(u8)
 }
 
 impl ToClient
 {
-	pub fn fromRaw(buf: Vec<u8>) -> Vec<Self>
+	// Only the game reads these.
+	#[allow(dead_code)]
+	pub fn fromRaw(buf: &[u8]) -> (Vec<Self>, usize)
 	{
 		let mut out = vec![];
 		let mut offset = 0;
@@ -57,29 +142,236 @@ impl ToClient
 		{
 			match buf[offset]
 			{
-				0 =>
+				0 if buf.len() >= offset + 12 =>
 				{
 					out.push(Self::Setup(
 						buf[offset + 1],
 						buf[offset + 2],
 						u16::from_be_bytes([buf[offset + 3], buf[offset + 4]]),
+						Quantization
+						{
+							position: u16::from_be_bytes([buf[offset + 5], buf[offset + 6]]),
+							velocity: u16::from_be_bytes([buf[offset + 7], buf[offset + 8]])
+						},
+						Rules
+						{
+							authoritative: buf[offset + 9] == 1,
+							speed: u16::from_be_bytes([buf[offset + 10], buf[offset + 11]])
+						}
 					));
+					offset += 12;
+				}
+				2 if buf.len() >= offset + 2 =>
+				{
+					out.push(Self::PlayerLeft(buf[offset + 1]));
+					offset += 2;
+				}
+				3 if buf.len() >= offset + 11 =>
+				{
+					out.push(Self::Session(
+						u64::from_be_bytes(buf[offset + 1..offset + 9].try_into().unwrap()),
+						u16::from_be_bytes([buf[offset + 9], buf[offset + 10]])
+					));
+					offset += 11;
+				}
+				4 if buf.len() >= offset + 5 =>
+				{
+					out.push(Self::Ping(u32::from_be_bytes(
+						buf[offset + 1..offset + 5].try_into().unwrap()
+					)));
+					offset += 5;
+				}
+				5 if buf.len() >= offset + 5 =>
+				{
+					out.push(Self::Pong(u32::from_be_bytes(
+						buf[offset + 1..offset + 5].try_into().unwrap()
+					)));
 					offset += 5;
 				}
-				x => { println!("Unknown byte: {x}"); offset += 1; }
+				6 if buf.len() >= offset + 2 =>
+				{
+					out.push(Self::Rejected(buf[offset + 1]));
+					offset += 2;
+				}
+				7 if buf.len() >= offset + 2 =>
+				{
+					let Some((slots, end)) = slots(buf, offset + 1) else { break; };
+					out.push(Self::Inventory(slots));
+					offset = end;
+				}
+				8 if buf.len() >= offset + 2 =>
+				{
+					let Some((cp, end)) = text(buf, offset + 1) else { break; };
+					out.push(Self::Teleport(cp));
+					offset = end;
+				}
+				9 if buf.len() >= offset + 4 =>
+				{
+					let Some((time, end)) = text(buf, offset + 1) else { break; };
+					let Some((user, end)) = text(buf, end) else { break; };
+					let Some((msg, end)) = text(buf, end) else { break; };
+					out.push(Self::Chat(time, user, msg));
+					offset = end;
+				}
+				10 if buf.len() >= offset + 2 =>
+				{
+					out.push(Self::TickRate(buf[offset + 1]));
+					offset += 2;
+				}
+				0 | 2..=10 => break,
+				x => { warn!("Unknown byte: {x}"); offset += 1; }
 			}
 		}
-		out
+		(out, offset)
 	}
 
 	pub fn toRaw(self) -> Vec<u8>
 	{
 		match self
 		{
-			Self::Setup(tickRate, id, port) =>
+			Self::Setup(tickRate, id, port, q, rules) =>
+			{
+				[
+					&[0, tickRate, id],
+					&port.to_be_bytes() as &[u8],
+					&q.position.to_be_bytes() as &[u8],
+					&q.velocity.to_be_bytes() as &[u8],
+					&[rules.authoritative as u8],
+					&rules.speed.to_be_bytes() as &[u8]
+				].concat()
+			}
+			Self::PlayerLeft(id) => vec![2, id],
+			Self::Session(token, timeout) =>
 			{
-				[&[0, tickRate, id], &port.to_be_bytes() as &[u8]].concat()
+				[&[3], &token.to_be_bytes() as &[u8], &timeout.to_be_bytes() as &[u8]].concat()
 			}
+			Self::Ping(id) => [&[4], &id.to_be_bytes() as &[u8]].concat(),
+			Self::Pong(id) => [&[5], &id.to_be_bytes() as &[u8]].concat(),
+			Self::Rejected(reason) => vec![6, reason],
+			Self::Inventory(slots) =>
+			{
+				let mut out = vec![7, slots.len().min(u8::MAX as usize) as u8];
+				for s in slots.iter().take(u8::MAX as usize)
+				{
+					for text in [&s.id, &s.name]
+					{
+						let text = short(text);
+						out.push(text.len() as u8);
+						out.extend_from_slice(text);
+					}
+					out.extend_from_slice(&[s.count, s.max]);
+				}
+				out
+			}
+			Self::Teleport(cp) => [&[8], &withLen(&cp) as &[u8]].concat(),
+			Self::Chat(time, user, msg) =>
+			{
+				[&[9], &withLen(&time) as &[u8], &withLen(&user), &withLen(&msg)].concat()
+			}
+			Self::TickRate(rate) => vec![10, rate]
 		}
 	}
-}
\ No newline at end of file
+}
+
+// count | (idLen | id | nameLen | name | count | max) * count
+#[allow(dead_code)]
+fn slots(buf: &[u8], offset: usize) -> Option<(Vec<Slot>, usize)>
+{
+	let count = *buf.get(offset)?;
+	let mut offset = offset + 1;
+	let mut out = vec![];
+	for _ in 0..count
+	{
+		let (id, next) = text(buf, offset)?;
+		let (name, next) = text(buf, next)?;
+		let n = buf.get(next..next + 2)?;
+		out.push(Slot { id, name, count: n[0], max: n[1] });
+		offset = next + 2;
+	}
+	Some((out, offset))
+}
+
+// len | text, cut to 255 bytes.
+fn text(buf: &[u8], offset: usize) -> Option<(String, usize)>
+{
+	let len = *buf.get(offset)? as usize;
+	let raw = buf.get(offset + 1..offset + 1 + len)?;
+	Some((String::from_utf8_lossy(raw).to_string(), offset + 1 + len))
+}
+
+fn withLen(s: &str) -> Vec<u8>
+{
+	let s = short(s);
+	[&[s.len() as u8], s].concat()
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// One byte per read, like a slow connection might deliver it.
+	fn trickle<T>(raw: &[u8], parse: fn(&[u8]) -> (Vec<T>, usize)) -> Vec<T>
+	{
+		let mut inbox = vec![];
+		let mut out = vec![];
+		for b in raw
+		{
+			inbox.push(*b);
+			let (messages, used) = parse(&inbox);
+			inbox.drain(..used);
+			out.extend(messages);
+		}
+		assert!(inbox.is_empty());
+		out
+	}
+
+	#[test]
+	fn toServerByteByByte()
+	{
+		let sent = || vec![
+			ToServer::Setup(12345, String::from("pwd")),
+			ToServer::Resume(u64::MAX, 7),
+			ToServer::Chat(String::from("привет")),
+			ToServer::Ping(1),
+			ToServer::SplitItem(1, 2, 3)
+		];
+		let raw: Vec<u8> = sent().into_iter().flat_map(ToServer::toRaw).collect();
+		assert_eq!(trickle(&raw, ToServer::fromRaw), sent());
+	}
+
+	#[test]
+	fn toClientByteByByte()
+	{
+		let sent = ||
+		{
+			let mut x = vec![
+				ToClient::Setup(
+					30, 2, 4000,
+					Quantization { position: 100, velocity: 50 },
+					Rules { authoritative: true, speed: 400 }
+				),
+				ToClient::Session(42, 60),
+				ToClient::Inventory(vec![Slot { id: String::from("key"), name: String::from("Ключ"), count: 1, max: 1 }]),
+				ToClient::Teleport(String::from("start")),
+				ToClient::TickRate(20)
+			];
+			for i in 0..20
+			{
+				x.push(ToClient::Chat(String::from("12:00"), format!("Player #{i}"), String::from("hi")));
+			}
+			x
+		};
+		let raw: Vec<u8> = sent().into_iter().flat_map(ToClient::toRaw).collect();
+		assert_eq!(trickle(&raw, ToClient::fromRaw), sent());
+	}
+
+	#[test]
+	fn unknownBytesAreSkipped()
+	{
+		let raw = [&[200u8] as &[u8], &ToServer::Ping(5).toRaw()].concat();
+		assert_eq!(ToServer::fromRaw(&raw), (vec![ToServer::Ping(5)], raw.len()));
+		// A cut message is left for the next read.
+		assert_eq!(ToServer::fromRaw(&raw[..3]), (vec![], 1));
+	}
+}
@@ -1,16 +1,62 @@
 #![allow(non_snake_case)]
 
-use std::time::{Duration, Instant};
+use std::{collections::{HashSet, VecDeque}, sync::atomic::{AtomicBool, Ordering}, thread::JoinHandle, time::{Duration, Instant}};
 
+use crate::{error, warn, info, debug};
+
+mod api;
+mod auth;
+mod chat;
 mod config;
+mod http;
 mod player;
+mod saves;
 mod state;
 mod web;
+mod websocket;
+pub mod console;
+pub mod inventory;
+pub mod log;
 pub mod message;
+pub mod movement;
+pub mod net;
+pub mod netsim;
+pub mod options;
+pub mod packet;
+pub mod script;
+pub mod stats;
+
+
+// Threads get this long to say goodbye before the server stops without them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Messages a player gets on joining.
+const CHAT_ON_JOIN: usize = 20;
+
+// Set by the stop button, the console, signals or the game. Checked every tick.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+pub fn stop() { STOP.store(true, Ordering::Relaxed); }
+
+// Ctrl+C and SIGTERM stop the standalone server like the stop button does.
+// A second one gives up on saving and exits at once.
+pub fn catchSignals()
+{
+	extern "C" fn handler(_: libc::c_int)
+	{
+		if STOP.swap(true, Ordering::Relaxed) { unsafe { libc::_exit(130); } }
+	}
+	unsafe
+	{
+		libc::signal(libc::SIGINT, handler as extern "C" fn(libc::c_int) as libc::sighandler_t);
+		libc::signal(libc::SIGTERM, handler as extern "C" fn(libc::c_int) as libc::sighandler_t);
+	}
+}
 
 fn launchWS() -> (
 	std::sync::mpsc::Sender<web::Response>,
-	std::sync::mpsc::Receiver<web::Request>
+	std::sync::mpsc::Receiver<web::Request>,
+	Option<JoinHandle<()>>
 )
 {
 	let (toWeb, fromMainToWeb) =
@@ -19,15 +65,16 @@ fn launchWS() -> (
 	let (fromWebToMain, fromWeb) =
 		std::sync::mpsc::channel::<web::Request>();
 	
-	let _ = std::thread::Builder::new()
+	let thread = std::thread::Builder::new()
 		.name(String::from("WebServer"))
 		.spawn(|| web::main(fromWebToMain, fromMainToWeb));
-	(toWeb, fromWeb)
+	(toWeb, fromWeb, thread.ok())
 }
 
 fn launchSession() -> (
 	std::sync::mpsc::Sender<player::Response>,
-	std::sync::mpsc::Receiver<player::Request>
+	std::sync::mpsc::Receiver<player::Request>,
+	Option<JoinHandle<()>>
 )
 {
 	let (toSession, fromMainToSession) =
@@ -36,71 +83,262 @@ fn launchSession() -> (
 	let (fromSessionToMain, fromSession) =
 		std::sync::mpsc::channel::<player::Request>();
 
-	let _ = std::thread::Builder::new()
+	let thread = std::thread::Builder::new()
 		.name(String::from("Session"))
 		.spawn(|| player::main(fromSessionToMain, fromMainToSession));
 
-	(toSession, fromSession)
+	(toSession, fromSession, thread.ok())
 }
 
-pub fn main()
+// Reads commands from stdin. Once stdin is closed the console is gone for good.
+// Only the standalone server has one, the game keeps its stdin.
+pub fn launchConsole() -> std::sync::mpsc::Receiver<console::Command>
 {
-	let mut cfg = config::load("res/system/cfg.json");
+	let (toMain, fromConsole) = std::sync::mpsc::channel::<console::Command>();
+	let _ = std::thread::Builder::new()
+		.name(String::from("Console"))
+		.spawn(|| console::main(toMain));
+	fromConsole
+}
+
+pub fn main(opts: options::Options, fromConsole: Option<std::sync::mpsc::Receiver<console::Command>>)
+{
+	options::init(opts);
+	let opts = options::get();
+	log::init(opts.logFilter.clone(), &opts.logPath());
+
+	let mut cfg = config::load(&opts.config);
 	if cfg.firstCP.is_empty()
 	{
-		println!("No configuration found. Creating new one.");
-		println!("Proceed to http://localhost:8080 and set the server up.");
-		config::save(&cfg, "res/system/cfg.json");
+		info!("No configuration found. Creating new one.");
+		info!("Proceed to http://localhost:{} and set the server up.", opts.webPort);
+		config::save(&cfg, &opts.config);
 	}
-	else { println!("Configuration found."); }
+	else { info!("Configuration found."); }
 
-	let mut state = state::load("res/system/save.json");
+	// Older configurations keep the admin password in plain text.
+	if cfg.adminHash.is_empty()
+	{
+		if cfg.password == config::Config::default().password
+		{
+			warn!("The web panel uses the default password. Change it after logging in.");
+		}
+		cfg.adminHash = auth::hash(&cfg.password);
+		cfg.password.clear();
+		config::save(&cfg, &opts.config);
+	}
+	let mut auth = auth::Auth::default();
+	let bansPath = opts.system("bans.json");
+	let mut bans = loadBans(&bansPath);
+	let mut muted = HashSet::<String>::new();
+	let mut players: Vec<player::Info> = vec![];
+
+	if !saves::valid(&cfg.saveSlot) { cfg.saveSlot = String::from("main"); }
+	saves::adopt(&cfg.saveSlot);
+	let mut state = state::load(&saves::path(&cfg.saveSlot));
+	state.slot = cfg.saveSlot.clone();
+	let mut occupied = false;
+	let mut limits = inventory::Limits
+	{
+		cellSize: cfg.itemCellSize,
+		items: inventory::Items::load(&opts.system("items.json"))
+	};
+	let mut lastSave = Instant::now();
+	state.netsim = cfg.simEnabled == 1;
+	// What the session runs with, while some saved settings wait for nobody to play.
+	let mut running = cfg.clone();
 
 	let (
 		mut toWeb,
-		mut fromWeb
+		mut fromWeb,
+		mut webThread
 	) = launchWS();
 
 	let (
 		mut toSession,
-		mut fromSession
+		mut fromSession,
+		mut sessionThread
 	) = launchSession();
 
-	let _ = toSession.send((0, player::Resp::UpdateConfig(usize::MAX, cfg.clone())));
+	let _ = toSession.send((0, player::Resp::UpdateConfig(session(&cfg, opts))));
+	let _ = toSession.send((0, player::Resp::SetCheckpoint(state.checkpoint.clone())));
+	let _ = toSession.send((0, player::Resp::Bans(bans.clone())));
 
-	let mut chat: Vec<(String, String)> = vec![];
+	let mut chat = chat::Chat::load(&opts.system("chat.jsonl"));
+	let mut netStats = vec![];
+	if fromConsole.is_some() { info!("Type 'help' for console commands."); }
 
-	let mut sysTimer = Duration::from_secs_f32(1.0 / 100.0);
+	let mut sysTimer = Duration::from_secs_f32(1.0 / cfg.sysTickRate.max(1) as f32);
+	// Once stopping, requests are still handled until both threads are gone.
+	let mut stopping: Option<Instant> = None;
 
 	loop
 	{
 		let timer = Instant::now();
+
+		if stopping.is_none() && STOP.load(Ordering::Relaxed)
+		{
+			info!("Stopping the server...");
+			let _ = toSession.send((0, player::Resp::Shutdown));
+			let _ = toWeb.send((0, web::Resp::Shutdown));
+			stopping = Some(Instant::now());
+		}
+		// The pass after both threads are gone picks up whatever they sent last.
+		let mut last = false;
+		if let Some(since) = stopping
+		{
+			let done = |x: &Option<JoinHandle<()>>| x.as_ref().is_none_or(|x| x.is_finished());
+			if done(&webThread) && done(&sessionThread) { last = true; }
+			else if since.elapsed() > SHUTDOWN_TIMEOUT
+			{
+				warn!("Threads did not stop in time, leaving them behind.");
+				last = true;
+			}
+		}
+
+		// Console commands go through the same handlers as the web panel.
+		let mut queue = VecDeque::new();
+		for cmd in fromConsole.iter().flat_map(|x| x.try_iter())
+		{
+			match cmd
+			{
+				console::Command::Status =>
+				{
+					println!("Save slot '{}', checkpoint '{}', saved {}.",
+						state.slot, state.checkpoint,
+						if state.date.is_empty() { "never" } else { &state.date });
+					println!("Gates are {}, network simulation is {}.",
+						if state.visible { "open" } else { "closed" },
+						if state.netsim { "on" } else { "off" });
+					println!("Players: {}/{}.",
+						players.iter().filter(|x| !x.detached).count(), cfg.playersCount);
+				}
+				console::Command::Players =>
+				{
+					if players.is_empty() { println!("No players."); }
+					for p in &players
+					{
+						println!("#{} {} ({}), ping {} ms, at {:.1} {:.1} {:.1}{}{}",
+							p.id,
+							if p.name.is_empty() { "noname" } else { &p.name },
+							p.ip,
+							p.stats.rtt.as_millis(),
+							p.pos.x, p.pos.y, p.pos.z,
+							if p.detached { ", reconnecting" } else { "" },
+							if p.muted { ", muted" } else { "" });
+					}
+				}
+				console::Command::Web(req) => queue.push_back((console::ID, req))
+			}
+		}
+
 		'webRecv: loop
 		{
-			match fromWeb.try_recv()
+			let next = match queue.pop_front()
+			{
+				Some(x) => Ok(x),
+				None => fromWeb.try_recv()
+			};
+			match next
 			{
+				Ok((id, web::Req::Api(token, msg))) =>
+				{
+					// Every HTTP request carries its own token, the connection keeps no session.
+					auth.closed(id);
+					if !token.is_empty() { auth.resume(id, &token); }
+					queue.push_front((id, web::Req::ApiDone));
+					queue.push_front((id, *msg));
+				}
 				Ok((id, msg)) =>
 				{
+					if id != console::ID && !auth.allowed(id, msg.permission())
+					{
+						warn!("WebClient #{id} is not logged in.");
+						let _ = toWeb.send((id, web::Resp::Auth(None, String::from("required"), 0)));
+						continue;
+					}
 					match msg
 					{
+						web::Req::Login(ip, password) =>
+						{
+							match auth.login(id, ip, &password, &cfg.adminHash)
+							{
+								auth::Login::Ok(token) =>
+								{
+									info!("WebClient #{id} logged in from {ip}.");
+									let _ = toWeb.send((id, web::Resp::Auth(Some(token), String::new(), 0)));
+									welcome(&toWeb, id, &cfg);
+									let _ = toWeb.send((id, web::Resp::State(state.clone(), netStats.clone())));
+									let _ = toWeb.send((id, web::Resp::Players(players.clone(), banList(&bans))));
+								}
+								auth::Login::Wrong =>
+								{
+									warn!("WebClient #{id} entered a wrong password.");
+									let _ = toWeb.send((id, web::Resp::Auth(None, String::from("wrong"), 0)));
+								}
+								auth::Login::Locked(wait) =>
+								{
+									let _ = toWeb.send((id, web::Resp::Auth(None, String::from("locked"), wait)));
+								}
+							}
+						}
+						web::Req::Resume(token) =>
+						{
+							if auth.resume(id, &token)
+							{
+								let _ = toWeb.send((id, web::Resp::Auth(Some(token), String::new(), 0)));
+								welcome(&toWeb, id, &cfg);
+								let _ = toWeb.send((id, web::Resp::State(state.clone(), netStats.clone())));
+								let _ = toWeb.send((id, web::Resp::Players(players.clone(), banList(&bans))));
+							}
+							else
+							{
+								let _ = toWeb.send((id, web::Resp::Auth(None, String::from("expired"), 0)));
+							}
+						}
+						web::Req::Logout =>
+						{
+							auth.logout(id);
+							let _ = toWeb.send((id, web::Resp::Auth(None, String::from("logout"), 0)));
+						}
+						web::Req::Closed => auth.closed(id),
+						web::Req::ApiDone =>
+						{
+							auth.closed(id);
+							let _ = toWeb.send((id, web::Resp::ApiDone));
+						}
+						web::Req::Api(..) => {}
+						web::Req::ChangePassword(old, new) =>
+						{
+							if !auth::verify(&cfg.adminHash, &old) || new.chars().count() < 6
+							{
+								let _ = toWeb.send((id, web::Resp::Modal("changePassword-fail".to_string())));
+								continue;
+							}
+							cfg.adminHash = auth::hash(&new);
+							config::save(&cfg, &opts.config);
+							auth.reset();
+							info!("Web panel password changed, every session has ended.");
+							let _ = toWeb.send((0, web::Resp::Auth(None, String::from("changed"), 0)));
+						}
 						web::Req::ChatMessages(offset) =>
 						{
-							let mut msg =
-								if offset >= chat.len() { vec![] }
-								else { chat[offset..chat.len()].to_vec() };
-							msg.reverse();
-							let _ = toWeb.send((id, web::Resp::ChatMessages(msg)));
+							let _ = toWeb.send((id, web::Resp::ChatMessages(chat.since(offset))));
 						}
 						web::Req::NewMessage(msg) =>
 						{
-							println!("WebClient #{id}: {msg}");
-							let name = format!("WebClient #{id}");
-							chat.push((name.clone(), msg.clone()));
-							let _ = toWeb.send((id, web::Resp::NewMessage(name, msg)));
+							let msg = msg.trim().to_string();
+							if msg.is_empty() { continue; }
+							let name = if id == console::ID { String::from("Server") }
+								else { format!("WebClient #{id}") };
+							info!("{name}: {msg}");
+							let e = chat.push(name, msg);
+							let _ = toWeb.send((id, web::Resp::NewMessage(e.clone())));
+							let _ = toSession.send((0, player::Resp::Chat(e)));
 						}
 						web::Req::State =>
 						{
-							let _ = toWeb.send((id, web::Resp::State(state.clone())));
+							let _ = toWeb.send((id, web::Resp::State(state.clone(), netStats.clone())));
 						}
 						web::Req::GetSettings =>
 						{
@@ -108,60 +346,133 @@ pub fn main()
 						}
 						web::Req::SaveSettings(new) =>
 						{
-							if cfg.locked
-							{
-								let _ = toWeb.send(
-									(id, web::Resp::Modal("saveSettings-fail".to_string()))
-								);
-								continue;
-							}
-							config::apply(&mut cfg, new);
-							config::save(&cfg, "res/system/save.json");
+							let adjusted = config::apply(&mut cfg, new);
+							state.netsim = cfg.simEnabled == 1;
+							limits.cellSize = cfg.itemCellSize;
+							config::save(&cfg, &opts.config);
 							sysTimer = Duration::from_secs_f32(
-								1.0 / cfg.sysTickRate as f32
-							);
-							let _ = toSession.send(
-								(0, player::Resp::UpdateConfig(id, cfg.clone()))
-							);
-							let _ = toWeb.send(
-								(id, web::Resp::Modal("saveSettings-success".to_string()))
+								1.0 / cfg.sysTickRate.max(1) as f32
 							);
+							let _ = toSession.send((0, player::Resp::UpdateConfig(session(&cfg, opts))));
+							// The session holds these back while anyone plays, including ones saved earlier.
+							let (now, waiting) = if occupied { config::live(&running, &cfg) } else { (cfg.clone(), vec![]) };
+							running = now;
+							if !adjusted.is_empty()
+							{
+								warn!("Saved, but {} did not fit and were adjusted.", adjusted.join(", "));
+							}
+							if !waiting.is_empty()
+							{
+								warn!("Saved, but {} will apply once no players are connected.", waiting.join(", "));
+							}
+							if id != console::ID
+							{
+								let names = |x: Vec<&str>| x.into_iter().map(String::from).collect();
+								let _ = toWeb.send((id, web::Resp::Saved(names(waiting), names(adjusted))));
+							}
+							modal(&toWeb, id, "saveSettings-success");
 						}
-						web::Req::Modal(id, result) =>
+						web::Req::Modal(modalID, result) =>
 						{
-							match id.as_str()
+							match modalID.as_str()
 							{
-								"saveSettings-success" => { println!("Settings saved."); }
-								"saveSettings-fail" => { println!("Failed to save settings."); }
+								"saveSettings-success" => { info!("Settings saved."); }
+								"saveSettings-fail" => { error!("Failed to save settings."); }
 								"stopServer" =>
 								{
-									if let Some(x) = result["pwd"].as_str()
+									// The console is trusted and needs no password.
+									let pwd = result["pwd"].as_str();
+									if id != console::ID && pwd.is_none()
+									{
+										info!("Revoked request to stop the server.");
+									}
+									else if id == console::ID || pwd.is_some_and(|x| auth::verify(&cfg.adminHash, x))
 									{
-										if x == cfg.password
-										{
-											println!("Correct password. Stopping the server...");
-											std::process::exit(0);
-										}
-										else
-										{
-											println!("Incorrect password to stop the server.");
-										}
+										stop();
 									}
 									else
 									{
-										println!("Revoked request to stop the server.");
+										warn!("Incorrect password to stop the server.");
+									}
+								}
+								x => { debug!("New modal: {x}: {result:#}") }
+							}
+						}
+						web::Req::Saves =>
+						{
+							let _ = toWeb.send((id, web::Resp::Saves(cfg.saveSlot.clone(), saves::list())));
+						}
+						web::Req::SaveSlot(action) =>
+						{
+							let slot = state.slot.clone();
+							match slotAction(action, &mut cfg, &mut state, occupied)
+							{
+								Ok(_) =>
+								{
+									if state.slot != slot
+									{
+										config::save(&cfg, &opts.config);
 									}
+									let _ = toSession.send(
+										(0, player::Resp::SetCheckpoint(state.checkpoint.clone()))
+									);
+									let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+								}
+								Err(x) =>
+								{
+									error!("Save slot action failed: {x}");
+									let _ = toWeb.send((id, web::Resp::Modal("saves-fail".to_string())));
 								}
-								x => { println!("New modal: {x}: {result:#}") }
 							}
+							let _ = toWeb.send((0, web::Resp::Saves(cfg.saveSlot.clone(), saves::list())));
+						}
+						web::Req::Players =>
+						{
+							let _ = toWeb.send((id, web::Resp::Players(players.clone(), banList(&bans))));
+						}
+						web::Req::PlayerAction(target, action) =>
+						{
+							// Bans and mutes stick to the address, known from the last player list.
+							let ip = players.iter().find(|x| x.id == target).map(|x| x.ip.clone());
+							match (action, ip)
+							{
+								(player::Action::Kick, _) =>
+								{
+									let _ = toSession.send((0, player::Resp::Kick(target)));
+								}
+								(player::Action::Teleport, _) =>
+								{
+									let _ = toSession.send((0, player::Resp::Teleport(target)));
+								}
+								(player::Action::Ban, Some(ip)) =>
+								{
+									info!("Banned {ip}.");
+									bans.insert(ip);
+									saveBans(&bans, &bansPath);
+									let _ = toSession.send((0, player::Resp::Bans(bans.clone())));
+								}
+								(player::Action::Mute(on), Some(ip)) =>
+								{
+									if on { muted.insert(ip.clone()); } else { muted.remove(&ip); }
+									for p in players.iter_mut().filter(|x| x.ip == ip) { p.muted = on; }
+								}
+								(action, None) => warn!("No player #{target} to {action:?}.")
+							}
+							let _ = toWeb.send((0, web::Resp::Players(players.clone(), banList(&bans))));
+						}
+						web::Req::Unban(ip) =>
+						{
+							if bans.remove(&ip)
+							{
+								info!("Unbanned {ip}.");
+								saveBans(&bans, &bansPath);
+								let _ = toSession.send((0, player::Resp::Bans(bans.clone())));
+							}
+							let _ = toWeb.send((0, web::Resp::Players(players.clone(), banList(&bans))));
 						}
 						web::Req::Buttons =>
 						{
-							let _ = toWeb.send((id, web::Resp::Buttons(vec![
-								(String::from("setVisible"), String::from("Открыть врата")),
-								(String::from("setInvisible"), String::from("Закрыть врата")),
-								(String::from("stop"), String::from("Остановить сервер"))
-							])));
+							let _ = toWeb.send((id, web::Resp::Buttons(buttons())));
 						}
 						web::Req::ClickButton(btn) =>
 						{
@@ -173,6 +484,26 @@ pub fn main()
 							{
 								let _ = toSession.send((0, player::Resp::SetVisible(id, false)));
 							}
+							if btn == "netsimOn" || btn == "netsimOff"
+							{
+								cfg.simEnabled = (btn == "netsimOn") as u8;
+								state.netsim = cfg.simEnabled == 1;
+								let _ = toSession.send((0, player::Resp::SetConditions(cfg.conditions())));
+								let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+							}
+							if btn == "save"
+							{
+								match state::save(&mut state, &saves::path(&cfg.saveSlot))
+								{
+									Ok(_) => lastSave = Instant::now(),
+									Err(x) => error!("Failed to save the game: {x}")
+								}
+								let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+							}
+							if btn == "saves"
+							{
+								let _ = toWeb.send((id, web::Resp::Saves(cfg.saveSlot.clone(), saves::list())));
+							}
 							if btn == "stop"
 							{
 								let _ = toWeb.send((
@@ -187,10 +518,13 @@ pub fn main()
 					match x
 					{
 						std::sync::mpsc::TryRecvError::Empty => {}
+						std::sync::mpsc::TryRecvError::Disconnected if stopping.is_some() => {}
 						std::sync::mpsc::TryRecvError::Disconnected =>
 						{
-							println!("WebServer channel has disconnected. Reloading...");
-							(toWeb, fromWeb) = launchWS();
+							error!("WebServer channel has disconnected. Reloading...");
+							(toWeb, fromWeb, webThread) = launchWS();
+							// Connection ids start over, sessions can be resumed.
+							auth.closeAll();
 						}
 					}
 					break 'webRecv;
@@ -206,21 +540,72 @@ pub fn main()
 				{
 					match req
 					{
-						player::Req::UnlockSettings(active) =>
+						player::Req::UnlockSettings(unlocked) =>
 						{
-							cfg.locked = active;
-						}
-						player::Req::ShowModal(web, id) =>
-						{
-							let _ = toWeb.send((
-								web,
-								web::Resp::Modal(id)
-							));
+							occupied = !unlocked;
+							if unlocked { running = cfg.clone(); }
 						}
+						player::Req::ShowModal(web, id) => modal(&toWeb, web, &id),
 						player::Req::SetVisible(active) =>
 						{
 							state.visible = active;
-							let _ = toWeb.send((0, web::Resp::State(state.clone())));
+							let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+						}
+						player::Req::Change(change) =>
+						{
+							let owner = change.owner().map(String::from);
+							if state.apply(change, &limits)
+							{
+								let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+							}
+							if let Some(ip) = owner
+							{
+								let slots = state.getAccount(ip.clone()).inventory.slots(&limits);
+								let _ = toSession.send((0, player::Resp::Inventory(ip, slots)));
+							}
+						}
+						player::Req::Joined(id) =>
+						{
+							let history = chat.recent(CHAT_ON_JOIN).to_vec();
+							let _ = toSession.send((0, player::Resp::ChatHistory(id, history)));
+						}
+						player::Req::Chat(id, ip, msg) =>
+						{
+							let msg = msg.trim().to_string();
+							if msg.is_empty() { continue; }
+							if muted.contains(&ip)
+							{
+								info!("Player #{id} is muted: {msg}");
+								continue;
+							}
+							let name = state.getAccount(ip).name;
+							let name = if name.is_empty() { format!("Player #{id}") } else { name };
+							info!("{name}: {msg}");
+							let e = chat.push(name, msg);
+							let _ = toWeb.send((0, web::Resp::NewMessage(e.clone())));
+							let _ = toSession.send((0, player::Resp::Chat(e)));
+						}
+						player::Req::Inventory(ip) =>
+						{
+							let slots = state.getAccount(ip.clone()).inventory.slots(&limits);
+							let _ = toSession.send((0, player::Resp::Inventory(ip, slots)));
+						}
+						player::Req::Players(mut list) =>
+						{
+							for p in &mut list
+							{
+								let a = state.getAccount(p.ip.clone());
+								p.name = a.name;
+								p.class = a.class;
+								p.muted = muted.contains(&p.ip);
+							}
+							netStats = list.iter()
+								.filter(|x| !x.detached)
+								.map(|x| (x.id, x.ip.clone(), x.stats.clone()))
+								.collect();
+							players = list;
+							let _ = toWeb.send((0, web::Resp::State(state.clone(), netStats.clone())));
+							let _ = toWeb.send((0, web::Resp::Players(players.clone(), banList(&bans))));
 						}
 					}
 				}
@@ -229,26 +614,183 @@ pub fn main()
 					match x
 					{
 						std::sync::mpsc::TryRecvError::Empty => {}
+						std::sync::mpsc::TryRecvError::Disconnected if stopping.is_some() => {}
 						std::sync::mpsc::TryRecvError::Disconnected =>
 						{
-							println!("Player session channel has disconnected. Reloading...");
-							(toSession, fromSession) = launchSession();
+							error!("Player session channel has disconnected. Reloading...");
+							(toSession, fromSession, sessionThread) = launchSession();
+							running = cfg.clone();
+							let _ = toSession.send(
+								(0, player::Resp::UpdateConfig(session(&cfg, opts)))
+							);
 							let _ = toSession.send(
-								(0, player::Resp::UpdateConfig(usize::MAX, cfg.clone()))
+								(0, player::Resp::SetCheckpoint(state.checkpoint.clone()))
 							);
+							let _ = toSession.send((0, player::Resp::Bans(bans.clone())));
 						}
 					}
 					break 'playerRecv;
 				}
 			}
 		}
+		if last { break; }
 
-		if timer.elapsed() < sysTimer
+		if cfg.autosave > 0 && state.dirty
+			&& lastSave.elapsed().as_secs() >= cfg.autosave as u64
 		{
-			if let Some(x) = sysTimer.checked_sub(timer.elapsed())
+			saves::rotate(&cfg.saveSlot, cfg.backups);
+			if let Err(x) = state::save(&mut state, &saves::path(&cfg.saveSlot))
 			{
-				std::thread::sleep(x);
+				error!("Failed to save the game: {x}");
 			}
+			lastSave = Instant::now();
 		}
+
+		if let Some(x) = sysTimer.checked_sub(timer.elapsed())
+		{
+			std::thread::sleep(x);
+		}
+	}
+
+	if let Err(x) = state::save(&mut state, &saves::path(&cfg.saveSlot))
+	{
+		error!("Failed to save the game: {x}");
 	}
-}
\ No newline at end of file
+	for t in [webThread, sessionThread].into_iter().flatten()
+	{
+		if t.is_finished() { let _ = t.join(); }
+	}
+	STOP.store(false, Ordering::Relaxed);
+	info!("Server is stopped.");
+}
+
+fn buttons() -> Vec<(String, String)>
+{
+	vec![
+		(String::from("setVisible"), String::from("Открыть врата")),
+		(String::from("setInvisible"), String::from("Закрыть врата")),
+		(String::from("netsimOn"), String::from("Включить симуляцию сети")),
+		(String::from("netsimOff"), String::from("Выключить симуляцию сети")),
+		(String::from("save"), String::from("Сохранить игру")),
+		(String::from("saves"), String::from("Сохранения")),
+		(String::from("stop"), String::from("Остановить сервер"))
+	]
+}
+
+// What the session runs with. Command-line overrides never make it into cfg.json.
+fn session(cfg: &config::Config, opts: &options::Options) -> config::Config
+{
+	let mut out = cfg.clone();
+	if let Some(port) = opts.port { out.port = port; }
+	out
+}
+
+// Shows a modal in the web panel, or tells the console what it would have said.
+fn modal(toWeb: &std::sync::mpsc::Sender<web::Response>, id: usize, name: &str)
+{
+	if id == console::ID { println!("Console: {name}"); }
+	else { let _ = toWeb.send((id, web::Resp::Modal(name.to_string()))); }
+}
+
+// What an admin sees right after logging in.
+fn welcome(toWeb: &std::sync::mpsc::Sender<web::Response>, id: usize, cfg: &config::Config)
+{
+	let _ = toWeb.send((id, web::Resp::GetSettings(cfg.clone())));
+	let _ = toWeb.send((id, web::Resp::Buttons(buttons())));
+	let _ = toWeb.send((id, web::Resp::Saves(cfg.saveSlot.clone(), saves::list())));
+}
+
+// Banned addresses, kept in bans.json next to the configuration.
+fn loadBans(path: &str) -> HashSet<String>
+{
+	let Ok(f) = std::fs::read_to_string(path) else { return HashSet::new(); };
+	match json::parse(&f)
+	{
+		Ok(x) => x.members().filter_map(|x| x.as_str()).map(String::from).collect(),
+		Err(x) =>
+		{
+			error!("Ban list is broken: {x}");
+			HashSet::new()
+		}
+	}
+}
+
+fn saveBans(bans: &HashSet<String>, path: &str)
+{
+	if let Err(x) = std::fs::write(path, json::stringify_pretty(banList(bans), 4))
+	{
+		error!("Failed to save the ban list: {x}");
+	}
+}
+
+fn banList(bans: &HashSet<String>) -> Vec<String>
+{
+	let mut list: Vec<String> = bans.iter().cloned().collect();
+	list.sort();
+	list
+}
+
+// Slots other than the active one are only files on disk.
+// Replacing the running game is allowed only when nobody plays.
+fn slotAction(
+	action: saves::Action,
+	cfg: &mut config::Config,
+	state: &mut state::State,
+	occupied: bool
+) -> std::io::Result<()>
+{
+	use std::io::ErrorKind;
+
+	let active = cfg.saveSlot.clone();
+	match action
+	{
+		saves::Action::Create(name) => saves::create(&name),
+		saves::Action::Copy(from, to) =>
+		{
+			if from == active && state.dirty { state::save(state, &saves::path(&active))?; }
+			saves::copy(&from, &to)
+		}
+		saves::Action::Rename(from, to) =>
+		{
+			saves::rename(&from, &to)?;
+			if from == active
+			{
+				cfg.saveSlot = to.clone();
+				state.slot = to;
+			}
+			Ok(())
+		}
+		saves::Action::Delete(name) =>
+		{
+			if name == active { return Err(ErrorKind::InvalidInput.into()); }
+			saves::delete(&name)
+		}
+		saves::Action::Load(name) =>
+		{
+			if occupied { return Err(ErrorKind::ResourceBusy.into()); }
+			if !saves::exists(&name) { return Err(ErrorKind::NotFound.into()); }
+			if state.dirty { state::save(state, &saves::path(&active))?; }
+			reload(state, &name);
+			cfg.saveSlot = name;
+			info!("Switched to save slot '{}'.", cfg.saveSlot);
+			Ok(())
+		}
+		saves::Action::Restore(name, n) =>
+		{
+			if name == active && occupied { return Err(ErrorKind::ResourceBusy.into()); }
+			saves::restore(&name, n, cfg.backups)?;
+			if name == active { reload(state, &name); }
+			info!("Slot '{name}' is rolled back to backup {n}.");
+			Ok(())
+		}
+	}
+}
+
+fn reload(state: &mut state::State, name: &str)
+{
+	let mut new = state::load(&saves::path(name));
+	new.slot = name.to_string();
+	new.visible = state.visible;
+	new.netsim = state.netsim;
+	*state = new;
+}
@@ -0,0 +1,117 @@
+use crate::envell::packet::State;
+
+pub const SPRINT: u16 = 1 << 0;
+pub const WALK: u16 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rules
+{
+	pub authoritative: bool,
+	pub speed: u16
+}
+
+impl Default for Rules
+{
+	fn default() -> Self
+	{
+		Self { authoritative: false, speed: 200 }
+	}
+}
+
+// Inputs are kept quantized, so the client predicts
+// with exactly the same values the server simulates.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Input
+{
+	pub seq: u16,
+	movement: [i8; 3],
+	yaw: u16,
+	pitch: u16,
+	pub buttons: u16
+}
+
+impl Input
+{
+	pub const SIZE: usize = 11;
+
+	// Only the game samples inputs, the server reads them.
+	#[allow(dead_code)]
+	pub fn new(seq: u16, movement: glam::Vec3, angle: glam::Vec2, buttons: u16) -> Self
+	{
+		let m = movement.clamp(glam::Vec3::splat(-1.0), glam::Vec3::ONE) * 127.0;
+		let wrap = |x: f32|
+		{
+			(x.rem_euclid(360.0) / 360.0 * 65536.0).round() as u32 as u16
+		};
+		Self
+		{
+			seq,
+			movement: [m.x.round() as i8, m.y.round() as i8, m.z.round() as i8],
+			yaw: wrap(angle.x),
+			pitch: wrap(angle.y),
+			buttons
+		}
+	}
+
+	pub fn movement(&self) -> glam::Vec3
+	{
+		glam::vec3(
+			self.movement[0] as f32,
+			self.movement[1] as f32,
+			self.movement[2] as f32
+		) / 127.0
+	}
+
+	pub fn angle(&self) -> glam::Vec2
+	{
+		glam::vec2(
+			(self.yaw as i16) as f32 / 65536.0 * 360.0,
+			(self.pitch as i16) as f32 / 65536.0 * 360.0
+		)
+	}
+
+	pub fn toRaw(&self) -> [u8; Self::SIZE]
+	{
+		let s = self.seq.to_be_bytes();
+		let y = self.yaw.to_be_bytes();
+		let p = self.pitch.to_be_bytes();
+		let b = self.buttons.to_be_bytes();
+		[
+			s[0], s[1],
+			self.movement[0] as u8, self.movement[1] as u8, self.movement[2] as u8,
+			y[0], y[1], p[0], p[1], b[0], b[1]
+		]
+	}
+
+	pub fn fromRaw(buf: &[u8]) -> Option<Self>
+	{
+		if buf.len() < Self::SIZE { return None; }
+		Some(Self
+		{
+			seq: u16::from_be_bytes([buf[0], buf[1]]),
+			movement: [buf[2] as i8, buf[3] as i8, buf[4] as i8],
+			yaw: u16::from_be_bytes([buf[5], buf[6]]),
+			pitch: u16::from_be_bytes([buf[7], buf[8]]),
+			buttons: u16::from_be_bytes([buf[9], buf[10]])
+		})
+	}
+}
+
+pub fn simulate(state: &mut State, input: &Input, rules: &Rules, dt: f32)
+{
+	let angle = input.angle();
+	let m = input.movement();
+	let mut speed = rules.speed as f32 * 0.01;
+	if input.buttons & SPRINT != 0 { speed *= 2.0; }
+	if input.buttons & WALK != 0 { speed *= 0.5; }
+
+	let yaw = angle.x.to_radians();
+	let d = glam::vec3(yaw.sin(), 0.0, yaw.cos());
+	state.vel = glam::vec3(
+		d.x * m.z - d.z * m.x,
+		m.y,
+		d.z * m.z + d.x * m.x
+	) * speed;
+	state.pos += state.vel * dt;
+	state.angle = glam::vec3(angle.x, angle.y, 0.0);
+}
@@ -0,0 +1,187 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use mio::{Interest, Registry, Token, net::{TcpListener, UdpSocket}};
+
+use crate::warn;
+
+pub const DISCOVERY_PORT: u16 = 26225;
+pub const DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x4e56);
+
+// Binds [::] first, which is dual-stack on most systems.
+// Where it is IPv6-only (Windows), a separate IPv4 socket on the same port is added.
+pub fn bindTcp(port: u16) -> Vec<TcpListener>
+{
+	let mut out = vec![];
+	if let Ok(l) = TcpListener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+	{
+		out.push(l);
+	}
+	let port = out.first()
+		.and_then(|x| x.local_addr().ok())
+		.map(|x| x.port())
+		.unwrap_or(port);
+	if let Ok(l) = TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+	{
+		out.push(l);
+	}
+	out
+}
+
+pub fn canonical(addr: SocketAddr) -> SocketAddr
+{
+	SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub struct DualUdp
+{
+	pub sockets: Vec<UdpSocket>
+}
+
+impl DualUdp
+{
+	pub fn bind(port: u16) -> Option<Self>
+	{
+		let mut sockets = vec![];
+		if let Ok(s) = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+		{
+			sockets.push(s);
+		}
+		let port = sockets.first()
+			.and_then(|x| x.local_addr().ok())
+			.map(|x| x.port())
+			.unwrap_or(port);
+		if let Ok(s) = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+		{
+			sockets.push(s);
+		}
+		if sockets.is_empty() { None } else { Some(Self { sockets }) }
+	}
+
+	pub fn port(&self) -> u16
+	{
+		self.sockets.first()
+			.and_then(|x| x.local_addr().ok())
+			.map(|x| x.port())
+			.unwrap_or(0)
+	}
+
+	pub fn register(&mut self, reg: &Registry, base: usize)
+	{
+		for (i, s) in self.sockets.iter_mut().enumerate()
+		{
+			let _ = reg.register(s, Token(base + i), Interest::READABLE);
+		}
+	}
+
+	pub fn deregister(&mut self, reg: &Registry)
+	{
+		for s in &mut self.sockets { let _ = reg.deregister(s); }
+	}
+
+	pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize>
+	{
+		let v4 = self.sockets.iter()
+			.find(|x| x.local_addr().is_ok_and(|a| a.is_ipv4()));
+		let v6 = self.sockets.iter()
+			.find(|x| x.local_addr().is_ok_and(|a| a.is_ipv6()));
+		match (addr.ip(), v4, v6)
+		{
+			(IpAddr::V4(_), Some(s), _) => s.send_to(buf, addr),
+			(IpAddr::V4(ip), None, Some(s)) =>
+			{
+				s.send_to(buf, SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()))
+			}
+			(IpAddr::V6(_), _, Some(s)) => s.send_to(buf, addr),
+			_ => Err(std::io::ErrorKind::AddrNotAvailable.into())
+		}
+	}
+
+	pub fn joinDiscovery(&self)
+	{
+		for s in &self.sockets
+		{
+			if s.local_addr().is_ok_and(|a| a.is_ipv6())
+			{
+				if let Err(x) = s.join_multicast_v6(&DISCOVERY_GROUP, 0)
+				{
+					warn!("Cannot join IPv6 discovery group: {x}");
+				}
+			}
+			else if let Err(x) = s.set_broadcast(true)
+			{
+				warn!("Cannot enable broadcast: {x}");
+			}
+		}
+	}
+}
+
+// Reply to a discovery request:
+// version | port | players | maxPlayers | flags | nameLen | name | cpLen | checkpoint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerInfo
+{
+	pub version: u8,
+	pub port: u16,
+	pub players: u8,
+	pub maxPlayers: u8,
+	pub password: bool,
+	pub name: String,
+	pub checkpoint: String
+}
+
+const INFO_PASSWORD: u8 = 1;
+
+impl ServerInfo
+{
+	pub fn toRaw(&self) -> Vec<u8>
+	{
+		let name = short(&self.name);
+		let cp = short(&self.checkpoint);
+		[
+			&[self.version] as &[u8],
+			&self.port.to_be_bytes(),
+			&[
+				self.players,
+				self.maxPlayers,
+				if self.password { INFO_PASSWORD } else { 0 },
+				name.len() as u8
+			],
+			name,
+			&[cp.len() as u8],
+			cp
+		].concat()
+	}
+
+	// Only the game listens for answers.
+	#[allow(dead_code)]
+	pub fn fromRaw(buf: &[u8]) -> Option<Self>
+	{
+		let text = |offset: usize| -> Option<(String, usize)>
+		{
+			let len = *buf.get(offset)? as usize;
+			let raw = buf.get(offset + 1..offset + 1 + len)?;
+			Some((String::from_utf8_lossy(raw).to_string(), offset + 1 + len))
+		};
+		if buf.len() < 7 { return None; }
+		let (name, offset) = text(6)?;
+		let (checkpoint, _) = text(offset)?;
+		Some(Self
+		{
+			version: buf[0],
+			port: u16::from_be_bytes([buf[1], buf[2]]),
+			players: buf[3],
+			maxPlayers: buf[4],
+			password: buf[5] & INFO_PASSWORD != 0,
+			name,
+			checkpoint
+		})
+	}
+}
+
+// Cuts a string to fit a one-byte length prefix without splitting a character.
+pub fn short(s: &str) -> &[u8]
+{
+	let mut end = s.len().min(u8::MAX as usize);
+	while !s.is_char_boundary(end) { end -= 1; }
+	&s.as_bytes()[..end]
+}
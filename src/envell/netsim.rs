@@ -0,0 +1,144 @@
+use std::{hash::{BuildHasher, Hasher}, time::{Duration, Instant}};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Conditions
+{
+	pub enabled: bool,
+	pub latency: u16,
+	pub jitter: u16,
+	pub loss: u8,
+	pub duplicate: u8,
+	pub reorder: u8
+}
+
+impl Conditions
+{
+	// The game reads its conditions from game.json, the server from its settings.
+	#[allow(dead_code)]
+	pub fn parse(data: &json::JsonValue) -> Self
+	{
+		Self
+		{
+			enabled: data["enabled"].as_bool().unwrap_or(false),
+			latency: data["latency"].as_u16().unwrap_or(0),
+			jitter: data["jitter"].as_u16().unwrap_or(0),
+			loss: data["loss"].as_u8().unwrap_or(0).min(100),
+			duplicate: data["duplicate"].as_u8().unwrap_or(0).min(100),
+			reorder: data["reorder"].as_u8().unwrap_or(0).min(100)
+		}
+	}
+}
+
+// Holds outgoing data back to imitate a bad network.
+// Reliable streams (TCP) are only delayed and keep their order,
+// since dropping or reordering bytes there would break the framing.
+#[derive(Debug)]
+pub struct Simulator<K>
+{
+	pub cond: Conditions,
+	reliable: bool,
+	queue: Vec<(Instant, K, Vec<u8>)>,
+	last: Instant,
+	rng: u64
+}
+
+impl<K> Simulator<K>
+{
+	pub fn new(reliable: bool) -> Self
+	{
+		let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+		h.write_u8(reliable as u8);
+		Self
+		{
+			cond: Conditions::default(),
+			reliable,
+			queue: vec![],
+			last: Instant::now(),
+			rng: h.finish() | 1
+		}
+	}
+
+	pub fn push(&mut self, to: K, data: Vec<u8>) where K: Clone
+	{
+		let now = Instant::now();
+		if !self.cond.enabled
+		{
+			// Whatever is still delayed from before goes out first.
+			let due = if self.reliable { now.max(self.last) } else { now };
+			self.last = due;
+			self.queue.push((due, to, data));
+			return;
+		}
+		if !self.reliable && self.chance(self.cond.loss) { return; }
+
+		let jitter = self.cond.jitter as f32 * (self.random() * 2.0 - 1.0);
+		let mut delay = (self.cond.latency as f32 + jitter).max(0.0);
+		if !self.reliable && self.chance(self.cond.reorder)
+		{
+			delay += (self.cond.jitter as f32 * 2.0).max(30.0);
+		}
+		let mut due = now + Duration::from_secs_f32(delay * 0.001);
+		if self.reliable
+		{
+			due = due.max(self.last);
+			self.last = due;
+		}
+		if !self.reliable && self.chance(self.cond.duplicate)
+		{
+			self.queue.push((due + Duration::from_millis(1), to.clone(), data.clone()));
+		}
+		self.queue.push((due, to, data));
+	}
+
+	pub fn due(&mut self) -> Vec<(K, Vec<u8>)>
+	{
+		if self.queue.is_empty() { return vec![]; }
+		let now = Instant::now();
+		let (mut ready, rest): (Vec<_>, Vec<_>) =
+			self.queue.drain(..).partition(|(t, _, _)| *t <= now);
+		self.queue = rest;
+		ready.sort_by_key(|(t, _, _)| *t);
+		ready.into_iter().map(|(_, k, d)| (k, d)).collect()
+	}
+
+	// Only the game throws away what it queued for a lost connection.
+	#[allow(dead_code)]
+	pub fn clear(&mut self)
+	{
+		self.queue.clear();
+		self.last = Instant::now();
+	}
+
+	fn random(&mut self) -> f32
+	{
+		self.rng ^= self.rng << 13;
+		self.rng ^= self.rng >> 7;
+		self.rng ^= self.rng << 17;
+		(self.rng >> 40) as f32 / (1u64 << 24) as f32
+	}
+
+	fn chance(&mut self, percent: u8) -> bool
+	{
+		percent > 0 && self.random() * 100.0 < percent as f32
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn disabledKeepsOrder()
+	{
+		let mut sim = Simulator::<u8>::new(true);
+		sim.cond = Conditions { enabled: true, latency: 50, ..Default::default() };
+		sim.push(0, vec![1]);
+		sim.cond.enabled = false;
+		sim.push(0, vec![2]);
+		assert!(sim.due().is_empty());
+		std::thread::sleep(Duration::from_millis(60));
+		let out: Vec<_> = sim.due().into_iter().map(|(_, d)| d[0]).collect();
+		assert_eq!(out, vec![1, 2]);
+	}
+}
@@ -0,0 +1,139 @@
+use std::sync::OnceLock;
+
+use crate::envell::log::Filter;
+
+const USAGE: &str = "Usage: envell [options]
+
+Options:
+  --config <path>    configuration file    (ENVELL_CONFIG, res/system/cfg.json)
+  --saves <dir>      save slots directory  (ENVELL_SAVES, res/system/saves)
+  --web-root <dir>   web panel files       (ENVELL_WEB_ROOT, res/web)
+  --web-port <port>  web panel port        (ENVELL_WEB_PORT, 8080)
+  --port <port>      game port, overrides the configuration (ENVELL_PORT)
+  --log <filter>     error, warn, info or debug, per target as info,player=debug (ENVELL_LOG, info)
+  --log-file <path>  log file, rotated at 1 MiB (ENVELL_LOG_FILE, <config dir>/logs/envell.log)
+  --help             show this message";
+
+// Where the server keeps its files and which ports it takes.
+// Command-line arguments win over environment variables, which win over defaults.
+#[derive(Clone, Debug)]
+pub struct Options
+{
+	pub config: String,
+	pub saves: String,
+	pub webRoot: String,
+	pub webPort: u16,
+	pub port: Option<u16>,
+	pub logFilter: Filter,
+	// Empty for the default next to the configuration.
+	pub logFile: String
+}
+
+impl Default for Options
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			config: String::from("res/system/cfg.json"),
+			saves: String::from("res/system/saves"),
+			webRoot: String::from("res/web"),
+			webPort: 8080,
+			port: None,
+			logFilter: Filter::default(),
+			logFile: String::new()
+		}
+	}
+}
+
+impl Options
+{
+	// Files that live next to the configuration, like items.json.
+	pub fn system(&self, file: &str) -> String
+	{
+		match std::path::Path::new(&self.config).parent()
+		{
+			Some(dir) if !dir.as_os_str().is_empty() => dir.join(file).to_string_lossy().to_string(),
+			_ => file.to_string()
+		}
+	}
+
+	// The log lives next to the configuration too, unless --log-file says otherwise.
+	pub fn logPath(&self) -> String
+	{
+		if self.logFile.is_empty() { self.system("logs/envell.log") } else { self.logFile.clone() }
+	}
+
+	fn set(&mut self, key: &str, value: &str) -> Result<(), String>
+	{
+		let port = || value.parse::<u16>().map_err(|_| format!("{key}: '{value}' is not a port"));
+		match key
+		{
+			"config" => self.config = value.to_string(),
+			"saves" => self.saves = value.trim_end_matches('/').to_string(),
+			"web-root" => self.webRoot = value.trim_end_matches('/').to_string(),
+			"web-port" => self.webPort = port()?,
+			"port" => self.port = Some(port()?),
+			"log" => self.logFilter = Filter::parse(value)
+				.ok_or(format!("log: unknown level in '{value}'"))?,
+			"log-file" => self.logFile = value.to_string(),
+			_ => return Err(format!("unknown option '--{key}'"))
+		}
+		Ok(())
+	}
+
+	pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String>
+	{
+		let mut o = Self::default();
+		for (var, key) in [
+			("ENVELL_CONFIG", "config"),
+			("ENVELL_SAVES", "saves"),
+			("ENVELL_WEB_ROOT", "web-root"),
+			("ENVELL_WEB_PORT", "web-port"),
+			("ENVELL_PORT", "port"),
+			("ENVELL_LOG", "log"),
+			("ENVELL_LOG_FILE", "log-file")
+		]
+		{
+			if let Ok(value) = std::env::var(var)
+			{
+				o.set(key, &value).map_err(|x| format!("{var}: {x}"))?;
+			}
+		}
+
+		while let Some(arg) = args.next()
+		{
+			if arg == "--help" || arg == "-h" { return Err(String::new()); }
+			let Some(key) = arg.strip_prefix("--") else
+			{
+				return Err(format!("unexpected argument '{arg}'"));
+			};
+			let (key, value) = match key.split_once('=')
+			{
+				Some((k, v)) => (k.to_string(), v.to_string()),
+				None => (key.to_string(), args.next().ok_or(format!("--{key} needs a value"))?)
+			};
+			o.set(&key, &value)?;
+		}
+		Ok(o)
+	}
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+// Only the standalone server reads its command line, exits on bad arguments or --help.
+// A server launched from the game runs with the defaults.
+pub fn fromArgs() -> Options
+{
+	match Options::parse(std::env::args().skip(1))
+	{
+		Ok(o) => o,
+		Err(x) if x.is_empty() => { println!("{USAGE}"); std::process::exit(0); }
+		Err(x) => { println!("{x}\n\n{USAGE}"); std::process::exit(2); }
+	}
+}
+
+// The first call wins, like with the log.
+pub fn init(o: Options) { let _ = OPTIONS.set(o); }
+
+pub fn get() -> &'static Options { OPTIONS.get_or_init(Options::default) }
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::envell::movement::Input;
+use crate::warn;
+
+pub const VERSION: u8 = 2;
+pub const HISTORY: usize = 64;
+
+const HAS_BASE: u8 = 0b01;
+const HAS_ACK: u8 = 0b10;
+const HAS_INPUT: u8 = 0b100;
+
+const POS_X: u16 = 1 << 0;
+const POS_Y: u16 = 1 << 1;
+const POS_Z: u16 = 1 << 2;
+const YAW: u16 = 1 << 3;
+const PITCH: u16 = 1 << 4;
+const ROLL: u16 = 1 << 5;
+const VEL_X: u16 = 1 << 6;
+const VEL_Y: u16 = 1 << 7;
+const VEL_Z: u16 = 1 << 8;
+const ANIM: u16 = 1 << 9;
+const FLAGS: u16 = 1 << 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct State
+{
+	pub pos: glam::Vec3,
+	pub angle: glam::Vec3,
+	pub vel: glam::Vec3,
+	pub anim: u8,
+	pub flags: u16
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantization
+{
+	pub position: u16,
+	pub velocity: u16
+}
+
+impl Default for Quantization
+{
+	fn default() -> Self
+	{
+		Self { position: 100, velocity: 100 }
+	}
+}
+
+// Deltas are computed on quantized values,
+// so both sides always agree on the baseline bit for bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Frame
+{
+	pos: [i32; 3],
+	angle: [u16; 3],
+	vel: [i16; 3],
+	anim: u8,
+	flags: u16
+}
+
+impl Frame
+{
+	pub fn quantize(s: &State, q: &Quantization) -> Self
+	{
+		let p = q.position.max(1) as f32;
+		let v = q.velocity.max(1) as f32;
+		let angle = |x: f32|
+		{
+			(x.rem_euclid(360.0) / 360.0 * 65536.0).round() as u32 as u16
+		};
+		Self
+		{
+			pos: [
+				(s.pos.x * p).round() as i32,
+				(s.pos.y * p).round() as i32,
+				(s.pos.z * p).round() as i32
+			],
+			angle: [angle(s.angle.x), angle(s.angle.y), angle(s.angle.z)],
+			vel: [
+				(s.vel.x * v).round() as i16,
+				(s.vel.y * v).round() as i16,
+				(s.vel.z * v).round() as i16
+			],
+			anim: s.anim,
+			flags: s.flags
+		}
+	}
+
+	pub fn state(&self, q: &Quantization) -> State
+	{
+		let p = q.position.max(1) as f32;
+		let v = q.velocity.max(1) as f32;
+		let angle = |x: u16| (x as i16) as f32 / 65536.0 * 360.0;
+		State
+		{
+			pos: glam::vec3(
+				self.pos[0] as f32 / p,
+				self.pos[1] as f32 / p,
+				self.pos[2] as f32 / p
+			),
+			angle: glam::vec3(
+				angle(self.angle[0]),
+				angle(self.angle[1]),
+				angle(self.angle[2])
+			),
+			vel: glam::vec3(
+				self.vel[0] as f32 / v,
+				self.vel[1] as f32 / v,
+				self.vel[2] as f32 / v
+			),
+			anim: self.anim,
+			flags: self.flags
+		}
+	}
+
+	fn write(&self, base: &Frame, out: &mut Vec<u8>)
+	{
+		let mut mask = 0u16;
+		let mut data = vec![];
+		for i in 0..3
+		{
+			if self.pos[i] != base.pos[i]
+			{
+				mask |= POS_X << i;
+				data.extend_from_slice(&self.pos[i].to_be_bytes());
+			}
+		}
+		for i in 0..3
+		{
+			if self.angle[i] != base.angle[i]
+			{
+				mask |= YAW << i;
+				data.extend_from_slice(&self.angle[i].to_be_bytes());
+			}
+		}
+		for i in 0..3
+		{
+			if self.vel[i] != base.vel[i]
+			{
+				mask |= VEL_X << i;
+				data.extend_from_slice(&self.vel[i].to_be_bytes());
+			}
+		}
+		if self.anim != base.anim
+		{
+			mask |= ANIM;
+			data.push(self.anim);
+		}
+		if self.flags != base.flags
+		{
+			mask |= FLAGS;
+			data.extend_from_slice(&self.flags.to_be_bytes());
+		}
+		out.extend_from_slice(&mask.to_be_bytes());
+		out.append(&mut data);
+	}
+
+	fn read(base: &Frame, r: &mut Reader) -> Option<Self>
+	{
+		let mut f = *base;
+		let mask = r.u16()?;
+		for (i, bit) in [POS_X, POS_Y, POS_Z].into_iter().enumerate()
+		{
+			if mask & bit != 0 { f.pos[i] = r.u32()? as i32; }
+		}
+		for (i, bit) in [YAW, PITCH, ROLL].into_iter().enumerate()
+		{
+			if mask & bit != 0 { f.angle[i] = r.u16()?; }
+		}
+		for (i, bit) in [VEL_X, VEL_Y, VEL_Z].into_iter().enumerate()
+		{
+			if mask & bit != 0 { f.vel[i] = r.u16()? as i16; }
+		}
+		if mask & ANIM != 0 { f.anim = r.u8()?; }
+		if mask & FLAGS != 0 { f.flags = r.u16()?; }
+		Some(f)
+	}
+}
+
+pub type Snapshot = HashMap<u8, Frame>;
+
+#[derive(Debug, Default)]
+pub struct History
+{
+	list: VecDeque<(u16, Snapshot)>
+}
+
+impl History
+{
+	pub fn push(&mut self, seq: u16, s: Snapshot)
+	{
+		self.list.push_back((seq, s));
+		while self.list.len() > HISTORY { self.list.pop_front(); }
+	}
+
+	pub fn get(&self, seq: u16) -> Option<&Snapshot>
+	{
+		self.list.iter().find(|(s, _)| *s == seq).map(|(_, x)| x)
+	}
+
+	// Only the game clears its history, when it reconnects.
+	#[allow(dead_code)]
+	pub fn clear(&mut self) { self.list.clear(); }
+}
+
+// version | sender | seq | flags | base? | ack? | lastInput? |
+// count | (id | mask | fields)* | count | input*
+#[derive(Debug, Default)]
+pub struct Packet
+{
+	pub sender: u8,
+	pub seq: u16,
+	pub base: Option<u16>,
+	pub ack: Option<u16>,
+	pub lastInput: Option<u16>,
+	pub entries: Snapshot,
+	pub inputs: Vec<Input>
+}
+
+impl Packet
+{
+	pub fn encode(&self, baseline: Option<&Snapshot>) -> Vec<u8>
+	{
+		let base = if baseline.is_some() { self.base } else { None };
+		let mut out = vec![VERSION, self.sender];
+		out.extend_from_slice(&self.seq.to_be_bytes());
+		out.push(
+			if base.is_some() { HAS_BASE } else { 0 } |
+			if self.ack.is_some() { HAS_ACK } else { 0 } |
+			if self.lastInput.is_some() { HAS_INPUT } else { 0 }
+		);
+		if let Some(b) = base { out.extend_from_slice(&b.to_be_bytes()); }
+		if let Some(a) = self.ack { out.extend_from_slice(&a.to_be_bytes()); }
+		if let Some(i) = self.lastInput { out.extend_from_slice(&i.to_be_bytes()); }
+		out.push(self.entries.len().min(u8::MAX as usize) as u8);
+		let zero = Frame::default();
+		for (id, f) in self.entries.iter().take(u8::MAX as usize)
+		{
+			out.push(*id);
+			let b = baseline.and_then(|x| x.get(id)).unwrap_or(&zero);
+			f.write(b, &mut out);
+		}
+		out.push(self.inputs.len().min(u8::MAX as usize) as u8);
+		for i in self.inputs.iter().take(u8::MAX as usize)
+		{
+			out.extend_from_slice(&i.toRaw());
+		}
+		out
+	}
+
+	pub fn header(buf: &[u8]) -> Option<Self>
+	{
+		let mut r = Reader::new(buf);
+		Self::readHeader(&mut r)
+	}
+
+	pub fn decode(buf: &[u8], baseline: Option<&Snapshot>) -> Option<Self>
+	{
+		let mut r = Reader::new(buf);
+		let mut p = Self::readHeader(&mut r)?;
+		if p.base.is_some() && baseline.is_none() { return None; }
+		let zero = Frame::default();
+		for _ in 0..r.u8()?
+		{
+			let id = r.u8()?;
+			let b = baseline.and_then(|x| x.get(&id)).unwrap_or(&zero);
+			p.entries.insert(id, Frame::read(b, &mut r)?);
+		}
+		for _ in 0..r.u8()?
+		{
+			p.inputs.push(Input::fromRaw(&r.take::<{ Input::SIZE }>()?)?);
+		}
+		Some(p)
+	}
+
+	fn readHeader(r: &mut Reader) -> Option<Self>
+	{
+		let version = r.u8()?;
+		if version != VERSION
+		{
+			warn!("State packet version {version} is not supported.");
+			return None;
+		}
+		let sender = r.u8()?;
+		let seq = r.u16()?;
+		let flags = r.u8()?;
+		let base = if flags & HAS_BASE != 0 { Some(r.u16()?) } else { None };
+		let ack = if flags & HAS_ACK != 0 { Some(r.u16()?) } else { None };
+		let lastInput = if flags & HAS_INPUT != 0 { Some(r.u16()?) } else { None };
+		Some(Self
+		{
+			sender, seq, base, ack, lastInput,
+			entries: Snapshot::new(),
+			inputs: vec![]
+		})
+	}
+}
+
+pub fn newer(a: u16, b: u16) -> bool
+{
+	a != b && a.wrapping_sub(b) < 0x8000
+}
+
+struct Reader<'a>
+{
+	buf: &'a [u8],
+	offset: usize
+}
+
+impl<'a> Reader<'a>
+{
+	fn new(buf: &'a [u8]) -> Self { Self { buf, offset: 0 } }
+
+	fn take<const N: usize>(&mut self) -> Option<[u8; N]>
+	{
+		let x = self.buf.get(self.offset..self.offset + N)?;
+		self.offset += N;
+		x.try_into().ok()
+	}
+
+	fn u8(&mut self) -> Option<u8> { Some(self.take::<1>()?[0]) }
+	fn u16(&mut self) -> Option<u16> { Some(u16::from_be_bytes(self.take()?)) }
+	fn u32(&mut self) -> Option<u32> { Some(u32::from_be_bytes(self.take()?)) }
+}
@@ -1,41 +1,159 @@
 
-use std::{collections::HashMap, io::{Read, Write}, time::{Duration, Instant}};
+use std::{collections::{HashMap, HashSet, VecDeque}, hash::{BuildHasher, Hasher}, io::{Read, Write}, net::SocketAddr, time::{Duration, Instant}};
 
-use mio::{Events, Interest, Poll, Token, net::{TcpListener, TcpStream, UdpSocket}};
+use mio::{Events, Interest, Poll, Registry, Token, net::{TcpListener, TcpStream}};
 
-use crate::envell::{config::Config, message::{self, ToClient}};
+use crate::envell::{chat, config::{self, Config}, inventory::Slot, message::{self, ToClient}, movement::{self, Input}, net::{self, DualUdp}, netsim::{Conditions, Simulator}, packet::{self, Frame, History, Packet, Snapshot, State}, script, state, stats::Stats};
+use crate::{error, warn, info, debug};
+
+const MAX_INPUTS_PER_TICK: usize = 3;
+const MAX_PENDING_INPUTS: usize = 32;
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+const LISTENER: usize = 256;
+const UDP: usize = 258;
+const BROADCAST: usize = 260;
 
 struct Player
 {
 	tcp: TcpStream,
 	ip: String,
 	udpPort: u16,
-	state: [u8; 9] // TODO rewrite
+	state: Frame,
+	sim: State,
+	inputs: VecDeque<Input>,
+	lastInput: Option<u16>,
+	received: History,
+	lastReceived: Option<u16>,
+	snapshots: History,
+	snapshotSeq: u16,
+	acked: Option<u16>,
+	token: u64,
+	lastSeen: Instant,
+	stats: Stats,
+	outbox: Simulator<()>,
+	// Start of a message whose rest has not arrived yet.
+	inbox: Vec<u8>
 }
 
 impl Player
 {
+	fn new(tcp: TcpStream, ip: String, cond: Conditions) -> Self
+	{
+		let mut outbox = Simulator::new(true);
+		outbox.cond = cond;
+		Self
+		{
+			tcp,
+			ip,
+			udpPort: 0,
+			state: Frame::default(),
+			sim: State::default(),
+			inputs: VecDeque::new(),
+			lastInput: None,
+			received: History::default(),
+			lastReceived: None,
+			snapshots: History::default(),
+			snapshotSeq: 0,
+			acked: None,
+			token: newToken(),
+			lastSeen: Instant::now(),
+			stats: Stats::default(),
+			outbox,
+			inbox: vec![]
+		}
+	}
+
 	pub fn send(&mut self, msg: ToClient)
 	{
-		let _ = self.tcp.write_all(&msg.toRaw());
+		let raw = msg.toRaw();
+		self.stats.sent(raw.len());
+		self.outbox.push((), raw);
+		self.flush();
+	}
+
+	fn flush(&mut self)
+	{
+		for (_, raw) in self.outbox.due()
+		{
+			let _ = self.tcp.write_all(&raw);
+		}
+	}
+
+	fn udpAddr(&self) -> SocketAddr
+	{
+		SocketAddr::new(self.ip.parse().unwrap(), self.udpPort)
 	}
 }
 
 type Party = HashMap<u8, Player>;
 
+// Slot kept for a player who lost connection, until they resume or time out.
+struct Detached
+{
+	id: u8,
+	ip: String,
+	since: Instant,
+	state: Frame,
+	sim: State
+}
+
+type Sessions = HashMap<u64, Detached>;
+
+// A player in the game or waiting to reconnect, as the web panel lists them.
+// Name, class and mute are filled in by the main thread.
+#[derive(Clone, Debug, Default)]
+pub struct Info
+{
+	pub id: u8,
+	pub ip: String,
+	pub name: String,
+	pub class: String,
+	pub pos: glam::Vec3,
+	pub stats: Stats,
+	pub detached: bool,
+	pub muted: bool
+}
+
+// What an admin can do to a player.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action
+{
+	Kick,
+	Ban,
+	Mute(bool),
+	Teleport
+}
+
 #[derive(Debug)]
 pub enum Req
 {
 	UnlockSettings(bool),
 	ShowModal(usize, String),
-	SetVisible(bool)
+	SetVisible(bool),
+	Players(Vec<Info>),
+	Change(state::Change),
+	Inventory(String),
+	// A player is set up and may get the recent chat.
+	Joined(u8),
+	Chat(u8, String, String)
 }
 
 #[derive(Debug)]
 pub enum Resp
 {
-	UpdateConfig(usize, Config),
-	SetVisible(usize, bool)
+	UpdateConfig(Config),
+	SetVisible(usize, bool),
+	SetConditions(Conditions),
+	SetCheckpoint(String),
+	Inventory(String, Vec<Slot>),
+	Kick(u8),
+	Bans(HashSet<String>),
+	Teleport(u8),
+	Chat(chat::Entry),
+	ChatHistory(u8, Vec<chat::Entry>),
+	// Players are told the server is gone, then the thread ends and its sockets close.
+	Shutdown
 }
 
 pub type Request = (u8, Req);
@@ -49,84 +167,140 @@ pub fn main(
 	let mut config = Config::default();
 	while let Ok((_, resp)) = fromMain.recv()
 	{
-		match resp
-		{
-			Resp::UpdateConfig(_, cfg) => { config = cfg; break }
-			_ => {}
-		}
+		if let Resp::UpdateConfig(cfg) = resp { config = cfg; break; }
 	}
-	println!("Session has acquired config.");
+	info!("Session has acquired config.");
 
 	let mut poll = Poll::new().expect("Failed to create socket selector");
 	let mut events = Events::with_capacity(64);
 	let mut players = Party::new();
+	let mut detached = Sessions::new();
+	let mut heartbeat = Instant::now();
+	let mut occupied = false;
+	let mut checkpoint = config.firstCP.clone();
+	let mut bans = HashSet::<String>::new();
+	// Settings that wait for the players to leave.
+	let mut pending: Option<Config> = None;
+	let mut udpOutbox = Simulator::<SocketAddr>::new(false);
+	udpOutbox.cond = config.conditions();
 
-	let mut listener;
-	if let Ok(tcp) = TcpListener::bind(
-		format!("0.0.0.0:{}", config.port).parse().unwrap()
-	) { listener = tcp; }
-	else if let Ok(tcp) = TcpListener::bind(
-		"0.0.0.0:0".parse().unwrap()
-	)
-	{ listener = tcp; config.port = listener.local_addr().unwrap().port(); }
-	else { panic!("Failed to create TCP listener at any port."); }
-
-	let mut udp = UdpSocket::bind(
-		"0.0.0.0:0".parse().unwrap()
-	).expect("Failed to create UDP socket");
-
-	let mut broadcast: Option<(UdpSocket, Instant)> = None;
-
-	println!("TCP: {} | UDP: {}",
-		listener.local_addr().unwrap(),
-		udp.local_addr().unwrap()
-	);
+	let mut listeners = net::bindTcp(config.port);
+	if listeners.is_empty()
+	{
+		listeners = net::bindTcp(0);
+		if let Some(l) = listeners.first()
+		{
+			config.port = l.local_addr().unwrap().port();
+		}
+	}
+	if listeners.is_empty() { panic!("Failed to create TCP listener at any port."); }
 
-	let _ = poll.registry().register(
-		&mut listener, Token(config.playersCount as usize),
-		Interest::READABLE
-	);
+	let mut udp = DualUdp::bind(0).expect("Failed to create UDP socket");
 
-	let _ = poll.registry().register(
-		&mut udp, Token(config.playersCount as usize + 1),
-		Interest::READABLE
-	);
+	let mut broadcast: Option<(DualUdp, Instant)> = None;
+
+	for l in &listeners { info!("TCP: {}", l.local_addr().unwrap()); }
+	for s in &udp.sockets { info!("UDP: {}", s.local_addr().unwrap()); }
+
+	for (i, l) in listeners.iter_mut().enumerate()
+	{
+		let _ = poll.registry().register(
+			l, Token(LISTENER + i),
+			Interest::READABLE
+		);
+	}
+
+	udp.register(poll.registry(), UDP);
 
-	let tickTime = Duration::from_secs_f32(1.0 / config.tickRate as f32);
 	let mut tickTimer = Instant::now();
 
+	let mut world = loadWorld(&config);
+	let mut sysTimer = Instant::now();
+
 	loop
 	{
 		while let Ok((id, resp)) = fromMain.try_recv()
 		{
 			match resp
 			{
-				Resp::UpdateConfig(web, cfg) =>
+				Resp::UpdateConfig(cfg) =>
 				{
-					if !players.is_empty()
+					// The newest settings replace whatever was waiting before them.
+					let now = if players.is_empty() && detached.is_empty() { pending = None; cfg }
+					else
 					{
-						let _ = toMain.send((
-							id,
-							Req::ShowModal(web, String::from("saveSettings-fail"))
-						));
-						continue;
+						let (now, waiting) = config::live(&config, &cfg);
+						pending = if waiting.is_empty() { None } else { Some(cfg) };
+						now
+					};
+					reconfigure(&mut config, now, &mut listeners, poll.registry(), &mut players, &mut world);
+					udpOutbox.cond = config.conditions();
+				}
+				Resp::SetCheckpoint(cp) => checkpoint = cp,
+				Resp::Inventory(ip, slots) =>
+				{
+					for p in players.values_mut().filter(|p| p.ip == ip && p.udpPort != 0)
+					{
+						p.send(ToClient::Inventory(slots.clone()));
+					}
+				}
+				Resp::Kick(id) =>
+				{
+					if kick(id, message::REJECT_KICKED, &mut players, &mut detached, poll.registry())
+					{
+						info!("Player #{id} has been kicked.");
 					}
-					println!("Player session: Config updated.");
-					config = cfg;
-
-					let _ = poll.registry().reregister(
-						&mut listener, Token(config.playersCount as usize),
-						Interest::READABLE
-					);
-					let _ = poll.registry().reregister(
-						&mut udp, Token(config.playersCount as usize + 1),
-						Interest::READABLE
-					);
-
-					let _ = toMain.send((
-						id,
-						Req::ShowModal(web, String::from("saveSettings-success"))
-					));
+				}
+				Resp::Bans(list) =>
+				{
+					bans = list;
+					let banned: Vec<u8> = players.iter()
+						.map(|(id, p)| (*id, &p.ip))
+						.chain(detached.values().map(|d| (d.id, &d.ip)))
+						.filter(|(_, ip)| bans.contains(*ip))
+						.map(|(id, _)| id)
+						.collect();
+					for id in banned
+					{
+						info!("Player #{id} is banned.");
+						kick(id, message::REJECT_BANNED, &mut players, &mut detached, poll.registry());
+					}
+				}
+				Resp::Teleport(id) =>
+				{
+					let Some(p) = players.get_mut(&id) else { continue; };
+					let cp = if checkpoint.is_empty() { config.firstCP.clone() } else { checkpoint.clone() };
+					info!("Player #{id} is sent to checkpoint '{cp}'.");
+					p.sim = State::default();
+					p.state = Frame::default();
+					p.inputs.clear();
+					p.send(ToClient::Teleport(cp));
+				}
+				Resp::Chat(e) =>
+				{
+					for p in players.values_mut().filter(|p| p.udpPort != 0)
+					{
+						p.send(ToClient::Chat(e.time.clone(), e.user.clone(), e.msg.clone()));
+					}
+				}
+				Resp::ChatHistory(id, list) =>
+				{
+					let Some(p) = players.get_mut(&id) else { continue; };
+					for e in list { p.send(ToClient::Chat(e.time, e.user, e.msg)); }
+				}
+				Resp::Shutdown =>
+				{
+					let ids: Vec<u8> = players.keys().copied().collect();
+					for id in ids { reject(id, message::REJECT_SHUTDOWN, &mut players, poll.registry()); }
+					if let Some((s, _)) = broadcast.as_mut() { s.deregister(poll.registry()); }
+					info!("Player session is closed.");
+					return;
+				}
+				Resp::SetConditions(cond) =>
+				{
+					info!("Network simulation: {cond:?}");
+					udpOutbox.cond = cond;
+					for p in players.values_mut() { p.outbox.cond = cond; }
 				}
 				Resp::SetVisible(web, active) =>
 				{
@@ -134,9 +308,9 @@ pub fn main(
 					{
 						if let Some((s, _)) = broadcast.as_mut()
 						{
-							let _ = poll.registry().deregister(s);
+							s.deregister(poll.registry());
 							broadcast = None;
-							println!("Broadcast shut down.");
+							info!("Broadcast shut down.");
 							let _ = toMain.send((
 								id, Req::ShowModal(web, String::from("setInvisible-success"))
 							));
@@ -144,7 +318,7 @@ pub fn main(
 						}
 						else
 						{
-							println!("Broadcast is already off.");
+							info!("Broadcast is already off.");
 							let _ = toMain.send((
 								id, Req::ShowModal(web, String::from("setInvisible-fail"))
 							));
@@ -153,203 +327,604 @@ pub fn main(
 					}
 					if broadcast.is_some()
 					{
-						println!("Broadcast is already active.");
+						info!("Broadcast is already active.");
 						let _ = toMain.send((
 							id, Req::ShowModal(web, String::from("setVisible-repeat"))
 						));
 						continue;
 					}
-					if let Ok(mut s) =
-						UdpSocket::bind("0.0.0.0:26225".parse().unwrap())
+					let Some(mut s) = DualUdp::bind(net::DISCOVERY_PORT)
+					else
 					{
-						if let Err(x) = s.set_broadcast(true)
-						{
-							println!("Cannot start broadcast: {x:?}");
-							let _ = toMain.send((
-								id,
-								Req::ShowModal(web, String::from("setVisible-fail"))
-							));
-							continue;
-						}
-						let _ = poll.registry().register(
-							&mut s, Token(config.playersCount as usize + 2),
-							Interest::READABLE
-						);
-						broadcast = Some((s, Instant::now()));
-						println!("Broadcast started.");
+						warn!("Cannot start broadcast: port {} is busy.", net::DISCOVERY_PORT);
 						let _ = toMain.send((
 							id,
-							Req::ShowModal(web, String::from("setVisible-success"))
+							Req::ShowModal(web, String::from("setVisible-fail"))
 						));
-						let _ = toMain.send((0, Req::SetVisible(true)));
+						continue;
+					};
+					s.joinDiscovery();
+					s.register(poll.registry(), BROADCAST);
+					broadcast = Some((s, Instant::now()));
+					info!("Broadcast started.");
+					let _ = toMain.send((
+						id,
+						Req::ShowModal(web, String::from("setVisible-success"))
+					));
+					let _ = toMain.send((0, Req::SetVisible(true)));
+				}
+			}
+		}
+
+		let sysTime = Duration::from_secs_f32(1.0 / config.sysTickRate.max(1) as f32);
+		if let Some(w) = world.as_mut().filter(|_| sysTimer.elapsed() >= sysTime)
+		{
+			let dt = sysTimer.elapsed().as_secs_f32();
+			sysTimer = Instant::now();
+			w.shared().checkpoint = if checkpoint.is_empty() { config.firstCP.clone() }
+				else { checkpoint.clone() };
+			for op in tickWorld(w, dt, &mut players, &detached, &config)
+			{
+				let ip = |id: u8| players.get(&id).map(|p| p.ip.clone());
+				let change = match op
+				{
+					script::Op::Checkpoint(cp) =>
+					{
+						checkpoint = cp.clone();
+						Some(state::Change::Checkpoint(cp))
 					}
+					script::Op::Give(id, item, n) => ip(id).map(|x| state::Change::GiveItem(x, item, n)),
+					script::Op::Take(id, item, n) => ip(id).map(|x| state::Change::TakeItem(x, item, n))
+				};
+				match change
+				{
+					Some(c) => { let _ = toMain.send((0, Req::Change(c))); }
+					None => warn!("World script has changed an unknown player.")
 				}
 			}
 		}
 
+		let tickTime = Duration::from_secs_f32(1.0 / config.tickRate.max(1) as f32);
 		if tickTimer.elapsed() >= tickTime
 		{
-			for (id1, p1) in &players
+			let rules = config.rules();
+			if rules.authoritative
 			{
-				for (id2, p2) in &players
+				let q = config.quantization();
+				for p in players.values_mut()
 				{
-					if *id1 == *id2 { continue; }
-					let _ = udp.send_to(
-						&[&[*id1] as &[u8], &p1.state].concat(),
-						format!("{}:{}", p2.ip, p2.udpPort).parse().unwrap()
-					);
+					for _ in 0..MAX_INPUTS_PER_TICK
+					{
+						let Some(i) = p.inputs.pop_front() else { break; };
+						movement::simulate(&mut p.sim, &i, &rules, tickTime.as_secs_f32());
+						p.sim = Frame::quantize(&p.sim, &q).state(&q);
+						p.lastInput = Some(i.seq);
+					}
+					p.state = Frame::quantize(&p.sim, &q);
 				}
 			}
+			let mut states: Snapshot = players.iter()
+				.map(|(id, p)| (*id, p.state))
+				.collect();
+			if let Some(w) = world.as_ref()
+			{
+				let q = config.quantization();
+				for (id, s) in &w.shared().entities
+				{
+					states.insert(*id, Frame::quantize(s, &q));
+				}
+			}
+			for (id, p) in &mut players
+			{
+				if p.udpPort == 0 { continue; }
+				let mut snapshot = states.clone();
+				if !rules.authoritative { snapshot.remove(id); }
+				p.snapshotSeq = p.snapshotSeq.wrapping_add(1);
+				let msg = Packet
+				{
+					sender: u8::MAX,
+					seq: p.snapshotSeq,
+					base: p.acked,
+					ack: p.lastReceived,
+					lastInput: p.lastInput,
+					entries: snapshot,
+					inputs: vec![]
+				};
+				let raw = msg.encode(p.acked.and_then(|x| p.snapshots.get(x)));
+				p.stats.sent(raw.len());
+				udpOutbox.push(p.udpAddr(), raw);
+				p.snapshots.push(msg.seq, msg.entries);
+			}
 			tickTimer = Instant::now();
 		}
 
-		if let Some((s, t)) = broadcast.as_mut()
+		for (addr, raw) in udpOutbox.due()
+		{
+			let _ = udp.send_to(&raw, addr);
+		}
+		for p in players.values_mut() { p.flush(); }
+
+		if heartbeat.elapsed() >= HEARTBEAT
 		{
-			if t.elapsed().as_secs() > 60
+			let timeout = Duration::from_secs(config.timeout as u64);
+			let mut lost = vec![];
+			for (id, p) in &mut players
+			{
+				let ping = p.stats.ping();
+				p.send(ToClient::Ping(ping));
+				p.stats.updateRates();
+				if p.lastSeen.elapsed() > timeout { lost.push(*id); }
+			}
+			let q = config.quantization();
+			let _ = toMain.send((0, Req::Players(
+				players.iter()
+					.map(|(id, p)| Info
+					{
+						id: *id,
+						ip: p.ip.clone(),
+						pos: p.state.state(&q).pos,
+						stats: p.stats.clone(),
+						..Default::default()
+					})
+					.chain(detached.values().map(|d| Info
+					{
+						id: d.id,
+						ip: d.ip.clone(),
+						pos: d.state.state(&q).pos,
+						detached: true,
+						..Default::default()
+					}))
+					.collect()
+			)));
+			for id in lost
+			{
+				info!("Player #{id} has timed out.");
+				detach(id, &mut players, &mut detached, poll.registry(), &config);
+			}
+
+			let reconnect = Duration::from_secs(config.reconnectTime as u64);
+			let expired: Vec<u64> = detached.iter()
+				.filter(|(_, d)| d.since.elapsed() > reconnect)
+				.map(|(t, _)| *t)
+				.collect();
+			for t in expired
 			{
-				println!("Broadcast time is out.");
-				let _ = poll.registry().deregister(s);
-				let _ = toMain.send((0, Req::SetVisible(false)));
-				broadcast = None;
+				if let Some(d) = detached.remove(&t)
+				{
+					info!("Player #{} did not come back.", d.id);
+					leave(&mut players, d.id);
+				}
 			}
+
+			if occupied && players.is_empty() && detached.is_empty()
+			{
+				occupied = false;
+				let _ = toMain.send((0, Req::UnlockSettings(true)));
+				if let Some(cfg) = pending.take()
+				{
+					info!("Nobody plays, applying the settings that waited.");
+					reconfigure(&mut config, cfg, &mut listeners, poll.registry(), &mut players, &mut world);
+					udpOutbox.cond = config.conditions();
+				}
+			}
+			heartbeat = Instant::now();
 		}
 
-		let _ = poll.poll(&mut events,
-			Some(std::time::Duration::from_millis(20))
-		);
+		if let Some((s, t)) = broadcast.as_mut()
+			&& t.elapsed().as_secs() > 60
+		{
+			info!("Broadcast time is out.");
+			s.deregister(poll.registry());
+			let _ = toMain.send((0, Req::SetVisible(false)));
+			broadcast = None;
+		}
+
+		let mut wait = Duration::from_millis(20);
+		if world.is_some()
+		{
+			wait = wait.min(sysTime.saturating_sub(sysTimer.elapsed()));
+		}
+		let _ = poll.poll(&mut events, Some(wait));
 
 		for e in events.iter()
 		{
-			let token = e.token();
-			let socketID = token.0 as u8;
-			if socketID == config.playersCount
+			let token = e.token().0;
+			if let Some(listener) = token.checked_sub(LISTENER)
+				.and_then(|i| listeners.get_mut(i))
 			{
 				while let Ok((mut tcp, addr)) = listener.accept()
 				{
-					let id = getEmptyID(&players, config.playersCount);
-					println!("New player #{id}: {addr}");
+					let ip = addr.ip().to_canonical().to_string();
+					if bans.contains(&ip)
+					{
+						info!("Rejecting banned {addr}.");
+						let _ = tcp.write(&ToClient::Rejected(message::REJECT_BANNED).toRaw());
+						let _ = tcp.shutdown(std::net::Shutdown::Both);
+						continue;
+					}
+					let id = getEmptyID(&players, &detached, config.playersCount);
+					if id == u8::MAX
+					{
+						info!("Server is full, rejecting {addr}.");
+						let _ = tcp.write(&ToClient::Rejected(message::REJECT_FULL).toRaw());
+						let _ = tcp.shutdown(std::net::Shutdown::Both);
+						continue;
+					}
+					info!("New player #{id}: {addr}");
 					let _ = poll.registry().register(
 						&mut tcp, Token(id as usize),
 						Interest::READABLE
 					);
-					let ip = tcp.peer_addr().unwrap().ip().to_string();
-					if players.len() == 0
+					if !occupied
 					{
-						let _ = toMain.send((socketID, Req::UnlockSettings(false)));
+						occupied = true;
+						let _ = toMain.send((0, Req::UnlockSettings(false)));
 					}
-					players.insert(id, Player
-					{
-						tcp: tcp,
-						ip: ip.clone(),
-						udpPort: 0,
-						state: [0u8; 9]
-					});
+					players.insert(id, Player::new(tcp, ip, udpOutbox.cond));
 				}
 				continue;
 			}
-			if socketID == config.playersCount + 1
+			if let Some(socket) = token.checked_sub(UDP)
+				.and_then(|i| udp.sockets.get(i))
 			{
-				let mut buf = [0u8; 128];
+				let mut buf = [0u8; 1500];
 				'udp: loop
 				{
-					match udp.recv_from(&mut buf)
+					match socket.recv_from(&mut buf)
 					{
-						Ok(_) =>
+						Ok((size, addr)) =>
 						{
-							if let Some(p) = players.get_mut(&buf[0])
+							let Some(header) = Packet::header(&buf[..size])
+							else { continue; };
+							if let Some(p) = players.get_mut(&header.sender)
 							{
-								p.state = [
-									buf[1], buf[2],
-									buf[3], buf[4],
-									buf[5], buf[6],
-									buf[7], buf[8],
-									buf[9]
-								];
+								// Anyone can write another player's id, only their own address counts.
+								if p.udpPort == 0 || net::canonical(addr) != p.udpAddr()
+								{
+									debug!("P{} sent from a foreign address {}", header.sender, net::canonical(addr));
+									continue;
+								}
+								p.lastSeen = Instant::now();
+								p.stats.received(size);
+								p.stats.sequence(header.seq);
+								receiveState(p, &buf[..size], header, &config);
 							}
 							else
 							{
-								println!("P{} not found", buf[0]);
+								debug!("P{} not found", header.sender);
 							}
 						}
 						Err(x) =>
 						{
 							if x.kind() == std::io::ErrorKind::WouldBlock { break 'udp; }
-							println!("Server UDP: {x}");
-							let _ = poll.registry().reregister(
-								&mut udp, Token(config.playersCount as usize + 1),
-								Interest::READABLE
-							);
+							error!("Server UDP: {x}");
 							break 'udp;
 						}
 					}
 				}
 				continue;
 			}
-			if socketID == config.playersCount + 2
+			if let Some(s) = token.checked_sub(BROADCAST)
+				.and_then(|i| broadcast.as_ref()?.0.sockets.get(i))
 			{
-				if let Some((s, _)) = broadcast.as_mut()
+				let mut buf = [0u8; 2];
+				while let Ok((size, addr)) = s.recv_from(&mut buf)
 				{
-					let mut buf = [0u8; 2];
-					while let Ok((size, addr)) = s.recv_from(&mut buf)
+					if size != 0 { continue; }
+					debug!("Found searcher: {}", net::canonical(addr));
+					let info = net::ServerInfo
 					{
-						if size == 0
-						{
-							println!("Found searcher: {addr}");
-							let _ = s.send_to(
-								&config.port.to_be_bytes() as &[u8],
-								addr
-							);
-						}
-					}
-					continue;
+						version: packet::VERSION,
+						port: listeners[0].local_addr().map(|x| x.port()).unwrap_or(config.port),
+						players: (players.len() + detached.len()) as u8,
+						maxPlayers: config.playersCount,
+						password: !config.joinPassword.is_empty(),
+						name: config.name.clone(),
+						checkpoint: if checkpoint.is_empty() { config.firstCP.clone() }
+							else { checkpoint.clone() }
+					};
+					let _ = s.send_to(&info.toRaw(), addr);
 				}
+				continue;
 			}
 
-			let player = players.get_mut(&socketID).unwrap();
+			if token >= LISTENER { continue; }
+			let socketID = token as u8;
+			let Some(player) = players.get_mut(&socketID) else { continue; };
 
 			if e.is_read_closed()
 			{
-				println!("Player #{socketID} has disconnected.");
-				let _ = poll.registry().deregister(&mut player.tcp);
-				players.remove(&socketID);
-				// TODO broadcast disconnection to other players
-				if players.len() == 0
-				{
-					let _ = toMain.send((socketID, Req::UnlockSettings(true)));
-				}
+				info!("Player #{socketID} has disconnected.");
+				detach(socketID, &mut players, &mut detached, poll.registry(), &config);
 				continue;
 			}
 
 			let mut buf = [0u8; 1024];
-			let mut out = vec![];
+			let mut received = 0;
 			while let Ok(size) = player.tcp.read(&mut buf)
 			{
-				out = [out, buf[..size].to_vec()].concat();
+				if size == 0 { break; }
+				player.inbox.extend_from_slice(&buf[..size]);
+				received += size;
+			}
+			if received > 0
+			{
+				player.lastSeen = Instant::now();
+				player.stats.received(received);
 			}
-			for msg in message::ToServer::fromRaw(out)
+			let (messages, used) = message::ToServer::fromRaw(&player.inbox);
+			player.inbox.drain(..used);
+			let mut resume = None;
+			let mut rejected = None;
+			for msg in messages
 			{
 				match msg
 				{
-					message::ToServer::Setup(port) =>
+					message::ToServer::Setup(port, pwd) =>
 					{
+						if !config.joinPassword.is_empty() && pwd != config.joinPassword
+						{
+							warn!("Player #{socketID} has a wrong password.");
+							rejected = Some(message::REJECT_PASSWORD);
+							break;
+						}
 						player.udpPort = port;
 						player.send(ToClient::Setup(
 							config.tickRate,
 							socketID,
-							udp.local_addr().unwrap().port()
+							udp.port(),
+							config.quantization(),
+							config.rules()
 						));
+						player.send(ToClient::Session(player.token, config.timeout));
+						let _ = toMain.send((socketID, Req::Inventory(player.ip.clone())));
+						let _ = toMain.send((socketID, Req::Joined(socketID)));
+					}
+					message::ToServer::MoveItem(from, to) =>
+					{
+						let _ = toMain.send((socketID, Req::Change(
+							state::Change::MoveItem(player.ip.clone(), from, to)
+						)));
+					}
+					message::ToServer::SplitItem(from, to, count) =>
+					{
+						let _ = toMain.send((socketID, Req::Change(
+							state::Change::SplitItem(player.ip.clone(), from, to, count)
+						)));
 					}
+					message::ToServer::Chat(msg) =>
+					{
+						if player.udpPort == 0 { continue; }
+						let _ = toMain.send((socketID, Req::Chat(socketID, player.ip.clone(), msg)));
+					}
+					message::ToServer::Ping(id) => player.send(ToClient::Pong(id)),
+					message::ToServer::Pong(id) => player.stats.pong(id),
+					message::ToServer::Resume(token, port) => { resume = Some((token, port)); }
+				}
+			}
+
+			if rejected.is_none() && !config.joinPassword.is_empty()
+				&& resume.is_some_and(|(x, _)| !detached.contains_key(&x))
+			{
+				warn!("Player #{socketID} has an unknown session and no password.");
+				rejected = Some(message::REJECT_PASSWORD);
+			}
+			if let Some(reason) = rejected
+			{
+				reject(socketID, reason, &mut players, poll.registry());
+				continue;
+			}
+
+			if let Some((token, port)) = resume
+			{
+				let Some(mut p) = players.remove(&socketID) else { continue; };
+				let mut id = socketID;
+				if let Some(d) = detached.remove(&token)
+				{
+					info!("Player #{} has reconnected as #{}.", socketID, d.id);
+					id = d.id;
+					p.token = token;
+					p.state = d.state;
+					p.sim = d.sim;
+					let _ = poll.registry().reregister(
+						&mut p.tcp, Token(id as usize),
+						Interest::READABLE
+					);
 				}
+				else { warn!("Player #{socketID} has an unknown session."); }
+				p.udpPort = port;
+				p.send(ToClient::Setup(
+					config.tickRate,
+					id,
+					udp.port(),
+					config.quantization(),
+					config.rules()
+				));
+				p.send(ToClient::Session(p.token, config.timeout));
+				let _ = toMain.send((id, Req::Inventory(p.ip.clone())));
+				players.insert(id, p);
+			}
+		}
+	}
+}
+
+// Applies settings to the running session. The tick rate reaches players at once,
+// the player limit counts for new connections, a new port gets new listeners.
+fn reconfigure(
+	config: &mut Config, mut cfg: Config,
+	listeners: &mut Vec<TcpListener>, reg: &Registry,
+	players: &mut Party, world: &mut Option<script::World>
+)
+{
+	if cfg.port != config.port
+	{
+		let mut fresh = net::bindTcp(cfg.port);
+		if fresh.is_empty()
+		{
+			warn!("Cannot listen on port {}, staying on {}.", cfg.port, config.port);
+			cfg.port = config.port;
+		}
+		else
+		{
+			for l in listeners.iter_mut() { let _ = reg.deregister(l); }
+			for (i, l) in fresh.iter_mut().enumerate()
+			{
+				let _ = reg.register(l, Token(LISTENER + i), Interest::READABLE);
 			}
+			*listeners = fresh;
+			info!("Players now connect to port {}.", cfg.port);
 		}
 	}
+	if cfg.tickRate != config.tickRate
+	{
+		info!("Tick rate is now {}.", cfg.tickRate);
+		for p in players.values_mut().filter(|p| p.udpPort != 0)
+		{
+			p.send(ToClient::TickRate(cfg.tickRate));
+		}
+	}
+	if (cfg.playersCount as usize) < players.len()
+	{
+		info!("{} players are over the new limit of {}, they stay until they leave.",
+			players.len() - cfg.playersCount as usize, cfg.playersCount);
+	}
+	let reload = cfg.worldScript != config.worldScript;
+	*config = cfg;
+	if reload { *world = loadWorld(config); }
+	info!("Player session: Config updated.");
+}
+
+fn loadWorld(config: &Config) -> Option<script::World>
+{
+	if config.worldScript.is_empty() { return None; }
+	script::World::load(&config.worldScript, config.sysTickRate)
+}
+
+// Players who are set up or waiting to reconnect are in the world.
+fn tickWorld(
+	w: &mut script::World, dt: f32,
+	players: &mut Party, detached: &Sessions, config: &Config
+) -> Vec<script::Op>
+{
+	let q = config.quantization();
+	let present: HashMap<u8, State> = players.iter()
+		.filter(|(_, p)| p.udpPort != 0)
+		.map(|(id, p)| (*id, p.state.state(&q)))
+		.chain(detached.values().map(|d| (d.id, d.state.state(&q))))
+		.collect();
+	let (joined, left): (Vec<u8>, Vec<u8>) =
+	{
+		let mut s = w.shared();
+		let joined = present.keys().filter(|x| !s.players.contains_key(x)).copied().collect();
+		let left = s.players.keys().filter(|x| !present.contains_key(x)).copied().collect();
+		s.players = present;
+		s.tickRate = config.sysTickRate;
+		(joined, left)
+	};
+	for id in joined { w.playerJoined(id); }
+	for id in left { w.playerLeft(id); }
+	w.update(dt);
+	let removed = std::mem::take(&mut w.shared().removed);
+	for id in removed { leave(players, id); }
+	std::mem::take(&mut w.shared().ops)
+}
+
+fn receiveState(p: &mut Player, buf: &[u8], header: Packet, config: &Config)
+{
+	if let Some(last) = p.lastReceived
+		&& !packet::newer(header.seq, last)
+	{
+		return;
+	}
+	let baseline = header.base.and_then(|x| p.received.get(x));
+	let Some(msg) = Packet::decode(buf, baseline) else { return; };
+	if config.rules().authoritative
+	{
+		for i in &msg.inputs
+		{
+			let last = p.inputs.back().map(|x| x.seq).or(p.lastInput);
+			if last.is_none_or(|x| packet::newer(i.seq, x)) { p.inputs.push_back(*i); }
+		}
+		while p.inputs.len() > MAX_PENDING_INPUTS { p.inputs.pop_front(); }
+	}
+	else if let Some(f) = msg.entries.get(&msg.sender)
+	{
+		p.state = *f;
+	}
+	if let Some(ack) = msg.ack
+		&& p.acked.is_none_or(|x| packet::newer(ack, x))
+	{
+		p.acked = Some(ack);
+	}
+	p.lastReceived = Some(msg.seq);
+	p.received.push(msg.seq, msg.entries);
+}
+
+fn detach(id: u8, players: &mut Party, sessions: &mut Sessions, reg: &Registry, config: &Config)
+{
+	let Some(mut p) = players.remove(&id) else { return; };
+	let _ = reg.deregister(&mut p.tcp);
+	let _ = p.tcp.shutdown(std::net::Shutdown::Both);
+	if config.reconnectTime == 0
+	{
+		leave(players, id);
+		return;
+	}
+	sessions.insert(p.token, Detached
+	{
+		id,
+		ip: p.ip,
+		since: Instant::now(),
+		state: p.state,
+		sim: p.sim
+	});
+}
+
+// Drops a player that never joined, without keeping a session for it.
+fn reject(id: u8, reason: u8, players: &mut Party, reg: &Registry)
+{
+	let Some(mut p) = players.remove(&id) else { return; };
+	let _ = p.tcp.write_all(&ToClient::Rejected(reason).toRaw());
+	let _ = reg.deregister(&mut p.tcp);
+	let _ = p.tcp.shutdown(std::net::Shutdown::Both);
+}
+
+// Drops a player for good, whether connected or waiting to reconnect.
+fn kick(id: u8, reason: u8, players: &mut Party, sessions: &mut Sessions, reg: &Registry) -> bool
+{
+	let before = sessions.len();
+	sessions.retain(|_, d| d.id != id);
+	let found = players.contains_key(&id) || sessions.len() != before;
+	reject(id, reason, players, reg);
+	if found { leave(players, id); }
+	found
+}
+
+fn leave(players: &mut Party, id: u8)
+{
+	for p in players.values_mut()
+	{
+		p.send(ToClient::PlayerLeft(id));
+	}
+}
+
+fn newToken() -> u64
+{
+	let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+	h.write_u128(
+		std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_nanos()
+	);
+	h.finish()
 }
 
-fn getEmptyID(party: &Party, count: u8) -> u8
+fn getEmptyID(party: &Party, sessions: &Sessions, count: u8) -> u8
 {
 	for i in 0..count
 	{
-		if !party.contains_key(&i)
+		if !party.contains_key(&i) && !sessions.values().any(|x| x.id == i)
 		{
 			return i;
 		}
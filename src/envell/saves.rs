@@ -0,0 +1,168 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::envell::{options, state};
+use crate::{error, info};
+
+fn dir() -> &'static str { &options::get().saves }
+
+// Before slots there was a single save file next to the configuration.
+fn legacy() -> String { options::get().system("save.json") }
+
+// What the web panel asks to do with the slots.
+#[derive(Clone, Debug)]
+pub enum Action
+{
+	Create(String),
+	Copy(String, String),
+	Rename(String, String),
+	Delete(String),
+	Load(String),
+	Restore(String, u8)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Info
+{
+	pub name: String,
+	pub date: String,
+	pub checkpoint: String,
+	pub backups: Vec<(u8, String, String)>
+}
+
+pub fn path(name: &str) -> String { format!("{}/{name}.json", dir()) }
+
+// Newest backup is 1.
+pub fn backupPath(name: &str, n: u8) -> String
+{
+	format!("{}/backups/{name}.{n}.json", dir())
+}
+
+// Slot names become file names, so only plain characters are allowed.
+pub fn valid(name: &str) -> bool
+{
+	!name.is_empty() && name.chars().count() <= 32 &&
+	name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn exists(name: &str) -> bool { std::path::Path::new(&path(name)).is_file() }
+
+// Moves the old single save into the given slot once.
+pub fn adopt(slot: &str)
+{
+	let _ = std::fs::create_dir_all(format!("{}/backups", dir()));
+	let legacy = legacy();
+	if exists(slot) || !std::path::Path::new(&legacy).is_file() { return; }
+	match std::fs::rename(&legacy, path(slot))
+	{
+		Ok(_) => info!("Save file moved to slot '{slot}'."),
+		Err(x) => error!("Failed to move the old save file: {x}")
+	}
+}
+
+pub fn list() -> Vec<Info>
+{
+	let mut out = vec![];
+	let Ok(dir) = std::fs::read_dir(dir()) else { return out; };
+	for e in dir.flatten()
+	{
+		let file = e.file_name().to_string_lossy().to_string();
+		let Some(name) = file.strip_suffix(".json") else { continue; };
+		if !valid(name) { continue; }
+		let (date, checkpoint) = peek(&path(name));
+		let mut backups = vec![];
+		for n in 1..=u8::MAX
+		{
+			let p = backupPath(name, n);
+			if !std::path::Path::new(&p).is_file() { break; }
+			let (date, checkpoint) = peek(&p);
+			backups.push((n, date, checkpoint));
+		}
+		out.push(Info { name: name.to_string(), date, checkpoint, backups });
+	}
+	out.sort_by(|a, b| a.name.cmp(&b.name));
+	out
+}
+
+pub fn create(name: &str) -> Result<()>
+{
+	check(name, false)?;
+	state::save(&mut state::State::default(), &path(name))
+}
+
+pub fn copy(from: &str, to: &str) -> Result<()>
+{
+	check(from, true)?;
+	check(to, false)?;
+	std::fs::copy(path(from), path(to)).map(|_| ())
+}
+
+pub fn rename(from: &str, to: &str) -> Result<()>
+{
+	check(from, true)?;
+	check(to, false)?;
+	std::fs::rename(path(from), path(to))?;
+	for n in 1..=u8::MAX
+	{
+		if std::fs::rename(backupPath(from, n), backupPath(to, n)).is_err() { break; }
+	}
+	Ok(())
+}
+
+pub fn delete(name: &str) -> Result<()>
+{
+	check(name, true)?;
+	std::fs::remove_file(path(name))?;
+	for n in 1..=u8::MAX
+	{
+		if std::fs::remove_file(backupPath(name, n)).is_err() { break; }
+	}
+	Ok(())
+}
+
+// Puts a backup in place of the slot. The current file becomes backup 1,
+// so restoring can be undone the same way.
+pub fn restore(name: &str, n: u8, keep: u8) -> Result<()>
+{
+	check(name, true)?;
+	let from = backupPath(name, n);
+	let data = std::fs::read(&from)?;
+	rotate(name, keep.max(1));
+	let tmp = format!("{}.tmp", path(name));
+	std::fs::write(&tmp, data)?;
+	std::fs::rename(&tmp, path(name))
+}
+
+// Shifts backups of the slot by one and copies the slot into backup 1.
+// Anything beyond `keep` is dropped.
+pub fn rotate(name: &str, keep: u8)
+{
+	if keep == 0 || !exists(name) { return; }
+	let _ = std::fs::remove_file(backupPath(name, keep));
+	for n in (1..keep).rev()
+	{
+		let _ = std::fs::rename(backupPath(name, n), backupPath(name, n + 1));
+	}
+	if let Err(x) = std::fs::copy(path(name), backupPath(name, 1))
+	{
+		error!("Failed to back up slot '{name}': {x}");
+	}
+	let mut n = keep.saturating_add(1);
+	while std::fs::remove_file(backupPath(name, n)).is_ok() { n = n.saturating_add(1); }
+}
+
+fn check(name: &str, existing: bool) -> Result<()>
+{
+	if !valid(name) { return Err(Error::new(ErrorKind::InvalidInput, "invalid slot name")); }
+	match (existing, exists(name))
+	{
+		(true, false) => Err(ErrorKind::NotFound.into()),
+		(false, true) => Err(ErrorKind::AlreadyExists.into()),
+		_ => Ok(())
+	}
+}
+
+fn peek(path: &str) -> (String, String)
+{
+	let s = state::load(path);
+	(s.date, s.checkpoint)
+}
@@ -0,0 +1,518 @@
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, rc::Rc};
+
+use mlua::{Lua, Table};
+
+use crate::envell::{config::MAX_PLAYERS, log, packet::State};
+use crate::{error, info};
+
+// Server-side world script, ticked by the session at sysTickRate.
+// It sees the players' latest states and drives its own entities,
+// which are sent to clients along with the players.
+//
+// Lua API:
+//   world.name(), world.spawn(id, path, vars), world.kill(id), world.entities()
+//   network.players(), network.tickRate()
+//   network.getState(id), getVelocity(id), getAnimation(id), getFlags(id)
+//   network.setState(id, x, y, z, yaw, pitch, roll?), setVelocity(id, x, y, z),
+//   network.setAnimation(id, anim), setFlags(id, flags), network.remove(id)
+//   save.checkpoint(), save.setCheckpoint(cp),
+//   save.giveItem(player, item, count), save.takeItem(player, item, count)
+//   log.error(msg), warn, info, debug, log.lines(since)
+//   aemath.*
+// Callbacks: Init(), Update(dt), OnPlayerJoined(id), OnPlayerLeft(id);
+// entity scripts get Init(vars) and Update(dt).
+
+// Changes to the saved game, applied by the main thread.
+#[derive(Clone, Debug)]
+pub enum Op
+{
+	Checkpoint(String),
+	Give(u8, String, u8),
+	Take(u8, String, u8)
+}
+
+#[derive(Default)]
+pub struct Shared
+{
+	pub players: HashMap<u8, State>,
+	pub entities: HashMap<u8, State>,
+	pub removed: Vec<u8>,
+	pub ops: Vec<Op>,
+	pub checkpoint: String,
+	pub tickRate: u16,
+	name: String,
+	spawned: Vec<(String, String, json::JsonValue)>,
+	killed: Vec<String>,
+	ents: Vec<String>
+}
+
+type Handle = Rc<RefCell<Shared>>;
+
+pub struct World
+{
+	script: Lua,
+	ents: BTreeMap<String, Lua>,
+	shared: Handle,
+	init: bool
+}
+
+impl World
+{
+	pub fn load(path: &str, tickRate: u16) -> Option<Self>
+	{
+		let src = match std::fs::read_to_string(path)
+		{
+			Ok(x) => x,
+			Err(x) => { error!("Cannot read world script '{path}': {x}"); return None; }
+		};
+		let shared = Rc::new(RefCell::new(Shared
+		{
+			tickRate,
+			name: std::path::Path::new(path).file_stem()
+				.map(|x| x.to_string_lossy().to_string())
+				.unwrap_or_default(),
+			..Default::default()
+		}));
+		let script = Lua::new();
+		bindAll(&script, &shared);
+		let _ = script.globals().raw_set("ScriptID", "world");
+		if let Err(x) = script.load(src).set_name(path).exec()
+		{
+			error!("Failed to load world script:\n{x}");
+			return None;
+		}
+		info!("World script '{path}' is loaded.");
+		Some(Self { script, ents: BTreeMap::new(), shared, init: true })
+	}
+
+	pub fn shared(&self) -> std::cell::RefMut<'_, Shared> { self.shared.borrow_mut() }
+
+	fn start(&mut self)
+	{
+		if !self.init { return; }
+		self.init = false;
+		call(&self.script, "Init", ());
+		self.apply();
+	}
+
+	pub fn update(&mut self, dt: f32)
+	{
+		self.start();
+		call(&self.script, "Update", dt);
+		for ent in self.ents.values() { call(ent, "Update", dt); }
+		self.apply();
+	}
+
+	pub fn playerJoined(&mut self, id: u8)
+	{
+		self.start();
+		call(&self.script, "OnPlayerJoined", id);
+		self.apply();
+	}
+
+	pub fn playerLeft(&mut self, id: u8)
+	{
+		self.start();
+		call(&self.script, "OnPlayerLeft", id);
+		self.apply();
+	}
+
+	// Spawning and killing are deferred, so scripts can do it while entities update.
+	fn apply(&mut self)
+	{
+		let (spawned, killed) =
+		{
+			let mut s = self.shared.borrow_mut();
+			(std::mem::take(&mut s.spawned), std::mem::take(&mut s.killed))
+		};
+		for id in killed { self.ents.remove(&id); }
+		for (id, path, vars) in spawned
+		{
+			let Ok(src) = std::fs::read_to_string(&path)
+			else { error!("Cannot read entity script '{path}'."); continue; };
+			let ent = Lua::new();
+			bindAll(&ent, &self.shared);
+			let _ = ent.globals().raw_set("ScriptID", format!("ent_{id}"));
+			if let Err(x) = ent.load(src).set_name(&path).exec()
+			{
+				error!("Failed to load entity '{id}':\n{x}");
+				continue;
+			}
+			let t = fromJSON(&ent, &vars);
+			self.ents.insert(id.clone(), ent);
+			call(&self.ents[&id], "Init", t);
+		}
+		self.shared.borrow_mut().ents = self.ents.keys().cloned().collect();
+	}
+}
+
+// A script that fails in a callback loses it, so the error is logged once. Shared with the game.
+pub fn call(script: &Lua, func: &str, args: impl mlua::IntoLuaMulti)
+{
+	if let Ok(f) = script.globals().raw_get::<mlua::Function>(func)
+		&& let Err(x) = f.call::<mlua::Value>(args)
+	{
+		error!("Script '{}' failed to call '{func}':\n{x}",
+			script.globals().raw_get::<String>("ScriptID").unwrap_or_default()
+		);
+		let _ = script.globals().raw_remove(func);
+	}
+}
+
+fn func<F, A, R>(s: &Lua, t: &Table, name: &str, f: F)
+where
+	F: Fn(&Lua, A) -> mlua::Result<R>
+		+ mlua::MaybeSend + 'static,
+	A: mlua::FromLuaMulti, R: mlua::IntoLuaMulti
+{
+	let _ = t.raw_set(name, s.create_function(f).unwrap());
+}
+
+fn bindAll(s: &Lua, shared: &Handle)
+{
+	world(s, shared);
+	network(s, shared);
+	save(s, shared);
+	log(s);
+	math(s);
+}
+
+fn world(s: &Lua, shared: &Handle)
+{
+	let t = s.create_table().unwrap();
+
+	let h = shared.clone();
+	func(s, &t, "name", move |_, _: ()| Ok(h.borrow().name.clone()));
+
+	let h = shared.clone();
+	func(s, &t, "spawn", move |_, (id, path, vars): (String, String, Option<Table>)|
+	{
+		let vars = vars.map(|x| toJSON(mlua::Value::Table(x))).unwrap_or(json::object!{});
+		h.borrow_mut().spawned.push((id, path, vars));
+		Ok(())
+	});
+
+	let h = shared.clone();
+	func(s, &t, "kill", move |_, id: String| { h.borrow_mut().killed.push(id); Ok(()) });
+
+	let h = shared.clone();
+	func(s, &t, "entities", move |_, _: ()| Ok(h.borrow().ents.clone()));
+
+	let _ = s.globals().raw_set("world", t);
+}
+
+fn network(s: &Lua, shared: &Handle)
+{
+	let t = s.create_table().unwrap();
+
+	let h = shared.clone();
+	func(s, &t, "players", move |_, _: ()|
+	{
+		let mut ids: Vec<u8> = h.borrow().players.keys().copied().collect();
+		ids.sort();
+		Ok(ids)
+	});
+
+	let h = shared.clone();
+	func(s, &t, "tickRate", move |_, _: ()| Ok(h.borrow().tickRate));
+
+	let h = shared.clone();
+	func(s, &t, "getState", move |_, id: u8|
+	{
+		let x = get(&h, id);
+		Ok((x.pos.x, x.pos.y, x.pos.z, x.angle.x, x.angle.y, x.angle.z))
+	});
+
+	let h = shared.clone();
+	func(s, &t, "getVelocity", move |_, id: u8|
+	{
+		let v = get(&h, id).vel;
+		Ok((v.x, v.y, v.z))
+	});
+
+	let h = shared.clone();
+	func(s, &t, "getAnimation", move |_, id: u8| Ok(get(&h, id).anim));
+
+	let h = shared.clone();
+	func(s, &t, "getFlags", move |_, id: u8| Ok(get(&h, id).flags));
+
+	let h = shared.clone();
+	func(s, &t, "setState", move |_, x: (u8, f32, f32, f32, f32, f32, Option<f32>)|
+	{
+		set(&h, x.0, |s|
+		{
+			s.pos = glam::vec3(x.1, x.2, x.3);
+			s.angle = glam::vec3(x.4, x.5, x.6.unwrap_or(0.0));
+		})
+	});
+
+	let h = shared.clone();
+	func(s, &t, "setVelocity", move |_, x: (u8, f32, f32, f32)|
+	{
+		set(&h, x.0, |s| s.vel = glam::vec3(x.1, x.2, x.3))
+	});
+
+	let h = shared.clone();
+	func(s, &t, "setAnimation", move |_, (id, anim): (u8, u8)|
+	{
+		set(&h, id, |s| s.anim = anim)
+	});
+
+	let h = shared.clone();
+	func(s, &t, "setFlags", move |_, (id, flags): (u8, u16)|
+	{
+		set(&h, id, |s| s.flags = flags)
+	});
+
+	let h = shared.clone();
+	func(s, &t, "remove", move |_, id: u8|
+	{
+		let mut s = h.borrow_mut();
+		if s.entities.remove(&id).is_some() { s.removed.push(id); }
+		Ok(())
+	});
+
+	let _ = s.globals().raw_set("network", t);
+}
+
+fn save(s: &Lua, shared: &Handle)
+{
+	let t = s.create_table().unwrap();
+
+	let h = shared.clone();
+	func(s, &t, "checkpoint", move |_, _: ()| Ok(h.borrow().checkpoint.clone()));
+
+	let h = shared.clone();
+	func(s, &t, "setCheckpoint", move |_, cp: String|
+	{
+		let mut s = h.borrow_mut();
+		s.checkpoint = cp.clone();
+		s.ops.push(Op::Checkpoint(cp));
+		Ok(())
+	});
+
+	let h = shared.clone();
+	func(s, &t, "giveItem", move |_, (id, item, count): (u8, String, Option<u8>)|
+	{
+		h.borrow_mut().ops.push(Op::Give(id, item, count.unwrap_or(1)));
+		Ok(())
+	});
+
+	let h = shared.clone();
+	func(s, &t, "takeItem", move |_, (id, item, count): (u8, String, Option<u8>)|
+	{
+		h.borrow_mut().ops.push(Op::Take(id, item, count.unwrap_or(1)));
+		Ok(())
+	});
+
+	let _ = s.globals().raw_set("save", t);
+}
+
+// Records are tagged with the script ID. The game binds the same table.
+pub fn log(s: &Lua)
+{
+	let t = s.create_table().unwrap();
+	for (name, level) in [
+		("error", log::Level::Error),
+		("warn", log::Level::Warn),
+		("info", log::Level::Info),
+		("debug", log::Level::Debug)
+	]
+	{
+		func(s, &t, name, move |s, msg: String|
+		{
+			let target: String = s.globals().raw_get("ScriptID").unwrap_or_default();
+			log::write(level, &target, msg);
+			Ok(())
+		});
+	}
+
+	// Records after `since`, oldest first.
+	func(s, &t, "lines", |s, since: Option<u64>|
+	{
+		let list = s.create_table().unwrap();
+		for x in log::since(since.unwrap_or(0))
+		{
+			let t = s.create_table().unwrap();
+			let _ = t.raw_set("id", x.id);
+			let _ = t.raw_set("time", x.time);
+			let _ = t.raw_set("level", x.level.name());
+			let _ = t.raw_set("target", x.target);
+			let _ = t.raw_set("msg", x.msg);
+			let _ = list.raw_push(t);
+		}
+		Ok(list)
+	});
+
+	let _ = s.globals().raw_set("log", t);
+}
+
+fn get(h: &Handle, id: u8) -> State
+{
+	let s = h.borrow();
+	s.players.get(&id).or(s.entities.get(&id)).copied().unwrap_or_default()
+}
+
+fn set(h: &Handle, id: u8, f: impl FnOnce(&mut State)) -> mlua::Result<()>
+{
+	let mut s = h.borrow_mut();
+	if id < MAX_PLAYERS || id == u8::MAX
+	{
+		return Err(mlua::Error::runtime(format!(
+			"id {id} is reserved, entities use {MAX_PLAYERS}..254"
+		)));
+	}
+	f(s.entities.entry(id).or_default());
+	Ok(())
+}
+
+pub fn fromJSON(s: &Lua, v: &json::JsonValue) -> mlua::Value
+{
+	match v
+	{
+		json::JsonValue::Null => mlua::Value::Nil,
+		json::JsonValue::Boolean(x) => mlua::Value::Boolean(*x),
+		json::JsonValue::Number(_) => mlua::Value::Number(v.as_f64().unwrap_or(0.0)),
+		json::JsonValue::Short(_) | json::JsonValue::String(_) =>
+		{
+			s.create_string(v.as_str().unwrap_or(""))
+				.map(mlua::Value::String)
+				.unwrap_or(mlua::Value::Nil)
+		}
+		json::JsonValue::Array(list) =>
+		{
+			let t = s.create_table().unwrap();
+			for x in list { let _ = t.raw_push(fromJSON(s, x)); }
+			mlua::Value::Table(t)
+		}
+		json::JsonValue::Object(obj) =>
+		{
+			let t = s.create_table().unwrap();
+			for (k, x) in obj.iter() { let _ = t.raw_set(k, fromJSON(s, x)); }
+			mlua::Value::Table(t)
+		}
+	}
+}
+
+fn toJSON(v: mlua::Value) -> json::JsonValue
+{
+	match v
+	{
+		mlua::Value::Boolean(x) => x.into(),
+		mlua::Value::Integer(x) => x.into(),
+		mlua::Value::Number(x) => x.into(),
+		mlua::Value::String(x) => x.to_string_lossy().into(),
+		mlua::Value::Table(t) =>
+		{
+			let mut obj = json::object!{};
+			for (k, x) in t.pairs::<mlua::Value, mlua::Value>().flatten()
+			{
+				let key = match k
+				{
+					mlua::Value::String(k) => k.to_string_lossy(),
+					mlua::Value::Integer(k) => k.to_string(),
+					_ => continue
+				};
+				let _ = obj.insert(&key, toJSON(x));
+			}
+			obj
+		}
+		_ => json::JsonValue::Null
+	}
+}
+
+pub fn math(s: &Lua)
+{
+	let t = s.create_table().unwrap();
+	
+	func(s, &t, "clamp", |_, x: (f32, f32, f32)|
+	{
+		Ok(x.0.clamp(x.1, x.2))
+	});
+
+	func(s, &t, "rectContains", |_,  x: (Table, Table)|
+	{
+		let p = glam::vec2(
+			x.0.raw_get("x").unwrap_or(0.0),
+			x.0.raw_get("y").unwrap_or(0.0)
+		);
+		let r = glam::vec4(
+			x.1.raw_get("x").unwrap_or(0.0),
+			x.1.raw_get("y").unwrap_or(0.0),
+			x.1.raw_get("w").unwrap_or(0.0),
+			x.1.raw_get("h").unwrap_or(0.0)
+		);
+		Ok(
+			p.x == p.x.clamp(r.x, r.x + r.z) &&
+			p.y == p.y.clamp(r.y, r.y + r.w)
+		)
+	});
+
+	func(s, &t, "rectIntersects", |_, i: (Table, Table)|
+	{
+		let r1 = glam::vec4(
+			i.0.raw_get("x").unwrap_or(0.0),
+			i.0.raw_get("y").unwrap_or(0.0),
+			i.0.raw_get("z").unwrap_or(0.0),
+			i.0.raw_get("w").unwrap_or(0.0),
+		);
+		let r2 = glam::vec4(
+			i.1.raw_get("x").unwrap_or(0.0),
+			i.1.raw_get("y").unwrap_or(0.0),
+			i.1.raw_get("z").unwrap_or(0.0),
+			i.1.raw_get("w").unwrap_or(0.0),
+		);
+		let il = r1.x.max(r2.x);
+		let it = r1.y.max(r2.y);
+		let ir = (r1.x + r1.z).min(r2.x + r2.z);
+		let ib = (r1.y + r1.w).min(r2.y + r2.w);
+		Ok((il < ir) && (it < ib))
+	});
+
+	func(s, &t, "lerp", |_, x: (f32, f32, f32)|
+	{
+		let a = x.0; let b = x.1; let t = x.2;
+		Ok(a * (1.0 - t) + b * t)
+	});
+
+	func(s, &t, "cubicIn", |_, x: f32|
+	{
+		Ok(x.powi(3))
+	});
+
+	func(s, &t, "cubicOut", |_, x: f32|
+	{
+		Ok(1.0 - (1.0 - x).powi(3))
+	});
+
+	func(s, &t, "cubicInOut", |_, x: f32|
+	{
+		Ok(
+			if x < 0.5 { 4.0 * x.powi(3) }
+			else { 1.0 - (-2.0 * x + 2.0).powi(3) * 0.5 }
+		)
+	});
+
+	func(s, &t, "sineIn", |_, x: f32|
+	{
+		Ok(1.0 - (x * std::f32::consts::PI).cos() * 0.5)
+	});
+
+	func(s, &t, "sineOut", |_, x: f32|
+	{
+		Ok((x * std::f32::consts::PI * 0.5).sin())
+	});
+
+	func(s, &t, "sineInOut", |_, x: f32|
+	{
+		Ok(-((x * std::f32::consts::PI).cos() - 1.0) * 0.5)
+	});
+
+	func(s, &t, "round", |_, x: (f32, u8)|
+	{
+		let factor = 10.0_f32.powi(x.1 as i32);
+		Ok((x.0 * factor).round() / factor)
+	});
+
+	let _ = s.globals().raw_set("aemath", t);
+}
\ No newline at end of file
@@ -1,12 +1,19 @@
 use std::collections::HashMap;
 
+use crate::envell::inventory::{self, Inventory, Limits};
+use crate::{error, warn, info};
+
+// Version of the save file layout, bumped whenever it changes.
+// Older files are migrated step by step on load.
+pub const VERSION: u32 = 1;
+
 #[derive(Clone, Debug)]
 pub struct Account
 {
-	name: String,
-	class: String,
+	pub name: String,
+	pub class: String,
 	color: (u8, u8, u8),
-	inventory: Vec<(String, u8)>,
+	pub inventory: Inventory,
 }
 
 impl Default for Account
@@ -18,7 +25,7 @@ impl Default for Account
 			name: String::new(),
 			class: String::new(),
 			color: (255, 255, 255),
-			inventory: vec![(String::new(), 0); 8]
+			inventory: Inventory::default()
 		}
 	}
 }
@@ -28,10 +35,38 @@ type Players = HashMap<String, Account>;
 #[derive(Clone, Default, Debug)]
 pub struct State
 {
+	pub slot: String,
 	pub date: String,
 	pub checkpoint: String,
 	pub players: Players,
-	pub visible: bool
+	pub visible: bool,
+	pub netsim: bool,
+	pub dirty: bool
+}
+
+// Changes requested by the server while the game goes on.
+#[derive(Clone, Debug)]
+pub enum Change
+{
+	Checkpoint(String),
+	GiveItem(String, String, u8),
+	TakeItem(String, String, u8),
+	MoveItem(String, u8, u8),
+	SplitItem(String, u8, u8, u8)
+}
+
+impl Change
+{
+	// Whose inventory the change touches.
+	pub fn owner(&self) -> Option<&str>
+	{
+		match self
+		{
+			Change::Checkpoint(_) => None,
+			Change::GiveItem(ip, ..) | Change::TakeItem(ip, ..) |
+			Change::MoveItem(ip, ..) | Change::SplitItem(ip, ..) => Some(ip)
+		}
+	}
 }
 
 impl State
@@ -40,6 +75,41 @@ impl State
 	{
 		self.players.get(&ip).cloned().unwrap_or_default()
 	}
+
+	pub fn apply(&mut self, change: Change, limits: &Limits) -> bool
+	{
+		let done = match change
+		{
+			Change::Checkpoint(cp) =>
+			{
+				self.checkpoint = cp;
+				true
+			}
+			Change::GiveItem(ip, id, count) =>
+			{
+				let left = self.players.entry(ip.clone()).or_default()
+					.inventory.add(&id, count, limits);
+				if left > 0 { warn!("{ip}: {left} of '{id}' did not fit."); }
+				left < count
+			}
+			Change::TakeItem(ip, id, count) =>
+			{
+				self.players.entry(ip).or_default().inventory.remove(&id, count)
+			}
+			Change::MoveItem(ip, from, to) =>
+			{
+				self.players.entry(ip).or_default()
+					.inventory.moveItem(from as usize, to as usize, limits)
+			}
+			Change::SplitItem(ip, from, to, count) =>
+			{
+				self.players.entry(ip).or_default()
+					.inventory.split(from as usize, to as usize, count, limits)
+			}
+		};
+		self.dirty |= done;
+		done
+	}
 }
 
 pub fn load(path: &str) -> State
@@ -48,36 +118,125 @@ pub fn load(path: &str) -> State
 
 	if let Ok(f) = std::fs::read_to_string(path)
 	{
-		if let Ok(state) = json::parse(&f)
+		match json::parse(&f).ok().and_then(migrate)
 		{
-			s.date = state["date"].as_str().unwrap_or("???").to_string();
-			s.checkpoint = state["checkpoint"].as_str().unwrap_or("???").to_string();
-			s.players = parsePlayers(&state["players"]);
+			Some(state) =>
+			{
+				s.date = state["date"].as_str().unwrap_or("???").to_string();
+				s.checkpoint = state["checkpoint"].as_str().unwrap_or("???").to_string();
+				s.players = parsePlayers(&state["players"]);
+			}
+			None =>
+			{
+				// The next save would overwrite it, so the file is kept aside for an admin.
+				let aside = aside(path);
+				match std::fs::rename(path, &aside)
+				{
+					Ok(_) => error!("Save file '{path}' is broken or too new, moved to '{aside}'. Starting over."),
+					Err(x) => error!("Save file '{path}' is broken or too new and could not be moved aside: {x}")
+				}
+			}
 		}
 	}
-	
+
 	s
 }
 
+// First free name of "<path>.broken", "<path>.broken.1" and so on.
+fn aside(path: &str) -> String
+{
+	let mut out = format!("{path}.broken");
+	let mut n = 1;
+	while std::path::Path::new(&out).exists()
+	{
+		out = format!("{path}.broken.{n}");
+		n += 1;
+	}
+	out
+}
+
+// Writes to a temporary file first, so a crash never leaves a half-written save.
+pub fn save(state: &mut State, path: &str) -> std::io::Result<()>
+{
+	use std::io::Write;
+
+	state.date = now();
+	let mut players = json::object!{};
+	for (ip, a) in &state.players
+	{
+		let _ = players.insert(ip, json::object!{
+			name: a.name.clone(),
+			class: a.class.clone(),
+			color: [a.color.0, a.color.1, a.color.2],
+			inventory: a.inventory.cells.iter()
+				.map(|(id, count)| json::object!{ id: id.clone(), count: *count })
+				.collect::<Vec<_>>()
+		});
+	}
+	let data = json::object!{
+		version: VERSION,
+		date: state.date.clone(),
+		checkpoint: state.checkpoint.clone(),
+		players: players
+	};
+
+	let tmp = format!("{path}.tmp");
+	let mut f = std::fs::File::create(&tmp)?;
+	f.write_all(json::stringify(data).as_bytes())?;
+	f.sync_all()?;
+	std::fs::rename(&tmp, path)?;
+	state.dirty = false;
+	info!("Game is saved to '{path}'.");
+	Ok(())
+}
+
+fn migrate(mut data: json::JsonValue) -> Option<json::JsonValue>
+{
+	loop
+	{
+		let version = data["version"].as_u32().unwrap_or(0);
+		data = match version
+		{
+			VERSION => return Some(data),
+			0 => from0(data),
+			_ => return None
+		};
+	}
+}
+
+// Version 0 kept players in a list with their address inside.
+fn from0(mut data: json::JsonValue) -> json::JsonValue
+{
+	let mut players = json::object!{};
+	for p in data["players"].members()
+	{
+		let mut p = p.clone();
+		let ip = p["ip"].take_string().unwrap_or(String::from("0.0.0.0"));
+		p.remove("ip");
+		let _ = players.insert(&ip, p);
+	}
+	data["players"] = players;
+	data["version"] = 1.into();
+	data
+}
+
 fn parsePlayers(src: &json::JsonValue) -> Players
 {
 	let mut out = Players::new();
 	if src.is_null() { return out; }
-	for p in src.members()
+	for (ip, p) in src.entries()
 	{
 		let color = &p["color"];
-		let mut inventory = vec![];
-		for i in 0..8
+		let mut inventory = Inventory::default();
+		for i in 0..inventory::SIZE
 		{
-			inventory.push(
-				(
-					p["inventory"][i]["id"].as_str().unwrap_or("").to_string(),
-					p["inventory"][i]["count"].as_u8().unwrap_or(0)
-				)
+			inventory.cells[i] = (
+				p["inventory"][i]["id"].as_str().unwrap_or("").to_string(),
+				p["inventory"][i]["count"].as_u8().unwrap_or(0)
 			);
 		}
 		out.insert(
-			p["ip"].as_str().unwrap_or("0.0.0.0").to_string(),
+			ip.to_string(),
 			Account
 			{
 				name: p["name"].as_str().unwrap_or("NoName").to_string(),
@@ -87,9 +246,66 @@ fn parsePlayers(src: &json::JsonValue) -> Players
 					color[1].as_u8().unwrap_or(255),
 					color[2].as_u8().unwrap_or(255)
 				),
-				inventory: inventory
+				inventory
 			}
 		);
 	}
 	out
-}
\ No newline at end of file
+}
+
+pub fn now() -> String
+{
+	let secs = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|x| x.as_secs() as i64)
+		.unwrap_or(0);
+	date(secs)
+}
+
+// UTC time as "YYYY-MM-DD HH:MM:SS", without pulling in a date crate.
+pub fn date(secs: i64) -> String
+{
+	let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let d = doy - (153 * mp + 2) / 5 + 1;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 };
+	let y = yoe + era * 400 + (m <= 2) as i64;
+	format!(
+		"{y:04}-{m:02}-{d:02} {:02}:{:02}:{:02}",
+		rem / 3600, rem % 3600 / 60, rem % 60
+	)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn tooNewSurvives()
+	{
+		let dir = std::env::temp_dir().join(format!("envell-state-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("main.json").to_string_lossy().to_string();
+		let future = format!("{{\"version\": {}, \"checkpoint\": \"far\"}}", VERSION + 1);
+		std::fs::write(&path, &future).unwrap();
+
+		let mut s = load(&path);
+		assert!(s.checkpoint.is_empty());
+		save(&mut s, &path).unwrap();
+		assert_eq!(std::fs::read_to_string(format!("{path}.broken")).unwrap(), future);
+
+		// A second broken file does not replace the first one.
+		std::fs::write(&path, "{").unwrap();
+		load(&path);
+		assert_eq!(std::fs::read_to_string(format!("{path}.broken")).unwrap(), future);
+		assert_eq!(std::fs::read_to_string(format!("{path}.broken.1")).unwrap(), "{");
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
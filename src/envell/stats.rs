@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct Stats
+{
+	pub rtt: Duration,
+	pub sentBytes: u64,
+	pub sentPackets: u64,
+	pub receivedBytes: u64,
+	pub receivedPackets: u64,
+	pub lost: u64,
+	pub expected: u64,
+	pub sendRate: f32,
+	pub receiveRate: f32,
+	lastSeq: Option<u16>,
+	ping: Option<(u32, Instant)>,
+	pingID: u32,
+	rateTimer: Instant,
+	rateSent: u64,
+	rateReceived: u64
+}
+
+impl Default for Stats
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			rtt: Duration::ZERO,
+			sentBytes: 0,
+			sentPackets: 0,
+			receivedBytes: 0,
+			receivedPackets: 0,
+			lost: 0,
+			expected: 0,
+			sendRate: 0.0,
+			receiveRate: 0.0,
+			lastSeq: None,
+			ping: None,
+			pingID: 0,
+			rateTimer: Instant::now(),
+			rateSent: 0,
+			rateReceived: 0
+		}
+	}
+}
+
+impl Stats
+{
+	pub fn sent(&mut self, bytes: usize)
+	{
+		self.sentBytes += bytes as u64;
+		self.sentPackets += 1;
+	}
+
+	pub fn received(&mut self, bytes: usize)
+	{
+		self.receivedBytes += bytes as u64;
+		self.receivedPackets += 1;
+	}
+
+	// Counts gaps in UDP sequence numbers as lost packets.
+	pub fn sequence(&mut self, seq: u16)
+	{
+		let Some(last) = self.lastSeq else
+		{
+			self.lastSeq = Some(seq);
+			self.expected += 1;
+			return;
+		};
+		let gap = seq.wrapping_sub(last);
+		if gap == 0 { return; }
+		if gap < 0x8000
+		{
+			self.expected += gap as u64;
+			self.lost += gap as u64 - 1;
+			self.lastSeq = Some(seq);
+		}
+		else { self.lost = self.lost.saturating_sub(1); }
+	}
+
+	// The game starts counting again after every Setup.
+	#[allow(dead_code)]
+	pub fn resetSequence(&mut self) { self.lastSeq = None; }
+
+	pub fn loss(&self) -> f32
+	{
+		if self.expected == 0 { 0.0 }
+		else { self.lost as f32 / self.expected as f32 }
+	}
+
+	pub fn ping(&mut self) -> u32
+	{
+		self.pingID = self.pingID.wrapping_add(1);
+		self.ping = Some((self.pingID, Instant::now()));
+		self.pingID
+	}
+
+	pub fn pong(&mut self, id: u32)
+	{
+		if let Some((x, t)) = self.ping
+			&& x == id
+		{
+			self.rtt = t.elapsed();
+			self.ping = None;
+		}
+	}
+
+	pub fn updateRates(&mut self)
+	{
+		let t = self.rateTimer.elapsed().as_secs_f32();
+		if t <= 0.0 { return; }
+		self.sendRate = (self.sentBytes - self.rateSent) as f32 / t;
+		self.receiveRate = (self.receivedBytes - self.rateReceived) as f32 / t;
+		self.rateSent = self.sentBytes;
+		self.rateReceived = self.receivedBytes;
+		self.rateTimer = Instant::now();
+	}
+}
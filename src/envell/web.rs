@@ -1,16 +1,73 @@
-use std::{collections::HashMap, io::{Read, Write}};
+use std::{collections::HashMap, io::{ErrorKind, Read, Write}, net::IpAddr};
 
 use base64::{prelude::BASE64_STANDARD, Engine};
 use mio::{net::{TcpListener, TcpStream}, Events, Interest, Poll, Token};
 
-use crate::envell::{config::{self, Config}, state::State};
+use crate::envell::{api, auth::Permission, chat, config::{self, Config}, http, log, options, player, saves, state::State, stats::Stats, websocket};
+use crate::{warn, info};
+
+// A client that falls this far behind on reading is dropped.
+const MAX_OUTGOING: usize = 16 << 20;
 
 #[derive(PartialEq, Debug)]
 enum ClientMode
 {
 	Http,
 	WebSocket,
-	Disconnected
+	Disconnected,
+	// Main knows the client is gone, what is left in its buffer still goes out.
+	Closing
+}
+
+struct Client
+{
+	mode: ClientMode,
+	tcp: TcpStream,
+	ip: IpAddr,
+	// Mirrors the session in main, so admin-only broadcasts skip guests.
+	admin: bool,
+	// Answers to a pending API request, whether it was HEAD and whether to keep the connection after it.
+	api: Option<(Vec<Resp>, bool, bool)>,
+	http: http::Parser,
+	ws: websocket::Decoder,
+	// Written as the socket takes it, the rest waits for WRITABLE.
+	out: Vec<u8>
+}
+
+impl Client
+{
+	fn send(&mut self, data: &[u8])
+	{
+		self.out.extend_from_slice(data);
+		if self.out.len() > MAX_OUTGOING
+		{
+			warn!("WebClient at {} does not keep up, dropping it.", self.ip);
+			self.broken();
+			return;
+		}
+		self.flush();
+	}
+
+	fn flush(&mut self)
+	{
+		while !self.out.is_empty()
+		{
+			match self.tcp.write(&self.out)
+			{
+				Ok(n) if n > 0 => { self.out.drain(..n); }
+				Err(x) if x.kind() == ErrorKind::WouldBlock => return,
+				Err(x) if x.kind() == ErrorKind::Interrupted => {}
+				_ => self.broken()
+			}
+		}
+	}
+
+	// Nobody will read the rest.
+	fn broken(&mut self)
+	{
+		self.out.clear();
+		if self.mode != ClientMode::Closing { self.mode = ClientMode::Disconnected; }
+	}
 }
 
 pub enum Req
@@ -22,19 +79,59 @@ pub enum Req
 	SaveSettings(json::JsonValue),
 	Modal(String, json::JsonValue),
 	Buttons,
-	ClickButton(String)
+	ClickButton(String),
+	Saves,
+	SaveSlot(saves::Action),
+	Login(IpAddr, String),
+	Resume(String),
+	Logout,
+	ChangePassword(String, String),
+	Closed,
+	Players,
+	PlayerAction(u8, player::Action),
+	Unban(String),
+	// HTTP request with its bearer token, answered with everything main sends until ApiDone.
+	Api(String, Box<Req>),
+	ApiDone
+}
+
+impl Req
+{
+	pub fn permission(&self) -> Permission
+	{
+		match self
+		{
+			Req::ChatMessages(_) | Req::NewMessage(_) => Permission::Chat,
+			Req::GetSettings | Req::SaveSettings(_) | Req::ChangePassword(..) => Permission::Settings,
+			Req::Buttons | Req::ClickButton(_) => Permission::Control,
+			Req::Saves | Req::SaveSlot(_) => Permission::Saves,
+			Req::Players | Req::PlayerAction(..) | Req::Unban(_) => Permission::Players,
+			Req::Modal(id, _) if id == "stopServer" => Permission::Stop,
+			_ => Permission::View
+		}
+	}
 }
 
 pub type Request = (usize, Req);
 
 pub enum Resp
 {
-	ChatMessages(Vec<(String, String)>),
-	NewMessage(String, String),
-	State(State),
+	ChatMessages(Vec<chat::Entry>),
+	NewMessage(chat::Entry),
+	State(State, Vec<(u8, String, Stats)>),
 	GetSettings(Config),
 	Modal(String),
-	Buttons(Vec<(String, String)>)
+	Buttons(Vec<(String, String)>),
+	Saves(String, Vec<saves::Info>),
+	// Session token when logged in, otherwise why not and how long to wait.
+	Auth(Option<String>, String, u64),
+	// Players and banned addresses.
+	Players(Vec<player::Info>, Vec<String>),
+	Log(Vec<log::Record>),
+	// Saved settings that apply once no players are connected, and those adjusted to fit.
+	Saved(Vec<String>, Vec<String>),
+	ApiDone,
+	Shutdown
 }
 
 pub type Response = (usize, Resp);
@@ -44,26 +141,39 @@ pub fn main(
 	fromMain: std::sync::mpsc::Receiver<Response>
 )
 {
-	let mut listener = TcpListener::bind(
-		"0.0.0.0:8080".parse().unwrap()
-	);
-	if listener.is_err() { listener = TcpListener::bind("0.0.0.0:0".parse().unwrap()); }
-
-	let mut listener = listener.expect("Failed to create web server.");
+	let mut listeners = crate::envell::net::bindTcp(options::get().webPort);
+	if listeners.is_empty() { listeners = crate::envell::net::bindTcp(0); }
+	if listeners.is_empty() { panic!("Failed to create web server."); }
 
-	println!("Launched web server on port {}.", listener.local_addr().unwrap().port());
+	info!("Launched web server on port {}.", listeners[0].local_addr().unwrap().port());
+	run(listeners, toMain, fromMain);
+}
 
+fn run(
+	mut listeners: Vec<TcpListener>,
+	toMain: std::sync::mpsc::Sender<Request>,
+	fromMain: std::sync::mpsc::Receiver<Response>
+)
+{
+	// Listeners take tokens from the top, clients count up from 1.
+	// Ids are never reused, main keeps sessions by them until it hears Closed.
 	let mut poll = Poll::new().expect("Failed to create socket selector.");
-	let _ = poll.registry().register(
-		&mut listener, Token(0),
-		Interest::READABLE
-	);
+	for (i, l) in listeners.iter_mut().enumerate()
+	{
+		let _ = poll.registry().register(
+			l, Token(usize::MAX - i),
+			Interest::READABLE
+		);
+	}
 
 	let mut events = Events::with_capacity(64);
 
 	let mut token = 1;
 	let mut clients =
-		HashMap::<Token, (ClientMode, TcpStream)>::new();
+		HashMap::<Token, Client>::new();
+
+	// Last log record sent to admins, later ones are streamed as they come.
+	let mut lastLog = 0;
 
 	loop
 	{
@@ -71,36 +181,140 @@ pub fn main(
 		{
 			match msg.1
 			{
-				Resp::NewMessage(user, msg) =>
+				Resp::NewMessage(e) =>
 				{
-					for (_, (m, c)) in &mut clients
+					for c in clients.values_mut()
 					{
-						if *m == ClientMode::WebSocket
+						if c.mode == ClientMode::WebSocket && c.admin
 						{
-							sendWS(c, Resp::NewMessage(user.clone(), msg.clone()));
+							sendWS(c, Resp::NewMessage(e.clone()));
 						}
 					}
 				}
-				Resp::State(state) =>
+				Resp::State(state, stats) if msg.0 == 0 =>
 				{
-					for (_, (m, c)) in &mut clients
+					for c in clients.values_mut()
 					{
-						if *m == ClientMode::WebSocket
+						if c.mode == ClientMode::WebSocket
 						{
-							sendWS(c, Resp::State(state.clone()));
+							let stats = if c.admin { stats.clone() } else { vec![] };
+							sendWS(c, Resp::State(state.clone(), stats));
 						}
 					}
 				}
+				Resp::Saves(active, list) if msg.0 == 0 =>
+				{
+					for c in clients.values_mut()
+					{
+						if c.mode == ClientMode::WebSocket && c.admin
+						{
+							sendWS(c, Resp::Saves(active.clone(), list.clone()));
+						}
+					}
+				}
+				Resp::Players(list, bans) if msg.0 == 0 =>
+				{
+					for c in clients.values_mut()
+					{
+						if c.mode == ClientMode::WebSocket && c.admin
+						{
+							sendWS(c, Resp::Players(list.clone(), bans.clone()));
+						}
+					}
+				}
+				// Every session has ended.
+				Resp::Auth(None, reason, wait) if msg.0 == 0 =>
+				{
+					for c in clients.values_mut()
+					{
+						c.admin = false;
+						if c.mode == ClientMode::WebSocket
+						{
+							sendWS(c, Resp::Auth(None, reason.clone(), wait));
+						}
+					}
+				}
+				Resp::Auth(token, reason, wait) =>
+				{
+					if let Some(c) = clients.get_mut(&Token(msg.0))
+					{
+						if let Some((answers, ..)) = &mut c.api
+						{
+							answers.push(Resp::Auth(token, reason, wait));
+							continue;
+						}
+						c.admin = token.is_some();
+						sendWS(c, Resp::Auth(token, reason, wait));
+						if c.admin
+						{
+							let mut recent = log::since(0);
+							recent.retain(|x| x.id <= lastLog);
+							sendWS(c, Resp::Log(recent));
+						}
+					}
+				}
+				Resp::Shutdown =>
+				{
+					for c in clients.values_mut()
+					{
+						match c.mode
+						{
+							ClientMode::WebSocket =>
+							{
+								c.send(&websocket::close(websocket::CLOSE_GOING_AWAY, "Server is stopping"));
+							}
+							ClientMode::Http if c.api.is_some() =>
+							{
+								c.send(&api::error(503, false));
+							}
+							_ => {}
+						}
+						// Whatever the socket does not take now is lost, the server is going away.
+						let _ = c.tcp.shutdown(std::net::Shutdown::Both);
+					}
+					info!("Web server is closed.");
+					return;
+				}
+				Resp::ApiDone =>
+				{
+					let Some(c) = clients.get_mut(&Token(msg.0)) else { continue; };
+					let Some((answers, head, keepAlive)) = c.api.take() else { continue; };
+					c.send(&api::response(answers, head, keepAlive));
+					if c.mode == ClientMode::Http
+					{
+						if keepAlive { handleHTTP(c, msg.0, &toMain); }
+						else { c.mode = ClientMode::Disconnected; }
+					}
+				}
 				resp =>
 				{
-					if let Some((_, tcp)) = clients.get_mut(&Token(msg.0))
+					if let Some(c) = clients.get_mut(&Token(msg.0))
 					{
-						sendWS(tcp, resp);
+						match &mut c.api
+						{
+							Some((answers, ..)) => answers.push(resp),
+							None if c.mode == ClientMode::WebSocket && !c.admin => sendWS(c, forGuest(resp)),
+							None if c.mode == ClientMode::WebSocket => sendWS(c, resp),
+							None => {}
+						}
 					}
 				}
 			}
 		}
 
+		let records = log::since(lastLog);
+		if let Some(x) = records.last()
+		{
+			lastLog = x.id;
+			for c in clients.values_mut()
+			{
+				if c.mode == ClientMode::WebSocket && c.admin
+				{
+					sendWS(c, Resp::Log(records.clone()));
+				}
+			}
+		}
+
 		let _ = poll.poll(
 			&mut events,
 			Some(std::time::Duration::from_millis(20))
@@ -109,76 +323,100 @@ pub fn main(
 		for e in events.iter()
 		{
 			let socketID = e.token().0;
-			if socketID == 0
+			if let Some(listener) = listeners.get_mut(usize::MAX - socketID)
 			{
-				while let Ok((mut tcp, _)) = listener.accept()
+				while let Ok((mut tcp, addr)) = listener.accept()
 				{
 					let t = Token(token);
 					let _ = poll.registry().register(
 						&mut tcp, t,
-						Interest::READABLE
+						Interest::READABLE | Interest::WRITABLE
 					);
-					clients.insert(t, (ClientMode::Http, tcp));
+					clients.insert(t, Client
+					{
+						mode: ClientMode::Http,
+						tcp,
+						ip: addr.ip(),
+						admin: false,
+						api: None,
+						http: http::Parser::default(),
+						ws: websocket::Decoder::server(),
+						out: vec![]
+					});
 					token += 1;
 				}
 			}
 			else
 			{
-				let client = clients.get_mut(&e.token()).unwrap();
+				let Some(client) = clients.get_mut(&e.token()) else { continue; };
+				if e.is_writable() { client.flush(); }
 
-				if e.is_read_closed()
+				let mut buf = [0u8; 4096];
+				while matches!(client.mode, ClientMode::Http | ClientMode::WebSocket)
 				{
-					let _ = poll.registry().deregister(&mut client.1);
-					clients.remove(&Token(socketID));
-					continue;
-				}
-				
-				let mut buf = [0u8; 1024];
-				while let Ok(size) = client.1.read(&mut buf)
-				{
-					match client.0
+					let size = match client.tcp.read(&mut buf)
 					{
-						ClientMode::Http =>
-						{
-							match handleHTTP(&mut client.1, &buf[..size])
-							{
-								0 => {}
-								1 => { client.0 = ClientMode::Disconnected; break; }
-								2 =>
-								{
-									if setupWS(
-										&mut client.1, &buf[..size],
-										socketID, &toMain
-									) { client.0 = ClientMode::WebSocket; }
-									else { client.0 = ClientMode::Disconnected; break; }
-								}
-								x => println!("Unknown HTTP status code: {x}")
-							}
-						}
-						ClientMode::WebSocket =>
+						Ok(0) => { client.mode = ClientMode::Disconnected; break; }
+						Ok(size) => size,
+						Err(x) if x.kind() == ErrorKind::WouldBlock => break,
+						Err(x) if x.kind() == ErrorKind::Interrupted => continue,
+						Err(_) => { client.broken(); break; }
+					};
+					if client.mode == ClientMode::Http
+					{
+						client.http.push(&buf[..size]);
+						handleHTTP(client, socketID, &toMain);
+					}
+					else
+					{
+						client.ws.push(&buf[..size]);
+						if !receiveWS(client, socketID, &toMain)
 						{
-							if size == 0 { client.0 = ClientMode::Disconnected; break; }
-							if let Some((msg, data)) = receiveWS(&buf[..size])
-							{
-								handleWS(socketID, &msg, data, &toMain);
-							}
-							else { client.0 = ClientMode::Disconnected; break; }
+							client.mode = ClientMode::Disconnected;
 						}
-						ClientMode::Disconnected => {}
 					}
 				}
-				if client.0 == ClientMode::Disconnected
-				{
-					let _ = poll.registry().deregister(&mut client.1);
-					clients.remove(&Token(socketID));
-					if clients.len() == 0 { token = 1; }
-				}
 			}
 		}
+
+		// Main hears about every client that is gone, right when it is gone.
+		// The client itself stays until the rest of its buffer is written.
+		clients.retain(|id, c|
+		{
+			if c.mode == ClientMode::Disconnected
+			{
+				let _ = toMain.send((id.0, Req::Closed));
+				c.mode = ClientMode::Closing;
+			}
+			if c.mode == ClientMode::Closing && c.out.is_empty()
+			{
+				let _ = poll.registry().deregister(&mut c.tcp);
+				return false;
+			}
+			true
+		});
 	}
 }
 
-fn sendWS(tcp: &mut TcpStream, msg: Resp)
+// Guests see the state of the server, but not who plays from where.
+fn forGuest(msg: Resp) -> Resp
+{
+	match msg
+	{
+		Resp::State(state, _) => Resp::State(state, vec![]),
+		x => x
+	}
+}
+
+fn sendWS(client: &mut Client, msg: Resp)
+{
+	let (topic, obj) = toJSON(msg);
+	let raw = json::stringify(json::object!{ type: topic, data: obj });
+	client.send(&websocket::text(&raw));
+}
+
+// Topic and data of a response, as the web panel expects them.
+pub fn toJSON(msg: Resp) -> (&'static str, json::JsonValue)
 {
 	let topic: &'static str;
 	let mut obj: json::JsonValue;
@@ -188,31 +426,34 @@ fn sendWS(tcp: &mut TcpStream, msg: Resp)
 		{
 			topic = "chatMessages";
 			obj = json::array![];
-			for (user, msg) in history
+			for e in history
 			{
 				let _ = obj.push(json::object!{
-					user: user,
-					msg: msg
+					time: e.time,
+					user: e.user,
+					msg: e.msg
 				});
 			}
 		}
-		Resp::NewMessage(user, msg) =>
+		Resp::NewMessage(e) =>
 		{
 			topic = "chatMessages";
 			obj = json::array![
 				{
-					user: user.clone(),
-					msg: msg.clone()
+					time: e.time.clone(),
+					user: e.user.clone(),
+					msg: e.msg.clone()
 				}
 			];
 		}
-		Resp::State(state) =>
+		Resp::State(state, stats) =>
 		{
 			topic = "state";
 			obj = json::array![
 				{
 					title: "Сохранение",
 					props: {
+						"Слот": state.slot.clone(),
 						"Чекпоинт": state.checkpoint.clone(),
 						"Дата сохранения": state.date.clone()
 					}
@@ -220,10 +461,26 @@ fn sendWS(tcp: &mut TcpStream, msg: Resp)
 				{
 					title: "Система",
 					props: {
-						"Врата открыты": if state.visible { "Да" } else { "Нет" }
+						"Врата открыты": if state.visible { "Да" } else { "Нет" },
+						"Симуляция сети": if state.netsim { "Вкл" } else { "Выкл" }
 					}
 				}
 			];
+			for (id, ip, s) in stats
+			{
+				let _ = obj.push(json::object!{
+					title: format!("Игрок #{id}"),
+					props: {
+						"Адрес": ip,
+						"Пинг (мс)": (s.rtt.as_secs_f32() * 1000.0).round(),
+						"Потери (%)": (s.loss() * 1000.0).round() / 10.0,
+						"Отправлено (КиБ/с)": (s.sendRate / 102.4).round() / 10.0,
+						"Получено (КиБ/с)": (s.receiveRate / 102.4).round() / 10.0,
+						"Пакеты (отпр./получ.)":
+							format!("{} / {}", s.sentPackets, s.receivedPackets)
+					}
+				});
+			}
 		}
 		Resp::GetSettings(cfg) =>
 		{
@@ -243,14 +500,12 @@ fn sendWS(tcp: &mut TcpStream, msg: Resp)
 					cancel: ""
 				}
 			};
-			let path = format!("res/web/modals/{id}.json");
+			let path = format!("{}/modals/{id}.json", options::get().webRoot);
 			if let Ok(f) = std::fs::read_to_string(path)
+				&& let Ok(mut x) = json::parse(&f)
 			{
-				if let Ok(mut x) = json::parse(&f)
-				{
-					let _ = x.insert("id", id);
-					obj = x;
-				}
+				let _ = x.insert("id", id);
+				obj = x;
 			}
 		}
 		Resp::Buttons(btns) =>
@@ -265,211 +520,423 @@ fn sendWS(tcp: &mut TcpStream, msg: Resp)
 				});
 			}
 		}
-	}
-
-	let raw = json::stringify(json::object!{ type: topic, data: obj });
-
-	let len =
-		if raw.len() <= 125 { vec![raw.len() as u8] }
-		else { [&[126u8] as &[u8], &(raw.len() as u16).to_be_bytes()].concat() };
-
-	let _ = tcp.write_all(&[
-		&[0b10_00_00_01 as u8],
-		len.as_slice(),
-		raw.as_bytes()
-	].concat());
-}
-
-fn handleWS(
-	id: usize,
-	msg: &str,
-	data: json::JsonValue,
-	toMain: &std::sync::mpsc::Sender<Request>
-)
-{
-	match msg
-	{
-		"chatMessages" =>
+		Resp::Saves(active, list) =>
 		{
-			let _ = toMain.send((id,
-				Req::ChatMessages(data["messagesLength"].as_usize().unwrap_or(0))
-			));
+			topic = "saves";
+			obj = json::object!{ active: active, slots: [] };
+			for x in list
+			{
+				let _ = obj["slots"].push(json::object!{
+					name: x.name,
+					date: x.date,
+					checkpoint: x.checkpoint,
+					backups: x.backups.into_iter()
+						.map(|(n, date, checkpoint)| json::object!{
+							index: n,
+							date: date,
+							checkpoint: checkpoint
+						})
+						.collect::<Vec<_>>()
+				});
+			}
 		}
-		"newMessage" =>
+		Resp::Players(list, bans) =>
 		{
-			let _ = toMain.send((id,
-				Req::NewMessage(data["msg"].as_str().unwrap_or("").to_string())
-			));
+			topic = "players";
+			obj = json::object!{ players: [], bans: bans };
+			for p in list
+			{
+				let _ = obj["players"].push(json::object!{
+					id: p.id,
+					name: p.name,
+					className: p.class,
+					ip: p.ip,
+					ping: (p.stats.rtt.as_secs_f32() * 1000.0).round(),
+					pos: [p.pos.x, p.pos.y, p.pos.z],
+					detached: p.detached,
+					muted: p.muted
+				});
+			}
 		}
-		"saveSettings" =>
+		Resp::Auth(token, reason, wait) =>
 		{
-			let _ = toMain.send((id, Req::SaveSettings(data)));
+			topic = "auth";
+			obj = json::object!{
+				admin: token.is_some(),
+				token: token.unwrap_or_default(),
+				reason: reason,
+				wait: wait
+			};
 		}
-		"modal" =>
+		Resp::Log(records) =>
 		{
-			let _ = toMain.send((id,  Req::Modal(
-				data["id"].as_str().unwrap_or("").to_string(),
-				data["result"].clone()
-			)));
+			topic = "log";
+			obj = json::array![];
+			for x in records
+			{
+				let _ = obj.push(json::object!{
+					id: x.id,
+					time: x.time,
+					level: x.level.name(),
+					target: x.target,
+					msg: x.msg
+				});
+			}
 		}
-		"clickButton" =>
+		Resp::Saved(waiting, adjusted) =>
 		{
-			let _ = toMain.send((id, Req::ClickButton(
-				data.as_str().unwrap_or("").to_string()
-			)));
+			topic = "savedSettings";
+			obj = json::object!{ waiting: waiting, adjusted: adjusted };
 		}
-		x =>
+		Resp::ApiDone | Resp::Shutdown =>
 		{
-			println!("Unknown request: {x}");
-			println!("{data:#}");
+			topic = "";
+			obj = json::JsonValue::Null;
 		}
 	}
-}
 
-////////// LOW LEVEL STUFF //////////
+	(topic, obj)
+}
 
-fn handleHTTP(tcp: &mut TcpStream, buf: &[u8]) -> u8
+// Request from a WebSocket message or an API call, None if it makes no sense.
+pub fn request(ip: IpAddr, msg: &str, data: json::JsonValue) -> Option<Req>
 {
-	let raw = String::from_utf8_lossy(buf).to_string();
-	let args: Vec<&str> = raw.split("\r\n").collect();
-	let mut path = args[0].split(" ").collect::<Vec<&str>>()[1];
+	let req = match msg
+	{
+		"chatMessages" => Req::ChatMessages(data["messagesLength"].as_usize().unwrap_or(0)),
+		"newMessage" => Req::NewMessage(data["msg"].as_str().unwrap_or("").to_string()),
+		"state" => Req::State,
+		"getSettings" => Req::GetSettings,
+		"buttons" => Req::Buttons,
+		"saveSettings" => Req::SaveSettings(data),
+		"modal" => Req::Modal(
+			data["id"].as_str().unwrap_or("").to_string(),
+			data["result"].clone()
+		),
+		"saves" => Req::Saves,
+		"saveSlot" =>
+		{
+			let name = data["name"].as_str().unwrap_or("").to_string();
+			let to = data["to"].as_str().unwrap_or("").to_string();
+			let action = match data["action"].as_str().unwrap_or("")
+			{
+				"create" => saves::Action::Create(name),
+				"copy" => saves::Action::Copy(name, to),
+				"rename" => saves::Action::Rename(name, to),
+				"delete" => saves::Action::Delete(name),
+				"load" => saves::Action::Load(name),
+				"restore" => saves::Action::Restore(name, data["backup"].as_u8().unwrap_or(1)),
+				x => { warn!("Unknown save slot action: {x}"); return None; }
+			};
+			Req::SaveSlot(action)
+		}
+		"login" => Req::Login(ip, data["password"].as_str().unwrap_or("").to_string()),
+		"resume" => Req::Resume(data["token"].as_str().unwrap_or("").to_string()),
+		"logout" => Req::Logout,
+		"changePassword" => Req::ChangePassword(
+			data["old"].as_str().unwrap_or("").to_string(),
+			data["new"].as_str().unwrap_or("").to_string()
+		),
+		"players" => Req::Players,
+		"playerAction" =>
+		{
+			let action = match data["action"].as_str().unwrap_or("")
+			{
+				"kick" => player::Action::Kick,
+				"ban" => player::Action::Ban,
+				"mute" => player::Action::Mute(true),
+				"unmute" => player::Action::Mute(false),
+				"teleport" => player::Action::Teleport,
+				x => { warn!("Unknown player action: {x}"); return None; }
+			};
+			Req::PlayerAction(data["id"].as_u8()?, action)
+		}
+		"unban" => Req::Unban(data["ip"].as_str().unwrap_or("").to_string()),
+		"clickButton" => Req::ClickButton(data.as_str().unwrap_or("").to_string()),
+		x =>
+		{
+			warn!("Unknown request: {x}\n{data:#}");
+			return None;
+		}
+	};
+	Some(req)
+}
 
-	if path == "/ws" { return 2; }
-	if path == "/" { path = "/index.html"; }
+////////// LOW LEVEL STUFF //////////
 
-	let (mimetype, bin) = match path.split(".").last().unwrap()
+// Answers every complete request received so far, static files right away.
+// An API request goes to main and holds the rest until it is answered.
+fn handleHTTP(
+	client: &mut Client,
+	id: usize,
+	toMain: &std::sync::mpsc::Sender<Request>
+)
+{
+	while client.api.is_none()
 	{
-		"html" => ("text/html", false),
-		"css" => ("text/css", false),
-		"js" => ("text/javascript", false),
-		"png" => ("image/png", true),
-		"otf" => ("application/x-font-opentype", true),
-		x => { println!("{x}"); ("", false) }
-	};
+		match client.http.next()
+		{
+			Ok(None) => break,
+			Ok(Some(req)) if req.path == "/ws" && req.isUpgrade() =>
+			{
+				if setupWS(client, &req, id, toMain)
+				{
+					client.mode = ClientMode::WebSocket;
+					let rest = client.http.take();
+					client.ws.push(&rest);
+					if !receiveWS(client, id, toMain)
+					{
+						client.mode = ClientMode::Disconnected;
+					}
+				}
+				else { client.mode = ClientMode::Disconnected; }
+				break;
+			}
+			Ok(Some(req)) if req.path.starts_with("/api/") =>
+			{
+				match api::route(&req, client.ip)
+				{
+					Ok(x) =>
+					{
+						let _ = toMain.send((id, Req::Api(api::token(&req), Box::new(x))));
+						client.api = Some((vec![], req.method == http::Method::Head, req.keepAlive()));
+					}
+					Err(status) =>
+					{
+						client.send(&api::reject(&req, status));
+						if !req.keepAlive() { client.mode = ClientMode::Disconnected; break; }
+					}
+				}
+			}
+			Ok(Some(req)) =>
+			{
+				client.send(&serve(&req));
+				if !req.keepAlive() { client.mode = ClientMode::Disconnected; break; }
+			}
+			Err(status) =>
+			{
+				client.send(&http::error(status, false, false));
+				client.mode = ClientMode::Disconnected;
+				break;
+			}
+		}
+	}
+}
 
-	let (found, data) =
-		match std::fs::read(String::from("res/web/") + path)
+fn serve(req: &http::Request) -> Vec<u8>
+{
+	let head = req.method == http::Method::Head;
+	let keepAlive = req.keepAlive();
+	if req.method != http::Method::Get && !head
 	{
-		Ok(f) => { (true, f) }
-		Err(x) => { println!("{x:#?}"); (false, vec![]) }
-	};
+		return http::error(405, head, keepAlive);
+	}
 
-	let _ = tcp.write_all(&match data.is_empty()
+	let mut path = req.path.clone();
+	if path.ends_with('/') { path += "index.html"; }
+
+	match std::fs::read(options::get().webRoot.clone() + &path)
 	{
-		true => "HTTP/1.1 404 Not Found".as_bytes().to_vec(),
-		false => [
-			(String::from("HTTP/1.1 200 OK") +
-			"\r\nConnection: keep-alive" +
-			"\r\nContent-Type: " + mimetype +
-			if bin { "" } else { "; charset=UTF-8" } +
-			"\r\nContent-Length: " + &data.len().to_string() +
-			"\r\n\r\n").as_bytes().to_vec(),
-			data,
-			"\r\n\r\n".as_bytes().to_vec()
-		].concat()
-	});
-
-	if found { 0 } else { 1 }
+		Ok(data) => http::response(
+			200, &[("Content-Type", http::mime(&path))],
+			&data, head, keepAlive
+		),
+		Err(_) => http::error(404, head, keepAlive)
+	}
 }
 
 fn setupWS(
-	tcp: &mut TcpStream,
-	buf: &[u8],
+	client: &mut Client,
+	req: &http::Request,
 	id: usize,
 	toMain: &std::sync::mpsc::Sender<Request>
 ) -> bool
 {
-	let raw = String::from_utf8_lossy(buf).to_string();
-	let args: Vec<(&str, &str)> = raw.split("\r\n").map(|x|
+	let key = req.header("sec-websocket-key").unwrap_or("").to_string();
+	if key.is_empty()
 	{
-		if !x.contains(": ") { return ("", ""); }
-		let t: Vec<&str> = x.split(": ").collect();
-		(t[0], t[1])
-	}).filter(|(a, b)| !a.is_empty() && !b.is_empty()).collect();
-	let args = {
-		let mut h = HashMap::new();
-		for (var, value) in args
-		{
-			h.insert(var.to_lowercase(), value);
-		}
-		h
-	};
-
-	let key = args.get("sec-websocket-key").unwrap_or(&"").to_string();
-	if key.is_empty() { println!("No key is provided."); return false; }
+		warn!("No key is provided.");
+		client.send(&http::error(400, false, false));
+		return false;
+	}
 
 	let magic = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 	let enc = BASE64_STANDARD.encode(
 		sha1_smol::Sha1::from(key + magic).digest().bytes()
 	);
-	let _ = tcp.write_all((
+	client.send((
 		String::from("HTTP/1.1 101 Switching Protocols") +
 		"\r\nUpgrade: websocket\r\nConnection: Upgrade" +
 		"\r\nSec-WebSocket-Accept: " + &enc + "\r\n\r\n"
 	).as_bytes());
 
+	// Guests only see the state until they log in or resume a session.
 	let _ = toMain.send((id, Req::State));
-	let _ = toMain.send((id, Req::GetSettings));
-	let _ = toMain.send((id, Req::Buttons));
-	
+	sendWS(client, Resp::Auth(None, String::from("required"), 0));
+
 	true
 }
 
-fn receiveWS(buf: &[u8]) -> Option<(String, json::JsonValue)>
+// Handles every complete message received so far.
+// Returns false once the connection is over, after sending a close frame.
+fn receiveWS(
+	client: &mut Client,
+	id: usize,
+	toMain: &std::sync::mpsc::Sender<Request>
+) -> bool
 {
-	let isFinal = buf[0] & 0b10_00_00_00 == 128;
-	let kind = buf[0] & 0b00_00_11_11;
-	if kind == 8 { return None; }
-	let (payloadLength, offset) = {
-		let check = buf[1] & 0b01_11_11_11;
-		if check <= 125 { (check as u64, 2) }
-		else if check == 126
-		{
-			(u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
-		}
-		else
+	loop
+	{
+		match client.ws.next()
 		{
-			(u64::from_be_bytes([
-				buf[2], buf[3], buf[4], buf[5],
-				buf[6], buf[7], buf[8], buf[9]
-			]), 10)
+			Ok(None) => return true,
+			Ok(Some(websocket::Message::Text(raw))) =>
+			{
+				let msg = json::parse(&raw).unwrap_or(json::JsonValue::Null);
+				match msg.entries().next()
+				{
+					Some((topic, data)) =>
+					{
+						if let Some(req) = request(client.ip, topic, data.clone())
+						{
+							let _ = toMain.send((id, req));
+						}
+					}
+					None => warn!("WebClient #{id} sent an invalid message.")
+				}
+			}
+			Ok(Some(websocket::Message::Binary(_))) =>
+			{
+				client.send(&websocket::close(websocket::CLOSE_UNSUPPORTED, ""));
+				return false;
+			}
+			Ok(Some(websocket::Message::Ping(data))) =>
+			{
+				client.send(&websocket::pong(&data));
+			}
+			Ok(Some(websocket::Message::Pong(_))) => {}
+			Ok(Some(websocket::Message::Close(code, _))) =>
+			{
+				let code = code.unwrap_or(websocket::CLOSE_NORMAL);
+				client.send(&websocket::close(code, ""));
+				return false;
+			}
+			Err(code) =>
+			{
+				warn!("WebClient #{id} broke the protocol, closing with {code}.");
+				client.send(&websocket::close(code, ""));
+				return false;
+			}
 		}
-	};
-	let key = &buf[offset..offset + 4];
-	if !isFinal { println!("Cannot process partial packet."); return None; }
-	let mut raw = vec![];
-	for i in 0..payloadLength as usize
-	{
-		raw.push(buf[offset + 4 + i] ^ key[i % 4]);
 	}
-	let msg = json::parse(
-		&String::from_utf8_lossy(&raw).to_string()
-	).unwrap_or(json::object!{ "invalid": {} });
-
-	let (msg, data) = msg.entries().nth(0).unwrap();
-	Some((msg.to_string(), data.to_owned()))
 }
 
-/*
-key "players"
-pub fn jsonPlayers(&self) -> json::JsonValue
+#[cfg(test)]
+mod tests
 {
-	let p = Server::getPlayers();
-	let mut arr = json::array![];
-	for i in 1..=5
+	use super::*;
+	use crate::envell::auth::{self, Auth};
+	use std::{sync::mpsc, time::Duration};
+
+	#[test]
+	fn reconnectIsGuest()
+	{
+		let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (toMain, fromWeb) = mpsc::channel();
+		let (toWeb, fromMain) = mpsc::channel();
+		let web = std::thread::spawn(move || run(vec![listener], toMain, fromMain));
+		let wait = Duration::from_secs(5);
+
+		// Main's side: a session, resumed by the token every API request carries.
+		let mut a = Auth::default();
+		let auth::Login::Ok(token) = a.login(0, addr.ip(), "secret", &auth::hash("secret"))
+			else { panic!("login failed"); };
+		a.closed(0);
+
+		let mut c = std::net::TcpStream::connect(addr).unwrap();
+		c.write_all(format!("GET /api/players HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\r\n").as_bytes()).unwrap();
+		let (first, req) = fromWeb.recv_timeout(wait).unwrap();
+		let Req::Api(t, _) = req else { panic!("expected an API request"); };
+		assert!(a.resume(first, &t));
+		assert!(a.allowed(first, Permission::Players));
+		toWeb.send((first, Resp::ApiDone)).unwrap();
+		let mut buf = [0u8; 1024];
+		assert!(c.read(&mut buf).unwrap() > 0);
+
+		// A keep-alive connection that hangs up still ends its session in main.
+		drop(c);
+		let (id, req) = fromWeb.recv_timeout(wait).unwrap();
+		assert_eq!(id, first);
+		assert!(matches!(req, Req::Closed));
+		a.closed(id);
+
+		// Nobody is connected now, yet the next connection gets an id of its own.
+		let mut c = std::net::TcpStream::connect(addr).unwrap();
+		c.write_all(b"GET /api/players HTTP/1.1\r\n\r\n").unwrap();
+		let (second, req) = fromWeb.recv_timeout(wait).unwrap();
+		assert_ne!(second, first);
+		assert!(matches!(req, Req::Api(ref t, _) if t.is_empty()));
+		assert!(!a.allowed(second, Permission::Players));
+
+		toWeb.send((0, Resp::Shutdown)).unwrap();
+		web.join().unwrap();
+	}
+
+	#[test]
+	fn largeFramesArriveWhole()
 	{
-		for (id, c) in p
+		let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (toMain, fromWeb) = mpsc::channel();
+		let (toWeb, fromMain) = mpsc::channel();
+		let web = std::thread::spawn(move || run(vec![listener], toMain, fromMain));
+
+		let mut c = std::net::TcpStream::connect(addr).unwrap();
+		c.write_all(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+		let (id, _) = fromWeb.recv_timeout(Duration::from_secs(5)).unwrap();
+
+		// Far more than a socket buffer holds, sent while the client reads nothing.
+		let big = "x".repeat(1 << 20);
+		for _ in 0..8 { toWeb.send((id, Resp::Saved(vec![big.clone()], vec![]))).unwrap(); }
+		std::thread::sleep(Duration::from_millis(300));
+
+		let mut raw = vec![];
+		let mut buf = [0u8; 65536];
+		c.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		let head = loop
+		{
+			let n = c.read(&mut buf).unwrap();
+			raw.extend_from_slice(&buf[..n]);
+			if let Some(x) = raw.windows(4).position(|x| x == b"\r\n\r\n") { break x + 4; }
+		};
+		let mut ws = websocket::Decoder::client().limit(usize::MAX);
+		ws.push(&raw[head..]);
+		let mut got = 0;
+		while got < 8
 		{
-			if *id != i || c.info.name == "noname" { continue; }
-			let _ = arr.push(json::object!{
-				id: *id,
-				name: c.info.name.clone(),
-				className: c.info.class.clone(),
-				hp: { current: c.info.hp, max: 100 },
-				mana: { current: 100, max: 100 }
-			});
+			match ws.next().unwrap()
+			{
+				Some(websocket::Message::Text(x)) =>
+				{
+					let msg = json::parse(&x).unwrap();
+					if msg["type"] == "savedSettings"
+					{
+						assert_eq!(msg["data"]["waiting"][0].as_str().unwrap().len(), big.len());
+						got += 1;
+					}
+				}
+				Some(_) => panic!("unexpected frame"),
+				None =>
+				{
+					let n = c.read(&mut buf).unwrap();
+					assert!(n > 0);
+					ws.push(&buf[..n]);
+				}
+			}
 		}
+
+		toWeb.send((0, Resp::Shutdown)).unwrap();
+		web.join().unwrap();
 	}
-	arr
 }
-*/
\ No newline at end of file
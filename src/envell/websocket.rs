@@ -0,0 +1,336 @@
+// RFC 6455 frames: decoding with reassembly across reads and encoding of server frames.
+
+pub const MAX_MESSAGE: usize = 1024 * 1024;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message
+{
+	Text(String),
+	Binary(Vec<u8>),
+	Ping(Vec<u8>),
+	Pong(Vec<u8>),
+	// Code is absent when the peer sent an empty close frame.
+	Close(Option<u16>, String)
+}
+
+#[derive(Debug)]
+pub struct Decoder
+{
+	buf: Vec<u8>,
+	// Opcode and data of a fragmented message still being received.
+	partial: Option<(u8, Vec<u8>)>,
+	masked: bool,
+	limit: usize
+}
+
+impl Decoder
+{
+	// Frames from clients must be masked.
+	pub fn server() -> Self { Self { buf: vec![], partial: None, masked: true, limit: MAX_MESSAGE } }
+
+	// Tests read back what the server sends.
+	#[cfg(test)]
+	pub fn client() -> Self { Self { buf: vec![], partial: None, masked: false, limit: MAX_MESSAGE } }
+
+	#[cfg(test)]
+	pub fn limit(mut self, bytes: usize) -> Self { self.limit = bytes; self }
+
+	pub fn push(&mut self, data: &[u8]) { self.buf.extend_from_slice(data); }
+
+	// Ok(None) means more bytes are needed. Err carries the close code to send
+	// before dropping the connection.
+	pub fn next(&mut self) -> Result<Option<Message>, u16>
+	{
+		loop
+		{
+			let Some((fin, opcode, payload)) = self.frame()? else { return Ok(None); };
+			match opcode
+			{
+				CLOSE => return parseClose(&payload).map(Some),
+				PING => return Ok(Some(Message::Ping(payload))),
+				PONG => return Ok(Some(Message::Pong(payload))),
+				CONTINUATION =>
+				{
+					let Some((_, data)) = self.partial.as_mut() else { return Err(CLOSE_PROTOCOL); };
+					if data.len() + payload.len() > self.limit { return Err(CLOSE_TOO_BIG); }
+					data.extend_from_slice(&payload);
+					if !fin { continue; }
+					let (kind, data) = self.partial.take().unwrap();
+					return message(kind, data).map(Some);
+				}
+				TEXT | BINARY =>
+				{
+					if self.partial.is_some() { return Err(CLOSE_PROTOCOL); }
+					if fin { return message(opcode, payload).map(Some); }
+					self.partial = Some((opcode, payload));
+				}
+				_ => return Err(CLOSE_PROTOCOL)
+			}
+		}
+	}
+
+	fn frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, u16>
+	{
+		let b = &self.buf;
+		if b.len() < 2 { return Ok(None); }
+		let fin = b[0] & 0x80 != 0;
+		if b[0] & 0x70 != 0 { return Err(CLOSE_PROTOCOL); }
+		let opcode = b[0] & 0x0F;
+		let masked = b[1] & 0x80 != 0;
+		if masked != self.masked { return Err(CLOSE_PROTOCOL); }
+
+		let (len, mut offset) = match b[1] & 0x7F
+		{
+			126 =>
+			{
+				if b.len() < 4 { return Ok(None); }
+				let len = u16::from_be_bytes([b[2], b[3]]) as u64;
+				if len < 126 { return Err(CLOSE_PROTOCOL); }
+				(len, 4)
+			}
+			127 =>
+			{
+				if b.len() < 10 { return Ok(None); }
+				let len = u64::from_be_bytes(b[2..10].try_into().unwrap());
+				if len >> 63 != 0 || len <= u16::MAX as u64 { return Err(CLOSE_PROTOCOL); }
+				(len, 10)
+			}
+			x => (x as u64, 2)
+		};
+
+		if opcode & 0x8 != 0 && (!fin || len > 125) { return Err(CLOSE_PROTOCOL); }
+		let pending = self.partial.as_ref().map(|x| x.1.len()).unwrap_or(0) as u64;
+		if len + if opcode == CONTINUATION { pending } else { 0 } > self.limit as u64
+		{
+			return Err(CLOSE_TOO_BIG);
+		}
+
+		let key = if masked
+		{
+			if b.len() < offset + 4 { return Ok(None); }
+			offset += 4;
+			Some([b[offset - 4], b[offset - 3], b[offset - 2], b[offset - 1]])
+		}
+		else { None };
+
+		let end = offset + len as usize;
+		if b.len() < end { return Ok(None); }
+		let mut payload = b[offset..end].to_vec();
+		if let Some(key) = key
+		{
+			for (i, x) in payload.iter_mut().enumerate() { *x ^= key[i % 4]; }
+		}
+		self.buf.drain(..end);
+		Ok(Some((fin, opcode, payload)))
+	}
+}
+
+fn message(opcode: u8, data: Vec<u8>) -> Result<Message, u16>
+{
+	if opcode == BINARY { return Ok(Message::Binary(data)); }
+	String::from_utf8(data).map(Message::Text).map_err(|_| CLOSE_INVALID_DATA)
+}
+
+fn parseClose(payload: &[u8]) -> Result<Message, u16>
+{
+	match payload.len()
+	{
+		0 => Ok(Message::Close(None, String::new())),
+		1 => Err(CLOSE_PROTOCOL),
+		_ =>
+		{
+			let code = u16::from_be_bytes([payload[0], payload[1]]);
+			let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+			if !valid { return Err(CLOSE_PROTOCOL); }
+			let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| CLOSE_INVALID_DATA)?;
+			Ok(Message::Close(Some(code), reason))
+		}
+	}
+}
+
+// Server frames are never masked and never fragmented.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8>
+{
+	let mut out = vec![0x80 | opcode];
+	match payload.len()
+	{
+		x if x <= 125 => out.push(x as u8),
+		x if x <= u16::MAX as usize =>
+		{
+			out.push(126);
+			out.extend_from_slice(&(x as u16).to_be_bytes());
+		}
+		x =>
+		{
+			out.push(127);
+			out.extend_from_slice(&(x as u64).to_be_bytes());
+		}
+	}
+	out.extend_from_slice(payload);
+	out
+}
+
+pub fn text(s: &str) -> Vec<u8> { frame(TEXT, s.as_bytes()) }
+
+pub fn pong(data: &[u8]) -> Vec<u8> { frame(PONG, &data[..data.len().min(125)]) }
+
+pub fn close(code: u16, reason: &str) -> Vec<u8>
+{
+	let mut end = reason.len().min(123);
+	while !reason.is_char_boundary(end) { end -= 1; }
+	frame(CLOSE, &[&code.to_be_bytes() as &[u8], &reason.as_bytes()[..end]].concat())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// Examples from RFC 6455, section 5.7.
+	const HELLO_MASKED: &[u8] = &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+	const HELLO: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+	const HEL_LO: &[u8] = &[0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+	const PING_HELLO: &[u8] = &[0x89, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+	const PONG_MASKED: &[u8] = &[0x8a, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+	fn decode(d: &mut Decoder, raw: &[u8]) -> Result<Option<Message>, u16>
+	{
+		d.push(raw);
+		d.next()
+	}
+
+	fn masked(first: u8, payload: &[u8]) -> Vec<u8>
+	{
+		let key = [1, 2, 3, 4];
+		let mut out = vec![first];
+		match payload.len()
+		{
+			x if x <= 125 => out.push(0x80 | x as u8),
+			x if x <= u16::MAX as usize =>
+			{
+				out.push(0x80 | 126);
+				out.extend_from_slice(&(x as u16).to_be_bytes());
+			}
+			x =>
+			{
+				out.push(0x80 | 127);
+				out.extend_from_slice(&(x as u64).to_be_bytes());
+			}
+		}
+		out.extend_from_slice(&key);
+		out.extend(payload.iter().enumerate().map(|(i, x)| x ^ key[i % 4]));
+		out
+	}
+
+	#[test]
+	fn rfcExamples()
+	{
+		let hello = Some(Message::Text(String::from("Hello")));
+		assert_eq!(decode(&mut Decoder::server(), HELLO_MASKED), Ok(hello.clone()));
+		assert_eq!(decode(&mut Decoder::client(), HELLO), Ok(hello.clone()));
+		assert_eq!(decode(&mut Decoder::client(), HEL_LO), Ok(hello));
+		assert_eq!(
+			decode(&mut Decoder::client(), PING_HELLO),
+			Ok(Some(Message::Ping(b"Hello".to_vec())))
+		);
+		assert_eq!(
+			decode(&mut Decoder::server(), PONG_MASKED),
+			Ok(Some(Message::Pong(b"Hello".to_vec())))
+		);
+		assert_eq!(text("Hello"), HELLO);
+		assert_eq!(pong(b"Hello")[0], 0x8a);
+	}
+
+	#[test]
+	fn acrossReads()
+	{
+		let mut d = Decoder::server();
+		let mut out = vec![];
+		for b in [HELLO_MASKED, HELLO_MASKED].concat()
+		{
+			d.push(&[b]);
+			while let Some(m) = d.next().unwrap() { out.push(m); }
+		}
+		assert_eq!(out, vec![Message::Text(String::from("Hello")); 2]);
+	}
+
+	#[test]
+	fn interleavedControl()
+	{
+		let mut d = Decoder::server();
+		d.push(&masked(0x01, b"ab"));
+		d.push(&masked(0x89, b"p"));
+		d.push(&masked(0x80, b"cd"));
+		assert_eq!(d.next(), Ok(Some(Message::Ping(b"p".to_vec()))));
+		assert_eq!(d.next(), Ok(Some(Message::Text(String::from("abcd")))));
+	}
+
+	#[test]
+	fn lengths()
+	{
+		for len in [125usize, 126, 65535, 65536]
+		{
+			let payload = vec![7u8; len];
+			let mut d = Decoder::server();
+			assert_eq!(decode(&mut d, &masked(0x82, &payload)), Ok(Some(Message::Binary(payload.clone()))));
+			let mut c = Decoder::client();
+			assert_eq!(decode(&mut c, &frame(BINARY, &payload)), Ok(Some(Message::Binary(payload))));
+		}
+		let big = frame(BINARY, &vec![0; 70000]);
+		assert_eq!(&big[..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+	}
+
+	#[test]
+	fn violations()
+	{
+		let p = Err(CLOSE_PROTOCOL);
+		assert_eq!(decode(&mut Decoder::server(), HELLO), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0xC1, b"x")), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x80, b"x")), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x83, b"x")), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x09, b"x")), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x89, &[0; 126])), p);
+		let mut d = Decoder::server();
+		d.push(&masked(0x01, b"a"));
+		assert_eq!(decode(&mut d, &masked(0x81, b"b")), p);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x81, &[0xff, 0xfe])), Err(CLOSE_INVALID_DATA));
+	}
+
+	#[test]
+	fn tooBig()
+	{
+		let mut d = Decoder::server().limit(10);
+		assert_eq!(decode(&mut d, &masked(0x81, &[b'a'; 11])), Err(CLOSE_TOO_BIG));
+		let mut d = Decoder::server().limit(10);
+		d.push(&masked(0x01, &[b'a'; 6]));
+		assert_eq!(decode(&mut d, &masked(0x80, &[b'a'; 6])), Err(CLOSE_TOO_BIG));
+	}
+
+	#[test]
+	fn closeFrames()
+	{
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x88, b"")), Ok(Some(Message::Close(None, String::new()))));
+		assert_eq!(
+			decode(&mut Decoder::server(), &masked(0x88, &[0x03, 0xe8, b'b', b'y', b'e'])),
+			Ok(Some(Message::Close(Some(1000), String::from("bye"))))
+		);
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x88, &[0x03])), Err(CLOSE_PROTOCOL));
+		assert_eq!(decode(&mut Decoder::server(), &masked(0x88, &[0x03, 0xed])), Err(CLOSE_PROTOCOL));
+		assert_eq!(close(1001, ""), vec![0x88, 0x02, 0x03, 0xe9]);
+		assert_eq!(close(1000, &"я".repeat(100)).len(), 2 + 2 + 122);
+	}
+}
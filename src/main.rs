@@ -14,4 +14,5 @@ fn main()
 		Window::update();
 		Window::render();
 	}
+	Window::stopServer();
 }
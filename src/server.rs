@@ -1,3 +1,8 @@
 mod envell;
 
-fn main() { envell::main(); }
\ No newline at end of file
+fn main()
+{
+	envell::catchSignals();
+	let opts = envell::options::fromArgs();
+	envell::main(opts, Some(envell::launchConsole()));
+}